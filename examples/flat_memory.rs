@@ -0,0 +1,74 @@
+// Run the 6502 core on its own, as a plain 64KB flat-memory machine with
+// no NES hardware. This is the starting point for reusing the CPU in other
+// 6502 systems or for teaching: implement CpuBus for your memory map,
+// attach it, and drive the public registers and step().
+//
+//     cargo run --example flat_memory
+use arness::assembler::assemble;
+use arness::cpu6502::{Cpu6502, CpuBus};
+
+// Writes to this address print a character, like a terminal port
+const OUTPUT_PORT: u16 = 0xF001;
+
+// Fill $0200-$020B with the first 12 Fibonacci numbers, print "OK" and
+// stop on BRK
+const PROGRAM: &str = "
+        LDA #0
+        STA $0200
+        LDA #1
+        STA $0201
+        LDX #0
+loop:   LDA $0200,X
+        CLC
+        ADC $0201,X
+        STA $0202,X
+        INX
+        CPX #10
+        BNE loop
+        LDA #$4F ; O
+        STA $F001
+        LDA #$4B ; K
+        STA $F001
+        LDA #10
+        STA $F001
+        BRK
+";
+
+struct FlatBus {
+    ram: Vec<u8>,
+}
+
+impl CpuBus for FlatBus {
+    fn read(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if addr == OUTPUT_PORT {
+            print!("{}", data as char);
+        } else {
+            self.ram[addr as usize] = data;
+        }
+    }
+}
+
+fn main() {
+    let program = assemble(PROGRAM, 0x0600).expect("program assembles");
+
+    let mut ram = vec![0; 0x10000];
+    let origin = program.origin as usize;
+    ram[origin..origin + program.bytes.len()].copy_from_slice(&program.bytes);
+
+    let mut cpu6502 = Cpu6502::new();
+    cpu6502.bus = Some(Box::new(FlatBus { ram }));
+    cpu6502.pc = program.origin;
+
+    // Step until the CPU reaches the BRK at the end of the program
+    while cpu6502.read(cpu6502.pc) != 0x00 {
+        cpu6502.step();
+    }
+
+    let numbers: Vec<u8> = (0x0200..0x020C).map(|addr| cpu6502.read(addr)).collect();
+    println!("fibonacci: {:?}", numbers);
+    println!("finished in {} cycles", cpu6502.cycles);
+}
@@ -0,0 +1,76 @@
+// Play a ROM in a window using the Emulator facade:
+//   cargo run --example play -- game.nes
+// Arrow keys are the D-pad, X is A, Z is B, Enter is Start and Right
+// Shift is Select. Escape quits. There is no PPU yet, so the window stays
+// black; the CPU, APU and controllers run underneath it.
+use arness::emulator::Emulator;
+use arness::frame::{FRAME_HEIGHT, FRAME_WIDTH};
+use arness::input::*;
+use arness::pacer::FramePacer;
+use minifb::{Key, Scale, Window, WindowOptions};
+use std::process::ExitCode;
+
+const KEYMAP: [(Key, u8); 8] = [
+    (Key::X, BUTTON_A),
+    (Key::Z, BUTTON_B),
+    (Key::RightShift, BUTTON_SELECT),
+    (Key::Enter, BUTTON_START),
+    (Key::Up, BUTTON_UP),
+    (Key::Down, BUTTON_DOWN),
+    (Key::Left, BUTTON_LEFT),
+    (Key::Right, BUTTON_RIGHT),
+];
+
+fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: play <rom.nes>");
+        return ExitCode::from(2);
+    };
+    match play(&path) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn play(path: &str) -> Result<(), String> {
+    let rom = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let mut emulator = Emulator::new();
+    emulator.load_rom(&rom).map_err(|e| e.to_string())?;
+
+    let options = WindowOptions {
+        scale: Scale::X2,
+        ..WindowOptions::default()
+    };
+    let mut window =
+        Window::new("Arness", FRAME_WIDTH, FRAME_HEIGHT, options).map_err(|e| e.to_string())?;
+    // The pacer keeps time, not the window
+    window.set_target_fps(0);
+
+    let mut pacer = FramePacer::new(emulator.region());
+    let mut buffer = vec![0u32; FRAME_WIDTH * FRAME_HEIGHT];
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let buttons = KEYMAP
+            .iter()
+            .filter(|(key, _)| window.is_key_down(*key))
+            .fold(0, |buttons, (_, button)| buttons | button);
+        emulator.set_controller_state(0, buttons);
+        emulator.run_frame();
+        if emulator.cpu().jammed {
+            return Err(format!("CPU jammed at ${:04X}", emulator.cpu().pc));
+        }
+
+        // minifb wants 0RGB pixels
+        let frame = emulator.framebuffer();
+        for (pixel, rgba) in buffer.iter_mut().zip(frame.pixels.chunks_exact(4)) {
+            *pixel = u32::from_be_bytes([0, rgba[0], rgba[1], rgba[2]]);
+        }
+        window
+            .update_with_buffer(&buffer, FRAME_WIDTH, FRAME_HEIGHT)
+            .map_err(|e| e.to_string())?;
+        pacer.wait();
+    }
+    Ok(())
+}
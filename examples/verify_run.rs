@@ -0,0 +1,96 @@
+// Replay a movie against a ROM headless, as fast as possible, and print
+// the final state hash. Two runs of the same movie must print the same
+// hash, which is how a speedrun submission is checked.
+//
+//     cargo run --release --example verify_run -- game.nes run.fm2
+//
+// Movies use the FCEUX FM2 input log: one line per frame such as
+// "|0|..D....A|........||", with the buttons in the order RLDUTSBA and a
+// command field where 1 is a soft reset and 2 a power cycle.
+use arness::emulator::Emulator;
+use std::process::ExitCode;
+
+// One frame of the movie
+struct MovieFrame {
+    commands: u8,
+    buttons: [u8; 2],
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [rom_path, movie_path] = args.as_slice() else {
+        eprintln!("usage: verify_run <rom.nes> <movie.fm2>");
+        return ExitCode::from(2);
+    };
+    match verify(rom_path, movie_path) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn verify(rom_path: &str, movie_path: &str) -> Result<(), String> {
+    let rom = std::fs::read(rom_path).map_err(|e| format!("cannot read {}: {}", rom_path, e))?;
+    let movie = std::fs::read_to_string(movie_path)
+        .map_err(|e| format!("cannot read {}: {}", movie_path, e))?;
+    let frames = parse_fm2(&movie)?;
+
+    let mut emulator = Emulator::new();
+    emulator.load_rom(&rom).map_err(|e| e.to_string())?;
+    let start = std::time::Instant::now();
+    for frame in &frames {
+        if frame.commands & 2 != 0 {
+            emulator.power_on();
+        } else if frame.commands & 1 != 0 {
+            emulator.reset();
+        }
+        emulator.set_controller_state(0, frame.buttons[0]);
+        emulator.set_controller_state(1, frame.buttons[1]);
+        emulator.run_frame();
+        if emulator.cpu().jammed {
+            return Err(format!(
+                "CPU jammed at ${:04X} on frame {}",
+                emulator.cpu().pc,
+                emulator.frame_count()
+            ));
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    println!("frames: {}", frames.len());
+    println!("state hash: {:016x}", emulator.cpu().state_hash());
+    println!(
+        "speed: {:.1}x real time",
+        frames.len() as f64 / emulator.region().frame_rate() / elapsed.max(1e-9)
+    );
+    Ok(())
+}
+
+// Read the input log of an FM2 movie, skipping the header
+fn parse_fm2(text: &str) -> Result<Vec<MovieFrame>, String> {
+    let mut frames = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let Some(fields) = line.strip_prefix('|') else {
+            continue;
+        };
+        let fields: Vec<&str> = fields.split('|').collect();
+        let error = || format!("line {}: bad input record", index + 1);
+        let commands = fields.first().ok_or_else(error)?;
+        let mut frame = MovieFrame {
+            commands: commands.trim().parse().map_err(|_| error())?,
+            buttons: [0; 2],
+        };
+        for (port, field) in fields.iter().skip(1).take(2).enumerate() {
+            // RLDUTSBA maps onto bits 7..0 of the controller byte
+            for (i, c) in field.chars().take(8).enumerate() {
+                if c != '.' && c != ' ' {
+                    frame.buttons[port] |= 0x80 >> i;
+                }
+            }
+        }
+        frames.push(frame);
+    }
+    Ok(frames)
+}
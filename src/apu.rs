@@ -0,0 +1,1546 @@
+// 2A03 audio processing unit. Channels are clocked once per CPU cycle by
+// Apu::tick; the frame counter drives their envelopes, length counters and
+// sweeps, and sample() mixes the channel outputs like the console's DAC.
+use crate::hash::Fnv1aHasher;
+use crate::region::Region;
+use std::cell::Cell;
+use std::hash::{Hash, Hasher};
+
+// Lengths loaded by the upper 5 bits of the length counter registers
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Pulse waveforms for the four duty settings
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// Triangle levels for each of the 32 sequencer steps
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+// Periods that depend on the console's region, all in CPU cycles
+#[derive(Debug, Hash)]
+struct Timing {
+    // Noise timer periods, selected by $400E
+    noise: [u16; 16],
+    // DMC output periods, selected by $4010
+    dmc: [u16; 16],
+    // Frame counter steps since the counter was reset
+    steps: [u32; 5],
+}
+
+static NTSC_TIMING: Timing = Timing {
+    noise: [
+        4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+    ],
+    dmc: [
+        428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+    ],
+    steps: [7457, 14913, 22371, 29829, 37281],
+};
+
+static PAL_TIMING: Timing = Timing {
+    noise: [
+        4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+    ],
+    dmc: [
+        398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+    ],
+    steps: [8313, 16627, 24939, 33253, 41565],
+};
+
+impl Timing {
+    // The Dendy's clone APU keeps the NTSC periods despite its PAL-like
+    // CPU clock
+    fn of(region: Region) -> &'static Timing {
+        match region {
+            Region::Pal => &PAL_TIMING,
+            Region::Ntsc | Region::Dendy => &NTSC_TIMING,
+        }
+    }
+}
+
+// CPU cycles lost to each DMC sample fetch. This is an approximation: the
+// real stall is 1-4 cycles depending on which cycle of which instruction
+// the fetch lands on, normally 3 or 4, and only 2 during OAM DMA. A fixed
+// 4 is close enough for audio, but it is not cycle accurate.
+pub const DMC_DMA_CYCLES: u64 = 4;
+
+// The tone channels, in register order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+    ];
+}
+
+// Recent output levels of one channel, for oscilloscope views. Holds the
+// newest `capacity` levels, overwriting the oldest.
+#[derive(Clone, Debug, Default)]
+pub struct Waveform {
+    levels: Vec<u8>,
+    capacity: usize,
+    next: usize,
+}
+
+impl Waveform {
+    pub fn new(capacity: usize) -> Self {
+        Waveform {
+            levels: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+
+    pub fn push(&mut self, level: u8) {
+        if self.levels.len() < self.capacity {
+            self.levels.push(level);
+        } else if self.capacity > 0 {
+            self.levels[self.next] = level;
+        }
+        self.next = (self.next + 1) % self.capacity.max(1);
+    }
+
+    // Levels from oldest to newest
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        let split = if self.levels.len() < self.capacity {
+            0
+        } else {
+            self.next
+        };
+        let (newer, older) = self.levels.split_at(split);
+        older.iter().chain(newer).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+// Volume envelope used by the pulse and noise channels
+#[derive(Clone, Debug, Default, Hash)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    // Constant volume, or the decay period
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0F;
+    }
+
+    // Quarter-frame clock
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+// Silences a channel after a set number of half frames
+#[derive(Clone, Debug, Default, Hash)]
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    value: u8,
+}
+
+impl LengthCounter {
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.value = LENGTH_TABLE[index as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    // Half-frame clock
+    fn clock(&mut self) {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.value > 0
+    }
+}
+
+// Square wave channel with duty cycle, envelope and pitch sweep
+#[derive(Clone, Debug, Default, Hash)]
+pub struct Pulse {
+    // The first pulse negates its sweep in ones' complement
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            ..Pulse::default()
+        }
+    }
+
+    // Registers $4000-$4003 (or $4004-$4007), by offset
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data >> 3);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Clocked every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.step = self.step.wrapping_sub(1) & 7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Period the sweep unit is aiming for
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.timer_period + change
+        } else if self.ones_complement {
+            self.timer_period.saturating_sub(change + 1)
+        } else {
+            self.timer_period.saturating_sub(change)
+        }
+    }
+
+    // Very low periods and sweep targets past $7FF silence the channel
+    fn muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x07FF
+    }
+
+    // Half-frame clock
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // Current level, 0-15
+    pub fn output(&self) -> u8 {
+        if self.muted()
+            || !self.length.active()
+            || DUTY_TABLE[self.duty as usize][self.step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+// Triangle wave channel with a linear counter and no volume control
+#[derive(Clone, Debug, Default, Hash)]
+pub struct Triangle {
+    step: u8,
+    timer_period: u16,
+    timer: u16,
+    length: LengthCounter,
+    // Also the length counter halt flag
+    control: bool,
+    linear_period: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    // Registers $4008-$400B, by offset
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_period = data & 0x7F;
+            }
+            1 => {}
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle. The sequencer only moves while both
+    // counters are running, so a silenced triangle holds its level.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 31;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Quarter-frame clock
+    fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_period;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // Current level, 0-15. Periods below 2 are ultrasonic; games use them
+    // to silence the channel, so they output the midpoint instead of
+    // aliasing.
+    pub fn output(&self) -> u8 {
+        if self.timer_period < 2 {
+            7
+        } else {
+            TRIANGLE_SEQUENCE[self.step as usize]
+        }
+    }
+}
+
+// Pseudo-random noise channel, used mostly for percussion
+#[derive(Clone, Debug, Hash)]
+pub struct Noise {
+    // Short mode taps bit 6 instead of bit 1, giving a 93-step metallic tone
+    short_mode: bool,
+    // Period table for the region and the index $400E picked from it
+    periods: &'static [u16; 16],
+    rate: u8,
+    timer_period: u16,
+    timer: u16,
+    // 15-bit linear feedback shift register
+    shift: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise {
+            short_mode: false,
+            periods: &NTSC_TIMING.noise,
+            rate: 0,
+            timer_period: NTSC_TIMING.noise[0],
+            timer: 0,
+            // The register is 1 at power on
+            shift: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+}
+
+impl Noise {
+    // Registers $400C-$400F, by offset
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.rate = data & 0x0F;
+                self.timer_period = self.periods[self.rate as usize];
+            }
+            _ => {
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn set_periods(&mut self, periods: &'static [u16; 16]) {
+        self.periods = periods;
+        self.timer_period = periods[self.rate as usize];
+    }
+
+    // Clocked every other CPU cycle; the periods are in CPU cycles, so
+    // the timer counts down by two
+    fn clock_timer(&mut self) {
+        if self.timer <= 2 {
+            self.timer = self.timer_period;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 2;
+        }
+    }
+
+    // Current level, 0-15
+    pub fn output(&self) -> u8 {
+        if self.shift & 1 != 0 || !self.length.active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+// Delta modulation channel, which plays 1-bit delta samples fetched from
+// CPU memory. The APU cannot reach memory itself: whoever clocks it checks
+// dmc_dma_request after each tick, reads the byte and hands it back with
+// dmc_dma_complete, stalling the CPU for DMC_DMA_CYCLES.
+#[derive(Clone, Debug, Hash)]
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    // Period table for the region and the index $4010 picked from it
+    periods: &'static [u16; 16],
+    rate: u8,
+    timer_period: u16,
+    timer: u16,
+    // 7-bit output level
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    irq: bool,
+    // Drop the sample buffer when $4015 disables the channel
+    flush_on_disable: bool,
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            periods: &NTSC_TIMING.dmc,
+            rate: 0,
+            timer_period: NTSC_TIMING.dmc[0],
+            timer: NTSC_TIMING.dmc[0],
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+            flush_on_disable: false,
+        }
+    }
+}
+
+impl Dmc {
+    // Registers $4010-$4013, by offset
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.rate = data & 0x0F;
+                self.timer_period = self.periods[self.rate as usize];
+            }
+            1 => self.level = data & 0x7F,
+            2 => self.sample_address = 0xC000 | (data as u16) << 6,
+            _ => self.sample_length = (data as u16) << 4 | 1,
+        }
+    }
+
+    fn set_periods(&mut self, periods: &'static [u16; 16]) {
+        self.periods = periods;
+        self.timer_period = periods[self.rate as usize];
+    }
+
+    // Bit 4 of $4015. Disabling stops further fetches but keeps the level
+    // and, on hardware, the byte already in the buffer, which still plays.
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+            if self.flush_on_disable {
+                self.buffer = None;
+            }
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period;
+
+        // Move the level by 2 toward each bit, staying within 0-127
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(byte) => {
+                    self.shift = byte;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // Address the channel wants to read, when its sample buffer is empty
+    fn dma_request(&self) -> Option<u16> {
+        (self.buffer.is_none() && self.bytes_remaining > 0).then_some(self.current_address)
+    }
+
+    fn dma_complete(&mut self, byte: u8) {
+        self.buffer = Some(byte);
+        // The address wraps from $FFFF to $8000
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // Current level, 0-127
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+// A snapshot of the APU for music rippers and audio overlays: what was
+// written to each register plus the counters that have moved on since.
+// Plain values throughout, so any format can store it; to_bytes packs the
+// fields in order, little-endian.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RegisterDump {
+    // Last value written to each of $4000-$4017 ($4014 and $4016 stay 0)
+    pub registers: [u8; 0x18],
+    // $4015 as a read would see it
+    pub status: u8,
+    // Timer periods of the pulses, triangle, noise and DMC. Sweeps move
+    // the pulse periods away from the written values.
+    pub periods: [u16; 5],
+    // Length counters of the pulses, triangle and noise
+    pub lengths: [u8; 4],
+    // Envelope volumes of the pulses and noise
+    pub envelopes: [u8; 3],
+    pub linear_counter: u8,
+    pub noise_shift: u16,
+    pub dmc_level: u8,
+    pub dmc_address: u16,
+    pub dmc_bytes_remaining: u16,
+    // CPU cycles into the frame counter sequence
+    pub frame_cycle: u32,
+    pub five_step: bool,
+}
+
+impl RegisterDump {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.registers.to_vec();
+        bytes.push(self.status);
+        for period in self.periods {
+            bytes.extend(period.to_le_bytes());
+        }
+        bytes.extend(self.lengths);
+        bytes.extend(self.envelopes);
+        bytes.push(self.linear_counter);
+        bytes.extend(self.noise_shift.to_le_bytes());
+        bytes.push(self.dmc_level);
+        bytes.extend(self.dmc_address.to_le_bytes());
+        bytes.extend(self.dmc_bytes_remaining.to_le_bytes());
+        bytes.extend(self.frame_cycle.to_le_bytes());
+        bytes.push(self.five_step as u8);
+        bytes
+    }
+}
+
+// The frame counter's position, from Apu::frame_sequencer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameSequencer {
+    // Steps already clocked in this sequence, 0-3 or 0-4 in 5-step mode
+    pub step: u8,
+    // CPU cycles since the sequence started
+    pub frame_cycle: u32,
+    pub five_step: bool,
+    // CPU cycles until a $4017 write resets the sequence
+    pub pending_write: Option<u8>,
+}
+
+// The APU's registers, channels and frame counter
+#[derive(Clone, Debug)]
+pub struct Apu {
+    pulse: [Pulse; 2],
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    timing: &'static Timing,
+    five_step: bool,
+    irq_inhibit: bool,
+    // Cleared by reading $4015, which only has &self
+    frame_irq: Cell<bool>,
+    frame_cycle: u32,
+    odd_cycle: bool,
+    // A $4017 write waiting to reset the sequence, with the cycles left
+    pending_frame_write: Option<(u8, u8)>,
+    // Last value written to each of $4000-$4017, for peek_reg. $4014 and
+    // $4016 belong to other devices and stay 0.
+    registers: [u8; 0x18],
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            pulse: [Pulse::new(true), Pulse::new(false)],
+            triangle: Triangle::default(),
+            noise: Noise::default(),
+            dmc: Dmc::default(),
+            timing: &NTSC_TIMING,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: Cell::new(false),
+            frame_cycle: 0,
+            odd_cycle: false,
+            pending_frame_write: None,
+            registers: [0; 0x18],
+        }
+    }
+
+    // Use the noise, DMC and frame counter periods of a region. A new APU
+    // starts out with NTSC timing.
+    pub fn set_region(&mut self, region: Region) {
+        self.timing = Timing::of(region);
+        self.noise.set_periods(&self.timing.noise);
+        self.dmc.set_periods(&self.timing.dmc);
+    }
+
+    // A CPU write to $4000-$4017
+    pub fn write(&mut self, addr: u16, data: u8) {
+        if let 0x4000..=0x4013 | 0x4015 | 0x4017 = addr {
+            self.registers[(addr - 0x4000) as usize] = data;
+        }
+        match addr {
+            0x4000..=0x4003 => self.pulse[0].write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse[1].write(addr - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, data),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, data),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, data),
+            0x4015 => {
+                self.pulse[0].length.set_enabled(data & 0x01 != 0);
+                self.pulse[1].length.set_enabled(data & 0x02 != 0);
+                self.triangle.length.set_enabled(data & 0x04 != 0);
+                self.noise.length.set_enabled(data & 0x08 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
+            }
+            // The inhibit flag applies at once; the mode change and sequence
+            // reset wait 3 CPU cycles, or 4 when written on an odd one
+            0x4017 => {
+                self.irq_inhibit = data & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq.set(false);
+                }
+                let delay = if self.odd_cycle { 4 } else { 3 };
+                self.pending_frame_write = Some((data, delay));
+            }
+            _ => {}
+        }
+    }
+
+    // A CPU read of $4015, which acknowledges the frame interrupt but not
+    // the DMC one
+    pub fn read_status(&self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq.set(false);
+        status
+    }
+
+    // $4015 without acknowledging the interrupt
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        for (i, pulse) in self.pulse.iter().enumerate() {
+            if pulse.length.active() {
+                status |= 1 << i;
+            }
+        }
+        if self.triangle.length.active() {
+            status |= 0x04;
+        }
+        if self.noise.length.active() {
+            status |= 0x08;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0x10;
+        }
+        if self.frame_irq.get() {
+            status |= 0x40;
+        }
+        if self.dmc.irq {
+            status |= 0x80;
+        }
+        status
+    }
+
+    // The value last written to an APU register, without side effects, for
+    // debuggers; the CPU itself reads these registers as open bus. $4015
+    // gives the status a read would see, and addresses that are not APU
+    // registers give None.
+    pub fn peek_reg(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4015 => Some(self.peek_status()),
+            0x4000..=0x4013 | 0x4017 => Some(self.registers[(addr - 0x4000) as usize]),
+            _ => None,
+        }
+    }
+
+    pub fn register_dump(&self) -> RegisterDump {
+        let [pulse1, pulse2] = &self.pulse;
+        RegisterDump {
+            registers: self.registers,
+            status: self.peek_status(),
+            periods: [
+                pulse1.timer_period,
+                pulse2.timer_period,
+                self.triangle.timer_period,
+                self.noise.timer_period,
+                self.dmc.timer_period,
+            ],
+            lengths: [
+                pulse1.length.value,
+                pulse2.length.value,
+                self.triangle.length.value,
+                self.noise.length.value,
+            ],
+            envelopes: [
+                pulse1.envelope.output(),
+                pulse2.envelope.output(),
+                self.noise.envelope.output(),
+            ],
+            linear_counter: self.triangle.linear_counter,
+            noise_shift: self.noise.shift,
+            dmc_level: self.dmc.level,
+            dmc_address: self.dmc.current_address,
+            dmc_bytes_remaining: self.dmc.bytes_remaining,
+            frame_cycle: self.frame_cycle,
+            five_step: self.five_step,
+        }
+    }
+
+    // True while the APU holds the CPU's IRQ line low
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq.get() || self.dmc.irq
+    }
+
+    // Advance by one CPU cycle
+    pub fn tick(&mut self) {
+        self.triangle.clock_timer();
+        self.dmc.clock_timer();
+        if self.odd_cycle {
+            for pulse in &mut self.pulse {
+                pulse.clock_timer();
+            }
+            self.noise.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+        self.clock_frame_counter();
+
+        if let Some((data, delay)) = self.pending_frame_write {
+            if delay > 1 {
+                self.pending_frame_write = Some((data, delay - 1));
+            } else {
+                self.pending_frame_write = None;
+                self.five_step = data & 0x80 != 0;
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+        }
+    }
+
+    // Where the frame counter is, for tests and debuggers
+    pub fn frame_sequencer(&self) -> FrameSequencer {
+        FrameSequencer {
+            step: self
+                .timing
+                .steps
+                .iter()
+                .take_while(|&&step| self.frame_cycle >= step)
+                .count() as u8,
+            frame_cycle: self.frame_cycle,
+            five_step: self.five_step,
+            pending_write: self.pending_frame_write.map(|(_, delay)| delay),
+        }
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let [step1, step2, step3, step4, step5] = self.timing.steps;
+        match self.frame_cycle {
+            cycle if cycle == step1 || cycle == step3 => self.clock_quarter_frame(),
+            cycle if cycle == step2 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            cycle if cycle == step4 && !self.five_step => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                if !self.irq_inhibit {
+                    self.frame_irq.set(true);
+                }
+                self.frame_cycle = 0;
+            }
+            cycle if cycle == step5 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_cycle = 0;
+            }
+            _ => {}
+        }
+    }
+
+    // Envelopes and the linear counter
+    fn clock_quarter_frame(&mut self) {
+        for pulse in &mut self.pulse {
+            pulse.envelope.clock();
+        }
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    // Length counters and sweeps
+    fn clock_half_frame(&mut self) {
+        for pulse in &mut self.pulse {
+            pulse.length.clock();
+            pulse.clock_sweep();
+        }
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+
+    // Pulse channel 0 or 1
+    pub fn pulse(&self, index: usize) -> &Pulse {
+        &self.pulse[index]
+    }
+
+    pub fn triangle(&self) -> &Triangle {
+        &self.triangle
+    }
+
+    pub fn noise(&self) -> &Noise {
+        &self.noise
+    }
+
+    pub fn dmc(&self) -> &Dmc {
+        &self.dmc
+    }
+
+    // Address the DMC needs read, if its sample buffer is empty
+    // Make $4015 disabling the DMC also drop its buffered byte, so the
+    // channel falls silent at the end of the current byte instead of the
+    // next one. Off by default, as on hardware; the level is kept either
+    // way, so games that park it somewhere do not pop.
+    pub fn set_dmc_flush_on_disable(&mut self, flush: bool) {
+        self.dmc.flush_on_disable = flush;
+    }
+
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    // Hand the DMC the byte read for dmc_dma_request
+    pub fn dmc_dma_complete(&mut self, byte: u8) {
+        self.dmc.dma_complete(byte);
+    }
+
+    // Current level of a channel
+    pub fn channel_output(&self, channel: Channel) -> u8 {
+        match channel {
+            Channel::Pulse1 => self.pulse[0].output(),
+            Channel::Pulse2 => self.pulse[1].output(),
+            Channel::Triangle => self.triangle.output(),
+            Channel::Noise => self.noise.output(),
+            Channel::Dmc => self.dmc.output(),
+        }
+    }
+
+    // Mixed output in 0.0-1.0, using the nonlinear DAC approximation
+    pub fn sample(&self) -> f32 {
+        let pulse = (self.pulse[0].output() + self.pulse[1].output()) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.output() as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+
+    // Hash of every register, counter and timer, folded into
+    // Cpu6502::state_hash
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = Fnv1aHasher::default();
+        self.pulse.hash(&mut hasher);
+        self.triangle.hash(&mut hasher);
+        self.noise.hash(&mut hasher);
+        self.dmc.hash(&mut hasher);
+        self.timing.hash(&mut hasher);
+        self.five_step.hash(&mut hasher);
+        self.irq_inhibit.hash(&mut hasher);
+        self.frame_irq.get().hash(&mut hasher);
+        self.frame_cycle.hash(&mut hasher);
+        self.odd_cycle.hash(&mut hasher);
+        self.pending_frame_write.hash(&mut hasher);
+        self.registers.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.tick();
+        }
+    }
+
+    #[test]
+    fn length_counter_loads_only_while_enabled() {
+        let mut apu = Apu::new();
+        apu.write(0x4003, 0x08);
+        assert_eq!(apu.pulse[0].length.value, 0);
+
+        apu.write(0x4015, 0x01);
+        apu.write(0x4003, 0x08);
+        assert_eq!(apu.pulse[0].length.value, 254);
+        apu.write(0x4003, 0x00);
+        assert_eq!(apu.pulse[0].length.value, 10);
+        for _ in 0..9 {
+            apu.clock_half_frame();
+        }
+        assert_eq!(apu.peek_status() & 0x01, 0x01);
+        apu.clock_half_frame();
+        assert_eq!(apu.peek_status() & 0x01, 0);
+
+        // Halted counters hold, and disabling the channel clears them
+        apu.write(0x4000, 0x20);
+        apu.write(0x4003, 0x00);
+        apu.clock_half_frame();
+        assert_eq!(apu.pulse[0].length.value, 10);
+        apu.write(0x4015, 0x00);
+        assert_eq!(apu.pulse[0].length.value, 0);
+    }
+
+    #[test]
+    fn envelope_decays_and_loops() {
+        let mut envelope = Envelope::default();
+        envelope.write(0x03);
+        envelope.start = true;
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+        // The divider period is volume + 1 quarter frames
+        for _ in 0..4 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 14);
+        for _ in 0..14 * 4 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+        for _ in 0..8 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+
+        envelope.write(0x23);
+        for _ in 0..4 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 15);
+
+        envelope.write(0x1A);
+        assert_eq!(envelope.output(), 10);
+    }
+
+    #[test]
+    fn sweep_mutes_low_periods_and_high_targets() {
+        let mut apu = Apu::new();
+        apu.write(0x4015, 0x01);
+        apu.write(0x4000, 0xBF);
+        apu.write(0x4002, 0x07);
+        apu.write(0x4003, 0x08);
+        assert!(apu.pulse[0].muted());
+        apu.write(0x4002, 0x08);
+        assert!(!apu.pulse[0].muted());
+
+        // With a shift of 0 the target is twice the period, which mutes a
+        // period of $400 or more even while the sweep is disabled
+        apu.write(0x4002, 0xFF);
+        apu.write(0x4003, 0x0B);
+        assert!(!apu.pulse[0].muted());
+        apu.write(0x4002, 0x00);
+        apu.write(0x4003, 0x0C);
+        assert!(apu.pulse[0].muted());
+        assert_eq!(apu.pulse[0].output(), 0);
+    }
+
+    #[test]
+    fn sweep_negate_differs_between_pulses() {
+        let mut apu = Apu::new();
+        for base in [0x4000, 0x4004] {
+            // Sweep enabled, period 0, negate, shift 1, on a period of $100
+            apu.write(base + 1, 0x89);
+            apu.write(base + 2, 0x00);
+            apu.write(base + 3, 0x01);
+        }
+        // Pulse 1 subtracts the ones' complement, pulse 2 the two's
+        assert_eq!(apu.pulse[0].sweep_target(), 0x7F);
+        assert_eq!(apu.pulse[1].sweep_target(), 0x80);
+        apu.clock_half_frame();
+        assert_eq!(apu.pulse[0].timer_period, 0x7F);
+        assert_eq!(apu.pulse[1].timer_period, 0x80);
+    }
+
+    #[test]
+    fn four_step_sequence_raises_the_frame_irq() {
+        let mut apu = Apu::new();
+        apu.write(0x4015, 0x01);
+        apu.write(0x4003, 0x00);
+        // The first half frame lands on cycle 14913
+        run(&mut apu, 14912);
+        assert_eq!(apu.pulse[0].length.value, 10);
+        run(&mut apu, 1);
+        assert_eq!(apu.pulse[0].length.value, 9);
+
+        run(&mut apu, 29828 - 14913);
+        assert!(!apu.irq_pending());
+        run(&mut apu, 1);
+        assert!(apu.irq_pending());
+        assert_eq!(apu.pulse[0].length.value, 8);
+        // Reading $4015 acknowledges it
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert!(!apu.irq_pending());
+
+        // Inhibited, the sequence runs but never interrupts
+        apu.write(0x4017, 0x40);
+        run(&mut apu, 29829 * 2);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn five_step_sequence_clocks_at_once_and_never_interrupts() {
+        let mut apu = Apu::new();
+        apu.write(0x4015, 0x01);
+        apu.write(0x4003, 0x00);
+        apu.write(0x4017, 0x80);
+        assert_eq!(apu.pulse[0].length.value, 10);
+        run(&mut apu, 3);
+        assert_eq!(apu.pulse[0].length.value, 9);
+        // Half frames at 14913 and 37281, none at 29829
+        run(&mut apu, 29829);
+        assert_eq!(apu.pulse[0].length.value, 8);
+        run(&mut apu, 37280 - 29829);
+        assert_eq!(apu.pulse[0].length.value, 8);
+        run(&mut apu, 1);
+        assert_eq!(apu.pulse[0].length.value, 7);
+        run(&mut apu, 37281 * 2);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn frame_counter_write_waits_three_or_four_cycles() {
+        // Written on an even cycle, the reset lands 3 cycles later
+        let mut apu = Apu::new();
+        run(&mut apu, 100);
+        apu.write(0x4017, 0x80);
+        run(&mut apu, 2);
+        let sequencer = apu.frame_sequencer();
+        assert_eq!((sequencer.frame_cycle, sequencer.five_step), (102, false));
+        assert_eq!(sequencer.pending_write, Some(1));
+        run(&mut apu, 1);
+        assert_eq!(
+            apu.frame_sequencer(),
+            FrameSequencer {
+                step: 0,
+                frame_cycle: 0,
+                five_step: true,
+                pending_write: None,
+            }
+        );
+
+        // On an odd cycle it takes 4
+        let mut apu = Apu::new();
+        run(&mut apu, 101);
+        apu.write(0x4017, 0x00);
+        run(&mut apu, 3);
+        assert_eq!(apu.frame_sequencer().frame_cycle, 104);
+        run(&mut apu, 1);
+        assert_eq!(apu.frame_sequencer().frame_cycle, 0);
+    }
+
+    #[test]
+    fn frame_irq_counts_from_the_delayed_reset() {
+        // The 4-step sequence restarts 3 cycles after an even-cycle write,
+        // so the interrupt comes 29829 cycles after that
+        let mut apu = Apu::new();
+        apu.write(0x4017, 0x00);
+        run(&mut apu, 3 + 29828);
+        assert!(!apu.irq_pending());
+        assert_eq!(apu.frame_sequencer().step, 3);
+        run(&mut apu, 1);
+        assert!(apu.irq_pending());
+        assert_eq!(apu.frame_sequencer().step, 0);
+
+        // Inhibit takes effect at once, acknowledging the pending flag
+        apu.write(0x4017, 0x40);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn triangle_linear_counter_reloads_and_counts_down() {
+        let mut apu = Apu::new();
+        apu.write(0x4015, 0x04);
+        apu.write(0x4008, 0x05);
+        apu.write(0x400B, 0x08);
+        apu.clock_quarter_frame();
+        assert_eq!(apu.triangle.linear_counter, 5);
+        // With control clear the reload flag drops after one use
+        for _ in 0..5 {
+            apu.clock_quarter_frame();
+        }
+        assert_eq!(apu.triangle.linear_counter, 0);
+
+        // With control set it reloads on every quarter frame, and the
+        // length counter is halted too
+        apu.write(0x4008, 0x85);
+        apu.write(0x400B, 0x08);
+        for _ in 0..10 {
+            apu.clock_quarter_frame();
+            apu.clock_half_frame();
+        }
+        assert_eq!(apu.triangle.linear_counter, 5);
+        assert_eq!(apu.triangle.length.value, 254);
+    }
+
+    #[test]
+    fn triangle_holds_its_level_when_silenced() {
+        let mut apu = Apu::new();
+        apu.write(0x4015, 0x04);
+        apu.write(0x4008, 0x01);
+        apu.write(0x400A, 0x02);
+        apu.write(0x400B, 0x08);
+        apu.clock_quarter_frame();
+        // A period of 2 steps the sequencer every third cycle
+        let start = apu.triangle.step;
+        run(&mut apu, 3);
+        assert_eq!(apu.triangle.step, (start + 1) & 31);
+
+        apu.clock_quarter_frame();
+        assert_eq!(apu.triangle.linear_counter, 0);
+        let level = apu.triangle.output();
+        run(&mut apu, 30);
+        assert_eq!(apu.triangle.output(), level);
+
+        // Ultrasonic periods output the midpoint
+        apu.write(0x400A, 0x01);
+        apu.write(0x400B, 0x08);
+        assert_eq!(apu.triangle.output(), 7);
+    }
+
+    // Clock the noise shift register once, whatever the timer says
+    fn shift_noise(noise: &mut Noise) {
+        noise.timer = 0;
+        noise.clock_timer();
+    }
+
+    // Shifts until the register returns to its power-on value
+    fn noise_period(short_mode: bool) -> usize {
+        let mut noise = Noise {
+            short_mode,
+            ..Noise::default()
+        };
+        (1..=32767)
+            .find(|_| {
+                shift_noise(&mut noise);
+                noise.shift == 1
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn noise_lfsr_modes() {
+        let mut noise = Noise::default();
+        // Long mode feeds back bit 0 xor bit 1 into bit 14
+        shift_noise(&mut noise);
+        assert_eq!(noise.shift, 0x4000);
+        assert_eq!(noise_period(false), 32767);
+        assert_eq!(noise_period(true), 93);
+    }
+
+    #[test]
+    fn noise_timer_uses_the_selected_period() {
+        let mut apu = Apu::new();
+        apu.write(0x400E, 0x8F);
+        assert!(apu.noise.short_mode);
+        assert_eq!(apu.noise.timer_period, 4068);
+        apu.write(0x400E, 0x00);
+        assert_eq!(apu.noise.timer_period, 4);
+        // The register only shifts every `period` CPU cycles
+        apu.write(0x400E, 0x01);
+        run(&mut apu, 2);
+        let shift = apu.noise.shift;
+        run(&mut apu, 7);
+        assert_eq!(apu.noise.shift, shift);
+        run(&mut apu, 1);
+        assert_ne!(apu.noise.shift, shift);
+    }
+
+    #[test]
+    fn region_tables() {
+        assert_eq!(NTSC_TIMING.noise[0], 4);
+        assert_eq!(NTSC_TIMING.noise[15], 4068);
+        assert_eq!(NTSC_TIMING.dmc[0], 428);
+        assert_eq!(NTSC_TIMING.dmc[15], 54);
+        assert_eq!(PAL_TIMING.noise[15], 3778);
+        assert_eq!(PAL_TIMING.dmc[0], 398);
+        assert_eq!(PAL_TIMING.dmc[15], 50);
+        // Every table is sorted from slowest to fastest, or the reverse
+        for timing in [&NTSC_TIMING, &PAL_TIMING] {
+            assert!(timing.noise.windows(2).all(|w| w[0] < w[1]));
+            assert!(timing.dmc.windows(2).all(|w| w[0] > w[1]));
+            assert!(timing.steps.windows(2).all(|w| w[0] < w[1]));
+        }
+        assert!(std::ptr::eq(Timing::of(Region::Dendy), &NTSC_TIMING));
+
+        // Switching region moves the current rates to the new table
+        let mut apu = Apu::new();
+        apu.write(0x400E, 0x0F);
+        apu.write(0x4010, 0x0F);
+        apu.set_region(Region::Pal);
+        assert_eq!(apu.noise.timer_period, 3778);
+        assert_eq!(apu.dmc.timer_period, 50);
+    }
+
+    #[test]
+    fn pal_frame_irq_comes_later() {
+        let mut apu = Apu::new();
+        apu.set_region(Region::Pal);
+        run(&mut apu, 33252);
+        assert!(!apu.irq_pending());
+        run(&mut apu, 1);
+        assert!(apu.irq_pending());
+    }
+
+    // Serve DMC fetches immediately, as if from memory full of `byte`,
+    // returning how many bytes were read
+    fn feed_dmc(apu: &mut Apu, byte: u8, limit: usize) -> usize {
+        let mut fetched = 0;
+        while fetched < limit && apu.dmc_dma_request().is_some() {
+            apu.dmc_dma_complete(byte);
+            apu.dmc.buffer = None;
+            fetched += 1;
+        }
+        fetched
+    }
+
+    #[test]
+    fn dmc_sample_address_and_length() {
+        let mut apu = Apu::new();
+        apu.write(0x4012, 0x01);
+        apu.write(0x4013, 0x02);
+        apu.write(0x4015, 0x10);
+        assert_eq!(apu.dmc_dma_request(), Some(0xC040));
+        assert_eq!(apu.peek_status() & 0x10, 0x10);
+        assert_eq!(feed_dmc(&mut apu, 0, 100), 33);
+        assert_eq!(apu.peek_status() & 0x10, 0);
+    }
+
+    #[test]
+    fn dmc_address_wraps_to_8000() {
+        let mut apu = Apu::new();
+        apu.write(0x4012, 0xFF);
+        apu.write(0x4013, 0x04);
+        apu.write(0x4015, 0x10);
+        assert_eq!(apu.dmc_dma_request(), Some(0xFFC0));
+        feed_dmc(&mut apu, 0, 64);
+        assert_eq!(apu.dmc_dma_request(), Some(0x8000));
+    }
+
+    #[test]
+    fn dmc_irq_at_the_end_unless_looping() {
+        let mut apu = Apu::new();
+        apu.write(0x4010, 0x80);
+        apu.write(0x4013, 0x00);
+        apu.write(0x4015, 0x10);
+        feed_dmc(&mut apu, 0, 1);
+        assert!(apu.irq_pending());
+        // Reading $4015 leaves the DMC interrupt alone, writing clears it
+        assert_eq!(apu.read_status() & 0x80, 0x80);
+        assert!(apu.irq_pending());
+        apu.write(0x4015, 0x00);
+        assert!(!apu.irq_pending());
+
+        // A looping sample restarts instead of interrupting
+        apu.write(0x4010, 0xC0);
+        apu.write(0x4015, 0x10);
+        assert_eq!(feed_dmc(&mut apu, 0, 5), 5);
+        assert!(!apu.irq_pending());
+        assert_eq!(apu.dmc_dma_request(), Some(0xC000));
+
+        // Clearing the enable bit in $4010 acknowledges it too
+        apu.write(0x4010, 0x80);
+        feed_dmc(&mut apu, 0, 1);
+        assert!(apu.irq_pending());
+        apu.write(0x4010, 0x00);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn dmc_output_follows_the_sample_bits() {
+        let mut apu = Apu::new();
+        apu.write(0x4011, 0x40);
+        apu.write(0x4013, 0x00);
+        apu.write(0x4015, 0x10);
+        apu.dmc_dma_complete(0xFF);
+        // The buffer is picked up when the current 8-bit cycle ends,
+        // then each bit moves the level by 2
+        let period = NTSC_TIMING.dmc[0] as u32;
+        run(&mut apu, period * 8);
+        assert_eq!(apu.dmc.output(), 0x40);
+        run(&mut apu, period * 8);
+        assert_eq!(apu.dmc.output(), 0x50);
+    }
+
+    #[test]
+    fn dmc_disable_keeps_the_level_and_the_buffered_byte() {
+        let mut apu = Apu::new();
+        apu.write(0x4011, 0x40);
+        apu.write(0x4013, 0x00);
+        apu.write(0x4015, 0x10);
+        apu.dmc_dma_complete(0xFF);
+        apu.write(0x4015, 0x00);
+        assert_eq!(apu.peek_status() & 0x10, 0);
+        assert_eq!(apu.dmc.output(), 0x40);
+
+        // The buffered byte still plays, then the level stays put
+        let period = NTSC_TIMING.dmc[0] as u32;
+        run(&mut apu, period * 16);
+        assert_eq!(apu.dmc.output(), 0x50);
+        run(&mut apu, period * 16);
+        assert_eq!(apu.dmc.output(), 0x50);
+        assert_eq!(apu.dmc_dma_request(), None);
+
+        // $4011 moves the level directly, even while disabled
+        apu.write(0x4011, 0x12);
+        assert_eq!(apu.dmc.output(), 0x12);
+    }
+
+    #[test]
+    fn dmc_enable_restarts_only_a_finished_sample() {
+        let mut apu = Apu::new();
+        apu.write(0x4013, 0x01);
+        apu.write(0x4015, 0x10);
+        feed_dmc(&mut apu, 0, 3);
+        apu.write(0x4015, 0x10);
+        assert_eq!(apu.dmc_dma_request(), Some(0xC003));
+
+        apu.write(0x4015, 0x00);
+        apu.write(0x4015, 0x10);
+        assert_eq!(apu.dmc_dma_request(), Some(0xC000));
+        assert_eq!(feed_dmc(&mut apu, 0, 100), 17);
+    }
+
+    #[test]
+    fn dmc_flush_on_disable_drops_the_buffer() {
+        let mut apu = Apu::new();
+        apu.set_dmc_flush_on_disable(true);
+        apu.write(0x4011, 0x40);
+        apu.write(0x4013, 0x00);
+        apu.write(0x4015, 0x10);
+        apu.dmc_dma_complete(0xFF);
+        apu.write(0x4015, 0x00);
+
+        let period = NTSC_TIMING.dmc[0] as u32;
+        run(&mut apu, period * 16);
+        assert_eq!(apu.dmc.output(), 0x40);
+    }
+
+    #[test]
+    fn register_dump_captures_registers_and_counters() {
+        let mut apu = Apu::new();
+        apu.write(0x4015, 0x05);
+        // Pulse 1: constant volume 9, a sweep up by period >> 1 every half
+        // frame, period $100
+        apu.write(0x4000, 0x19);
+        apu.write(0x4001, 0x81);
+        apu.write(0x4002, 0x00);
+        apu.write(0x4003, 0x09);
+        apu.write(0x4008, 0x85);
+        apu.write(0x400B, 0x00);
+        apu.write(0x4011, 0x30);
+        apu.write(0x4016, 0x01);
+        run(&mut apu, 7458);
+
+        let dump = apu.register_dump();
+        assert_eq!(&dump.registers[..4], [0x19, 0x81, 0x00, 0x09]);
+        assert_eq!((dump.registers[0x11], dump.registers[0x16]), (0x30, 0));
+        assert_eq!(dump.status, 0x05);
+        // Length index 1 loads 254; no half frame has passed yet
+        assert_eq!(dump.lengths, [254, 0, 10, 0]);
+        assert_eq!(dump.periods[0], 0x100);
+        assert_eq!(dump.envelopes[0], 9);
+        assert_eq!((dump.linear_counter, dump.dmc_level), (5, 0x30));
+        assert_eq!(dump.frame_cycle, 7458);
+
+        // The sweep moves the period after the first half frame
+        run(&mut apu, 7456);
+        assert_eq!(apu.register_dump().periods[0], 0x180);
+
+        let bytes = dump.to_bytes();
+        assert_eq!(bytes.len(), 55);
+        assert_eq!(&bytes[..4], [0x19, 0x81, 0x00, 0x09]);
+        assert_eq!(&bytes[25..27], [0x00, 0x01]);
+    }
+}
@@ -0,0 +1,478 @@
+// A tiny two-pass 6502 assembler so tests and examples can be written as
+// readable source instead of hand-assembled byte arrays.
+//
+// Syntax follows the disassembler output:
+//   loop:   LDA #$10        ; immediate
+//           STA $0200,X     ; absolute indexed
+//           LDA ($10),Y     ; indirect indexed
+//           BNE loop        ; branch to a label
+//           LDA #<table     ; low/high byte of a label with < and >
+//   table:  .byte 1, 2, $03
+//           .word loop
+//           .org $9000      ; skip forward, filling with zeros
+// Numbers can be $hex, %binary or decimal, and a leading '-' negates a
+// number (LDA #-1 loads $FF). Comments start with ';'.
+use crate::opcodes::{self, AddressingMode, OPCODES};
+use std::collections::HashMap;
+use std::fmt;
+
+// An assembly error with the 1-based source line it came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// Assembled machine code plus the address of every label
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Program {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub labels: HashMap<String, u16>,
+}
+
+// Which part of a value an expression selects
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Part {
+    Full,
+    Low,
+    High,
+}
+
+#[derive(Clone, Debug)]
+enum Term {
+    Number(u16),
+    Label(String),
+}
+
+#[derive(Clone, Debug)]
+struct Expr {
+    term: Term,
+    offset: i32,
+    part: Part,
+}
+
+impl Expr {
+    // The value if it can be computed from what is known so far
+    fn value(&self, labels: &HashMap<String, u16>) -> Option<u16> {
+        let value = self.signed(labels)? as u16;
+        Some(match self.part {
+            Part::Full => value,
+            Part::Low => value & 0xFF,
+            Part::High => value >> 8,
+        })
+    }
+
+    // The full value before it wraps to 16 bits, negative for -N
+    fn signed(&self, labels: &HashMap<String, u16>) -> Option<i32> {
+        let base = match &self.term {
+            Term::Number(n) => *n,
+            Term::Label(name) => *labels.get(name)?,
+        };
+        Some(base as i32 + self.offset)
+    }
+}
+
+// One statement after the first pass
+enum Item {
+    Instruction {
+        line: usize,
+        opcode: u8,
+        mode: AddressingMode,
+        operand: Option<Expr>,
+    },
+    Bytes(usize, Vec<Expr>),
+    Words(usize, Vec<Expr>),
+    Fill(usize),
+}
+
+// Assemble source code placed at `origin`
+pub fn assemble(source: &str, origin: u16) -> Result<Program, AsmError> {
+    let mut labels = HashMap::new();
+    let mut items = Vec::new();
+    let mut pc = origin as usize;
+
+    // First pass: parse, pick addressing modes and assign label addresses
+    for (index, raw) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |message: String| AsmError { line, message };
+        let mut text = raw.split(';').next().unwrap_or("").trim();
+
+        if let Some((label, rest)) = split_label(text) {
+            if labels.insert(label.to_string(), pc as u16).is_some() {
+                return Err(error(format!("label {} defined twice", label)));
+            }
+            text = rest;
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (word, rest) = match text.split_once(char::is_whitespace) {
+            Some((word, rest)) => (word, rest.trim()),
+            None => (text, ""),
+        };
+        let item = match word.to_ascii_lowercase().as_str() {
+            ".byte" | ".db" => Item::Bytes(line, parse_list(rest).map_err(error)?),
+            ".word" | ".dw" => Item::Words(line, parse_list(rest).map_err(error)?),
+            ".org" => {
+                let target = parse_expr(rest)
+                    .map_err(error)?
+                    .value(&labels)
+                    .ok_or_else(|| error(".org needs a known address".to_string()))?
+                    as usize;
+                if target < pc {
+                    return Err(error(format!(
+                        ".org ${:04X} is behind the current address",
+                        target
+                    )));
+                }
+                Item::Fill(target - pc)
+            }
+            _ => parse_instruction(word, rest, &labels).map_err(error)?(line),
+        };
+
+        pc += match &item {
+            Item::Instruction { mode, .. } => mode.size() as usize,
+            Item::Bytes(_, exprs) => exprs.len(),
+            Item::Words(_, exprs) => exprs.len() * 2,
+            Item::Fill(count) => *count,
+        };
+        if pc > 0x10000 {
+            return Err(error("program runs past $FFFF".to_string()));
+        }
+        items.push(item);
+    }
+
+    // Second pass: encode with every label known
+    let mut bytes = Vec::new();
+    for item in &items {
+        let here = origin as usize + bytes.len();
+        match item {
+            Item::Instruction {
+                line,
+                opcode,
+                mode,
+                operand,
+            } => {
+                bytes.push(*opcode);
+                let Some(expr) = operand else { continue };
+                let value = resolve(expr, &labels, *line)?;
+                match mode {
+                    AddressingMode::Relative => {
+                        let offset = value as i32 - (here as i32 + 2);
+                        if !(-128..=127).contains(&offset) {
+                            return Err(AsmError {
+                                line: *line,
+                                message: format!("branch target ${:04X} is out of range", value),
+                            });
+                        }
+                        bytes.push(offset as u8);
+                    }
+                    _ if mode.size() == 2 => bytes.push(resolve_byte(expr, &labels, *line)?),
+                    _ => bytes.extend_from_slice(&value.to_le_bytes()),
+                }
+            }
+            Item::Bytes(line, exprs) => {
+                for expr in exprs {
+                    bytes.push(resolve_byte(expr, &labels, *line)?);
+                }
+            }
+            Item::Words(line, exprs) => {
+                for expr in exprs {
+                    bytes.extend_from_slice(&resolve(expr, &labels, *line)?.to_le_bytes());
+                }
+            }
+            Item::Fill(count) => bytes.resize(bytes.len() + count, 0),
+        }
+    }
+
+    Ok(Program {
+        origin,
+        bytes,
+        labels,
+    })
+}
+
+fn resolve(expr: &Expr, labels: &HashMap<String, u16>, line: usize) -> Result<u16, AsmError> {
+    expr.value(labels).ok_or_else(|| AsmError {
+        line,
+        message: match &expr.term {
+            Term::Label(name) => format!("unknown label {}", name),
+            Term::Number(_) => "bad expression".to_string(),
+        },
+    })
+}
+
+// Resolve a one-byte operand or .byte value. Negative numbers down to
+// -128 are stored in two's complement.
+fn resolve_byte(expr: &Expr, labels: &HashMap<String, u16>, line: usize) -> Result<u8, AsmError> {
+    let value = resolve(expr, labels, line)?;
+    if expr.part != Part::Full {
+        return Ok(value as u8);
+    }
+    match expr.signed(labels) {
+        Some(signed @ -128..=255) => Ok(signed as u8),
+        Some(signed) if signed < 0 => Err(AsmError {
+            line,
+            message: format!("value {} does not fit in a byte", signed),
+        }),
+        _ => Err(AsmError {
+            line,
+            message: format!("value ${:04X} does not fit in a byte", value),
+        }),
+    }
+}
+
+// Split "name: rest" into the label and the remaining text
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let (label, rest) = text.split_once(':')?;
+    let label = label.trim();
+    is_identifier(label).then(|| (label, rest.trim()))
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Parse an instruction, returning a constructor that takes the line number
+fn parse_instruction(
+    mnemonic: &str,
+    operand: &str,
+    labels: &HashMap<String, u16>,
+) -> Result<impl FnOnce(usize) -> Item, String> {
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    let operand: String = operand.chars().filter(|c| !c.is_whitespace()).collect();
+    let operand = operand.as_str();
+    if !OPCODES.iter().any(|op| op.mnemonic == mnemonic) {
+        return Err(format!("unknown instruction {}", mnemonic));
+    }
+    let has = |mode| opcodes::find(&mnemonic, mode).is_some();
+
+    let (modes, expr): (&[AddressingMode], Option<Expr>) = if operand.is_empty() {
+        (
+            &[AddressingMode::Implied, AddressingMode::Accumulator],
+            None,
+        )
+    } else if operand.eq_ignore_ascii_case("A") {
+        (&[AddressingMode::Accumulator], None)
+    } else if let Some(value) = operand.strip_prefix('#') {
+        (&[AddressingMode::Immediate], Some(parse_expr(value)?))
+    } else if let Some(inner) = strip_suffix_ci(operand, ",X)").and_then(|s| s.strip_prefix('(')) {
+        (&[AddressingMode::IndirectX], Some(parse_expr(inner)?))
+    } else if let Some(inner) = strip_suffix_ci(operand, "),Y").and_then(|s| s.strip_prefix('(')) {
+        (&[AddressingMode::IndirectY], Some(parse_expr(inner)?))
+    } else if let Some(inner) = operand.strip_prefix('(').and_then(|s| s.strip_suffix(')')) {
+        (&[AddressingMode::Indirect], Some(parse_expr(inner)?))
+    } else if let Some(base) = strip_suffix_ci(operand, ",X") {
+        let expr = parse_expr(base)?;
+        let zero_page = fits_zero_page(&expr, labels) && has(AddressingMode::ZeroPageX);
+        if zero_page {
+            (&[AddressingMode::ZeroPageX], Some(expr))
+        } else {
+            (&[AddressingMode::AbsoluteX], Some(expr))
+        }
+    } else if let Some(base) = strip_suffix_ci(operand, ",Y") {
+        let expr = parse_expr(base)?;
+        let zero_page = fits_zero_page(&expr, labels) && has(AddressingMode::ZeroPageY);
+        if zero_page {
+            (&[AddressingMode::ZeroPageY], Some(expr))
+        } else {
+            (&[AddressingMode::AbsoluteY], Some(expr))
+        }
+    } else {
+        let expr = parse_expr(operand)?;
+        if has(AddressingMode::Relative) {
+            (&[AddressingMode::Relative], Some(expr))
+        } else if fits_zero_page(&expr, labels) && has(AddressingMode::ZeroPage) {
+            (&[AddressingMode::ZeroPage], Some(expr))
+        } else {
+            (&[AddressingMode::Absolute], Some(expr))
+        }
+    };
+
+    let (opcode, mode) = modes
+        .iter()
+        .find_map(|&mode| opcodes::find(&mnemonic, mode).map(|opcode| (opcode, mode)))
+        .ok_or_else(|| format!("{} does not support operand '{}'", mnemonic, operand))?;
+    Ok(move |line| Item::Instruction {
+        line,
+        opcode,
+        mode,
+        operand: expr,
+    })
+}
+
+// Only values already known to be below $100 use zero-page modes, so the
+// instruction size never changes between the two passes
+fn fits_zero_page(expr: &Expr, labels: &HashMap<String, u16>) -> bool {
+    expr.part != Part::Full || matches!(expr.value(labels), Some(value) if value <= 0xFF)
+}
+
+fn strip_suffix_ci<'a>(text: &'a str, suffix: &str) -> Option<&'a str> {
+    let split = text.len().checked_sub(suffix.len())?;
+    let (head, tail) = (text.get(..split)?, text.get(split..)?);
+    tail.eq_ignore_ascii_case(suffix).then_some(head)
+}
+
+fn parse_list(text: &str) -> Result<Vec<Expr>, String> {
+    text.split(',')
+        .map(|item| parse_expr(item.trim()))
+        .collect()
+}
+
+// Parse [<|>] (number | -number | label) [(+|-) number]
+fn parse_expr(text: &str) -> Result<Expr, String> {
+    let text = text.trim();
+    let (part, text) = match text.chars().next() {
+        Some('<') => (Part::Low, &text[1..]),
+        Some('>') => (Part::High, &text[1..]),
+        _ => (Part::Full, text),
+    };
+    if let Some(rest) = text.strip_prefix('-') {
+        // -N is 0 - N, so it wraps like any other offset
+        return match parse_expr(rest)? {
+            Expr {
+                term: Term::Number(n),
+                offset,
+                part: Part::Full,
+            } if !rest.trim_start().starts_with('-') => Ok(Expr {
+                term: Term::Number(0),
+                offset: offset - n as i32,
+                part,
+            }),
+            _ => Err(format!("bad number '-{}'", rest)),
+        };
+    }
+    let (base, offset) = match text.find(['+', '-']) {
+        Some(i) if i > 0 => {
+            let amount = parse_number(text[i + 1..].trim())? as i32;
+            let offset = if &text[i..i + 1] == "-" {
+                -amount
+            } else {
+                amount
+            };
+            (text[..i].trim(), offset)
+        }
+        _ => (text, 0),
+    };
+    let term = if is_identifier(base) {
+        Term::Label(base.to_string())
+    } else {
+        Term::Number(parse_number(base)?)
+    };
+    Ok(Expr { term, offset, part })
+}
+
+fn parse_number(text: &str) -> Result<u16, String> {
+    let parsed = if let Some(hex) = text.strip_prefix('$') {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(bin) = text.strip_prefix('%') {
+        u16::from_str_radix(bin, 2)
+    } else {
+        text.parse()
+    };
+    parsed.map_err(|_| format!("bad number '{}'", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        assemble(source, 0x8000).unwrap().bytes
+    }
+
+    fn error_line(source: &str) -> usize {
+        assemble(source, 0x8000).unwrap_err().line
+    }
+
+    #[test]
+    fn picks_zero_page_only_for_known_small_values() {
+        assert_eq!(bytes("LDA $10"), [0xA5, 0x10]);
+        assert_eq!(bytes("LDA $1234"), [0xAD, 0x34, 0x12]);
+        assert_eq!(bytes("LDA $10,X"), [0xB5, 0x10]);
+        assert_eq!(bytes("LDX $10,Y"), [0xB6, 0x10]);
+        // LDA has no zero page,Y form
+        assert_eq!(bytes("LDA $10,Y"), [0xB9, 0x10, 0x00]);
+        // A label defined earlier below $100 is zero page
+        assert_eq!(
+            bytes(".org $8000\nLDA <here\nhere: NOP"),
+            [0xA5, 0x02, 0xEA]
+        );
+    }
+
+    #[test]
+    fn forward_labels_are_absolute() {
+        // `data` is unknown in the first pass, so it gets the 3-byte form
+        // even though it resolves to a small offset
+        let program = assemble("LDA data\nRTS\ndata: .byte 7", 0x0000).unwrap();
+        assert_eq!(program.bytes, [0xAD, 0x04, 0x00, 0x60, 0x07]);
+        assert_eq!(program.labels["data"], 0x0004);
+    }
+
+    #[test]
+    fn low_and_high_bytes() {
+        let source = "LDA #<table\nLDX #>table\nLDY #>table+$100\ntable: .byte 0";
+        assert_eq!(bytes(source), [0xA9, 0x06, 0xA2, 0x80, 0xA0, 0x81, 0x00]);
+        assert_eq!(bytes(".word table\ntable:"), [0x02, 0x80]);
+    }
+
+    #[test]
+    fn branches_reach_128_bytes_back_and_127_forward() {
+        assert_eq!(bytes("loop: BNE loop"), [0xD0, 0xFE]);
+        assert_eq!(bytes("BEQ next\nNOP\nnext: NOP"), [0xF0, 0x01, 0xEA, 0xEA]);
+
+        let far_forward = "BNE far\n.org $8081\nfar: NOP";
+        assert_eq!(bytes(far_forward)[..2], [0xD0, 0x7F]);
+        assert_eq!(error_line("BNE far\n.org $8082\nfar: NOP"), 1);
+
+        let far_back = "back: NOP\n.org $807E\nBNE back";
+        assert_eq!(bytes(far_back)[0x7E..], [0xD0, 0x80]);
+        assert_eq!(error_line("back: NOP\n.org $807F\nBNE back"), 3);
+    }
+
+    #[test]
+    fn org_fills_forward_and_rejects_going_back() {
+        assert_eq!(bytes("NOP\n.org $8004\nNOP"), [0xEA, 0, 0, 0, 0xEA]);
+        assert_eq!(error_line("NOP\nNOP\n.org $8001"), 3);
+        assert_eq!(error_line(".org later\nlater:"), 1);
+    }
+
+    #[test]
+    fn byte_values_must_fit() {
+        assert_eq!(bytes(".byte 255, $80, %1"), [0xFF, 0x80, 0x01]);
+        assert_eq!(error_line("NOP\n.byte 256"), 2);
+        assert_eq!(error_line("LDA #$100"), 1);
+        // .word has room for the full value
+        assert_eq!(bytes(".word 256"), [0x00, 0x01]);
+    }
+
+    #[test]
+    fn negative_numbers_are_twos_complement() {
+        assert_eq!(bytes("LDA #-1"), [0xA9, 0xFF]);
+        assert_eq!(bytes("LDX #-128"), [0xA2, 0x80]);
+        assert_eq!(bytes(".byte -2, -1+2"), [0xFE, 0x01]);
+        assert_eq!(bytes(".word -1"), [0xFF, 0xFF]);
+        assert_eq!(error_line("LDA #-129"), 1);
+        assert_eq!(error_line("LDA #--1"), 1);
+        assert_eq!(error_line("LDA #-label"), 1);
+    }
+
+    #[test]
+    fn reports_unknown_names() {
+        assert_eq!(error_line("NOP\nFOO"), 2);
+        assert_eq!(error_line("JMP nowhere"), 1);
+        assert_eq!(error_line("a: NOP\na: NOP"), 2);
+    }
+}
@@ -0,0 +1,484 @@
+use crate::patch::{self, PatchError};
+use crate::region::Region;
+use std::fmt;
+
+// Size units used by the iNES header
+pub const PRG_BANK_SIZE: usize = 16 * 1024;
+pub const CHR_BANK_SIZE: usize = 8 * 1024;
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+
+// Nametable mirroring selected by the cartridge
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+// Whether pattern tables come from ROM or from RAM on the board
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChrKind {
+    Rom,
+    Ram,
+}
+
+// How forgiving the iNES parser is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    // Reject dirty headers and files whose size does not match the header
+    Strict,
+    // Accept real-world dumps: pad truncated data with zeros, ignore extra
+    // bytes at the end, and ignore bytes 7-15 when an iNES 1.0 header has
+    // garbage (like "DiskDude!") in its unused bytes
+    #[default]
+    Lenient,
+}
+
+// What lenient parsing did about a file of the wrong size
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SizeRecovery {
+    // Missing PRG/CHR data was filled with zeros
+    ZeroPadded,
+    // Bytes after the last declared bank were dropped
+    IgnoredTrailingData,
+    // The file had 512 extra bytes and no trainer flag, and only reading
+    // them as a trainer gave a sensible reset vector; they were loaded as
+    // a trainer
+    UnflaggedTrainer,
+    // The trainer flag was set but the file is 512 bytes short; the flag
+    // was ignored
+    MissingTrainer,
+}
+
+// A file size that did not match the header, for front-ends to report
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeMismatch {
+    // Size the header declares, including header and trainer
+    pub expected: usize,
+    pub actual: usize,
+    pub recovery: SizeRecovery,
+}
+
+impl fmt::Display for SizeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.recovery {
+            SizeRecovery::ZeroPadded => "padded the missing data with zeros",
+            SizeRecovery::IgnoredTrailingData => "ignored the extra data",
+            SizeRecovery::UnflaggedTrainer => "loaded the extra 512 bytes as a trainer",
+            SizeRecovery::MissingTrainer => "ignored the trainer flag",
+        };
+        write!(
+            f,
+            "header declares {} bytes, file has {}; {}",
+            self.expected, self.actual, action
+        )
+    }
+}
+
+// Reasons an iNES image can be rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CartridgeError {
+    // The file does not start with "NES<EOF>"
+    BadMagic,
+    // The file is shorter than the header says it should be
+    Truncated { expected: usize, actual: usize },
+    // Unused iNES 1.0 header bytes are not zero (strict mode only)
+    DirtyHeader,
+    // The file has data after the last declared bank (strict mode only)
+    TrailingData { expected: usize, actual: usize },
+    // The board needs a mapper that is not emulated
+    UnsupportedMapper(u8),
+    // A soft patch could not be applied
+    Patch(PatchError),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::BadMagic => write!(f, "not an iNES file (bad magic)"),
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "file is truncated: header declares {} bytes, found {}",
+                expected, actual
+            ),
+            CartridgeError::DirtyHeader => write!(f, "header has garbage in unused bytes"),
+            CartridgeError::TrailingData { expected, actual } => write!(
+                f,
+                "file has extra data: header declares {} bytes, found {}",
+                expected, actual
+            ),
+            CartridgeError::UnsupportedMapper(mapper) => {
+                write!(f, "mapper {} is not supported", mapper)
+            }
+            CartridgeError::Patch(e) => write!(f, "cannot apply patch: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+// Where a CPU address lands in PRG-ROM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrgLocation {
+    // 16KB bank number
+    pub bank: usize,
+    // Offset inside that bank
+    pub offset: usize,
+    // Offset from the start of PRG-ROM (without header or trainer)
+    pub rom_offset: usize,
+}
+
+// A cartridge loaded from an iNES image
+#[derive(Clone, Debug)]
+pub struct Cartridge {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub trainer: Option<Vec<u8>>,
+    pub mapper: u8,
+    pub mirroring: Mirroring,
+    pub has_battery: bool,
+    // True for NES 2.0 headers
+    pub nes2: bool,
+    // Timing declared by the header, if it names exactly one region
+    pub header_region: Option<Region>,
+    // CHR-RAM size in bytes, used when there is no CHR-ROM
+    pub chr_ram_size: usize,
+    // Set when the file size did not match the header and was repaired
+    pub size_mismatch: Option<SizeMismatch>,
+}
+
+impl Cartridge {
+    // Parse an iNES image leniently
+    pub fn from_ines(bytes: &[u8]) -> Result<Cartridge, CartridgeError> {
+        Cartridge::parse(bytes, ParseMode::default())
+    }
+
+    // Parse an iNES image with the given strictness
+    pub fn parse(bytes: &[u8], mode: ParseMode) -> Result<Cartridge, CartridgeError> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != b"NES\x1A" {
+            return Err(CartridgeError::BadMagic);
+        }
+
+        // Old dumping tools wrote their name over bytes 7-15, which makes
+        // the high mapper nibble and the region bits meaningless
+        let nes2 = bytes[7] & 0b0000_1100 == 0b0000_1000;
+        let dirty = !nes2 && bytes[12..16].iter().any(|&b| b != 0);
+        if dirty && mode == ParseMode::Strict {
+            return Err(CartridgeError::DirtyHeader);
+        }
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&bytes[..HEADER_SIZE]);
+        if dirty {
+            header[7..].fill(0);
+        }
+
+        let prg_size = bytes[4] as usize * PRG_BANK_SIZE;
+        let chr_size = bytes[5] as usize * CHR_BANK_SIZE;
+        let flags6 = header[6];
+        let flags7 = header[7];
+        let mut has_trainer = flags6 & 0b0000_0100 != 0;
+
+        let expected =
+            HEADER_SIZE + if has_trainer { TRAINER_SIZE } else { 0 } + prg_size + chr_size;
+        let actual = bytes.len();
+        if mode == ParseMode::Strict && actual < expected {
+            return Err(CartridgeError::Truncated { expected, actual });
+        }
+        if mode == ParseMode::Strict && actual > expected {
+            return Err(CartridgeError::TrailingData { expected, actual });
+        }
+
+        // A file one trainer short most likely has the trainer flag wrong.
+        // One trainer too long is more often junk at the end, so the extra
+        // bytes are only taken as a trainer when the PRG-ROM makes sense
+        // that way and not the other. Anything else is padded or ignored.
+        let size_mismatch = (actual != expected).then(|| {
+            let recovery = if !has_trainer
+                && actual == expected + TRAINER_SIZE
+                && !plausible_reset_vector(bytes, HEADER_SIZE, prg_size)
+                && plausible_reset_vector(bytes, HEADER_SIZE + TRAINER_SIZE, prg_size)
+            {
+                has_trainer = true;
+                SizeRecovery::UnflaggedTrainer
+            } else if has_trainer && actual + TRAINER_SIZE == expected {
+                has_trainer = false;
+                SizeRecovery::MissingTrainer
+            } else if actual < expected {
+                SizeRecovery::ZeroPadded
+            } else {
+                SizeRecovery::IgnoredTrailingData
+            };
+            SizeMismatch {
+                expected,
+                actual,
+                recovery,
+            }
+        });
+        let prg_start = HEADER_SIZE + if has_trainer { TRAINER_SIZE } else { 0 };
+        let chr_start = prg_start + prg_size;
+        let expected = chr_start + chr_size;
+
+        // Zero-fill whatever is missing from a truncated file
+        let padded;
+        let bytes = if bytes.len() < expected {
+            padded = [bytes, &vec![0; expected - bytes.len()]].concat();
+            &padded
+        } else {
+            bytes
+        };
+
+        // NES 2.0 stores the CPU/PPU timing in byte 12. iNES 1.0 only has a
+        // PAL bit in byte 9, which is trusted only when the unused bytes are
+        // clean.
+        let header_region = if nes2 {
+            match header[12] & 0b11 {
+                0 => Some(Region::Ntsc),
+                1 => Some(Region::Pal),
+                3 => Some(Region::Dendy),
+                _ => None,
+            }
+        } else if header[11..16].iter().all(|&b| b == 0) && header[9] & 1 != 0 {
+            Some(Region::Pal)
+        } else {
+            None
+        };
+
+        // NES 2.0 gives the CHR-RAM size as a shift count in byte 11
+        let chr_ram_size = match header[11] & 0x0F {
+            shift if nes2 && shift != 0 => 64 << shift,
+            _ if nes2 => 0,
+            _ => CHR_BANK_SIZE,
+        };
+
+        let mirroring = if flags6 & 0b0000_1000 != 0 {
+            Mirroring::FourScreen
+        } else if flags6 & 0b0000_0001 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        Ok(Cartridge {
+            prg_rom: bytes[prg_start..chr_start].to_vec(),
+            chr_rom: bytes[chr_start..expected].to_vec(),
+            trainer: has_trainer.then(|| bytes[HEADER_SIZE..prg_start].to_vec()),
+            mapper: (flags7 & 0xF0) | (flags6 >> 4),
+            mirroring,
+            has_battery: flags6 & 0b0000_0010 != 0,
+            nes2,
+            header_region,
+            chr_ram_size,
+            size_mismatch,
+        })
+    }
+
+    // Apply an IPS or BPS patch to the image in memory, then parse it
+    pub fn from_ines_with_patch(bytes: &[u8], patch: &[u8]) -> Result<Cartridge, CartridgeError> {
+        let patched = patch::apply(bytes, patch).map_err(CartridgeError::Patch)?;
+        Cartridge::from_ines(&patched)
+    }
+
+    // Whether the board has CHR-ROM or CHR-RAM
+    pub fn chr_kind(&self) -> ChrKind {
+        if self.chr_rom.is_empty() {
+            ChrKind::Ram
+        } else {
+            ChrKind::Rom
+        }
+    }
+
+    // Size of CHR memory in bytes. Boards without CHR-ROM get 8KB of
+    // CHR-RAM unless a NES 2.0 header says otherwise.
+    pub fn chr_len(&self) -> usize {
+        match self.chr_kind() {
+            ChrKind::Rom => self.chr_rom.len(),
+            ChrKind::Ram => self.chr_ram_size,
+        }
+    }
+
+    // Physical 1KB CHR bank mapped into each 1KB slot of $0000-$1FFF, for
+    // pattern table viewers. Only NROM is supported, which maps the first
+    // 8KB straight through; other mappers return None.
+    pub fn chr_bank_layout(&self) -> Option<[usize; 8]> {
+        (self.mapper == 0).then(|| std::array::from_fn(|slot| slot))
+    }
+
+    // Map a CPU address to its PRG-ROM bank and offset, so traces and
+    // debuggers can show which physical ROM byte is executing. Only NROM
+    // is supported, since no other mapper exists yet; others return None.
+    pub fn resolve_prg_address(&self, cpu_addr: u16) -> Option<PrgLocation> {
+        if self.mapper != 0 || cpu_addr < 0x8000 || self.prg_rom.is_empty() {
+            return None;
+        }
+        // A 16KB NROM is mirrored into both halves of $8000-$FFFF
+        let rom_offset = (cpu_addr as usize - 0x8000) % self.prg_rom.len();
+        Some(PrgLocation {
+            bank: rom_offset / PRG_BANK_SIZE,
+            offset: rom_offset % PRG_BANK_SIZE,
+            rom_offset,
+        })
+    }
+}
+
+// Whether PRG-ROM starting at `prg_start` has a reset vector pointing
+// into $8000-$FFFE, as any bootable image needs. Padding is usually $00 or
+// $FF, so $FFFF counts as implausible.
+fn plausible_reset_vector(bytes: &[u8], prg_start: usize, prg_size: usize) -> bool {
+    let Some(at) = (prg_start + prg_size).checked_sub(4) else {
+        return false;
+    };
+    match bytes.get(at..at + 2) {
+        Some(&[lo, hi]) => (0x8000..0xFFFF).contains(&u16::from_le_bytes([lo, hi])),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An iNES 1.0 image with numbered PRG banks and the reset vector at
+    // $8000, followed by `extra` bytes of $EE
+    fn image(prg_banks: u8, chr_banks: u8, flags6: u8, extra: usize) -> Vec<u8> {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, prg_banks, chr_banks, flags6];
+        rom.resize(HEADER_SIZE, 0);
+        if flags6 & 0b0000_0100 != 0 {
+            rom.extend(vec![0x77; TRAINER_SIZE]);
+        }
+        for bank in 0..prg_banks {
+            let mut prg = vec![bank; PRG_BANK_SIZE];
+            prg[PRG_BANK_SIZE - 4..PRG_BANK_SIZE - 2].copy_from_slice(&[0x00, 0x80]);
+            rom.extend(prg);
+        }
+        rom.extend(vec![0xCC; chr_banks as usize * CHR_BANK_SIZE]);
+        rom.extend(vec![0xEE; extra]);
+        rom
+    }
+
+    const EXACT: usize = HEADER_SIZE + PRG_BANK_SIZE + CHR_BANK_SIZE;
+
+    #[test]
+    fn parses_a_clean_header_in_both_modes() {
+        let rom = image(1, 1, 0b0000_0011, 0);
+        for mode in [ParseMode::Strict, ParseMode::Lenient] {
+            let cart = Cartridge::parse(&rom, mode).unwrap();
+            assert_eq!(cart.prg_rom.len(), PRG_BANK_SIZE);
+            assert_eq!(cart.chr_rom.len(), CHR_BANK_SIZE);
+            assert_eq!(cart.mirroring, Mirroring::Vertical);
+            assert!(cart.has_battery);
+            assert_eq!(cart.size_mismatch, None);
+        }
+        assert_eq!(
+            Cartridge::parse(b"NES\x00 not a rom....", ParseMode::Lenient).unwrap_err(),
+            CartridgeError::BadMagic
+        );
+    }
+
+    #[test]
+    fn dirty_header_is_rejected_or_ignored() {
+        let mut rom = image(1, 1, 0x10, 0);
+        // High mapper nibble and a PAL bit under a dumper's signature
+        rom[7] = 0x40;
+        rom[9] = 0x01;
+        rom[12..16].copy_from_slice(b"Dude");
+        assert_eq!(
+            Cartridge::parse(&rom, ParseMode::Strict).unwrap_err(),
+            CartridgeError::DirtyHeader
+        );
+        let cart = Cartridge::parse(&rom, ParseMode::Lenient).unwrap();
+        assert_eq!(cart.mapper, 1);
+        assert_eq!(cart.header_region, None);
+    }
+
+    #[test]
+    fn truncated_file_is_rejected_or_padded() {
+        let mut rom = image(1, 1, 0, 0);
+        rom.truncate(EXACT - 100);
+        assert_eq!(
+            Cartridge::parse(&rom, ParseMode::Strict).unwrap_err(),
+            CartridgeError::Truncated {
+                expected: EXACT,
+                actual: EXACT - 100
+            }
+        );
+        let cart = Cartridge::parse(&rom, ParseMode::Lenient).unwrap();
+        assert_eq!(cart.chr_rom.len(), CHR_BANK_SIZE);
+        assert_eq!(cart.chr_rom[CHR_BANK_SIZE - 100..], [0; 100]);
+        assert_eq!(
+            cart.size_mismatch.map(|m| m.recovery),
+            Some(SizeRecovery::ZeroPadded)
+        );
+    }
+
+    #[test]
+    fn trailing_data_is_rejected_or_dropped() {
+        let rom = image(1, 1, 0, 100);
+        assert_eq!(
+            Cartridge::parse(&rom, ParseMode::Strict).unwrap_err(),
+            CartridgeError::TrailingData {
+                expected: EXACT,
+                actual: EXACT + 100
+            }
+        );
+        let cart = Cartridge::parse(&rom, ParseMode::Lenient).unwrap();
+        assert_eq!(cart.chr_rom, vec![0xCC; CHR_BANK_SIZE]);
+        assert_eq!(
+            cart.size_mismatch.map(|m| m.recovery),
+            Some(SizeRecovery::IgnoredTrailingData)
+        );
+    }
+
+    fn recovery(rom: &[u8]) -> Option<SizeRecovery> {
+        let cart = Cartridge::parse(rom, ParseMode::Lenient).unwrap();
+        cart.size_mismatch.map(|m| m.recovery)
+    }
+
+    #[test]
+    fn unflagged_trainer_needs_a_shifted_reset_vector() {
+        // A trainer without its flag. Read without the trainer, the vector
+        // comes from the middle of PRG-ROM, here $0000.
+        let mut rom = image(1, 1, 0b0000_0100, 0);
+        rom[6] = 0;
+        let vector = HEADER_SIZE + PRG_BANK_SIZE - 4;
+        rom[vector..vector + 2].copy_from_slice(&[0x00, 0x00]);
+        assert_eq!(recovery(&rom), Some(SizeRecovery::UnflaggedTrainer));
+        let cart = Cartridge::from_ines(&rom).unwrap();
+        assert_eq!(cart.trainer, Some(vec![0x77; TRAINER_SIZE]));
+        assert_eq!(
+            cart.prg_rom[PRG_BANK_SIZE - 4..PRG_BANK_SIZE - 2],
+            [0x00, 0x80]
+        );
+    }
+
+    #[test]
+    fn extra_512_bytes_with_a_good_vector_are_trailing_data() {
+        let rom = image(1, 1, 0, TRAINER_SIZE);
+        assert_eq!(recovery(&rom), Some(SizeRecovery::IgnoredTrailingData));
+        let cart = Cartridge::from_ines(&rom).unwrap();
+        assert_eq!(cart.trainer, None);
+        assert_eq!(cart.prg_rom[..4], [0; 4]);
+    }
+
+    #[test]
+    fn trainer_flag_on_a_file_one_trainer_short_is_dropped() {
+        let mut rom = image(1, 1, 0, 0);
+        rom[6] = 0b0000_0100;
+        assert_eq!(recovery(&rom), Some(SizeRecovery::MissingTrainer));
+        let cart = Cartridge::from_ines(&rom).unwrap();
+        assert_eq!(cart.trainer, None);
+        assert_eq!(cart.chr_rom, vec![0xCC; CHR_BANK_SIZE]);
+    }
+
+    #[test]
+    fn other_size_errors_pad_or_ignore() {
+        let mut short = image(1, 1, 0, 0);
+        short.truncate(EXACT - TRAINER_SIZE);
+        assert_eq!(recovery(&short), Some(SizeRecovery::ZeroPadded));
+        assert_eq!(
+            recovery(&image(1, 1, 0, 1)),
+            Some(SizeRecovery::IgnoredTrailingData)
+        );
+        assert_eq!(recovery(&image(1, 1, 0, 0)), None);
+    }
+}
@@ -0,0 +1,111 @@
+// Opcode coverage for validation runs: which opcodes a test ROM executed
+// and which addressing modes took the page-crossing penalty, so gaps in
+// what the ROM exercised are easy to spot
+use crate::opcodes::{AddressingMode, OPCODES};
+use std::fmt::Write;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coverage {
+    executed: [u64; 256],
+    page_crossed: [u64; 256],
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage {
+            executed: [0; 256],
+            page_crossed: [0; 256],
+        }
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    // Count one execution of an opcode
+    #[inline]
+    pub fn record(&mut self, opcode: u8, page_crossed: bool) {
+        self.executed[opcode as usize] += 1;
+        if page_crossed {
+            self.page_crossed[opcode as usize] += 1;
+        }
+    }
+
+    // Times an opcode was executed
+    pub fn executed(&self, opcode: u8) -> u64 {
+        self.executed[opcode as usize]
+    }
+
+    // Times an opcode paid the page-crossing penalty
+    pub fn page_crossed(&self, opcode: u8) -> u64 {
+        self.page_crossed[opcode as usize]
+    }
+
+    // Opcodes never executed, official ones only if asked
+    pub fn missing(&self, official_only: bool) -> Vec<u8> {
+        (0..=255u8)
+            .filter(|&op| self.executed(op) == 0)
+            .filter(|&op| !official_only || OPCODES[op as usize].official)
+            .collect()
+    }
+
+    // Addressing modes with a page-crossing penalty that never paid it
+    pub fn modes_without_page_cross(&self) -> Vec<AddressingMode> {
+        let mut modes = Vec::new();
+        for info in OPCODES.iter() {
+            if info.page_cross_penalty && !modes.contains(&info.mode) {
+                modes.push(info.mode);
+            }
+        }
+        modes.retain(|&mode| {
+            !OPCODES
+                .iter()
+                .enumerate()
+                .any(|(op, info)| info.mode == mode && self.page_crossed[op] > 0)
+        });
+        modes
+    }
+
+    pub fn clear(&mut self) {
+        *self = Coverage::default();
+    }
+
+    // Human-readable summary of the run
+    pub fn report(&self) -> String {
+        let count = |official: bool| {
+            let total = OPCODES.iter().filter(|op| op.official == official).count();
+            let hit = (0..256)
+                .filter(|&op| OPCODES[op].official == official && self.executed[op] > 0)
+                .count();
+            (hit, total)
+        };
+        let (official_hit, official_total) = count(true);
+        let (unofficial_hit, unofficial_total) = count(false);
+
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
+            "official opcodes: {}/{}",
+            official_hit, official_total
+        );
+        let _ = writeln!(
+            text,
+            "unofficial opcodes: {}/{}",
+            unofficial_hit, unofficial_total
+        );
+        for op in self.missing(true) {
+            let info = &OPCODES[op as usize];
+            let _ = writeln!(
+                text,
+                "  missing ${:02X} {} {:?}",
+                op, info.mnemonic, info.mode
+            );
+        }
+        for mode in self.modes_without_page_cross() {
+            let _ = writeln!(text, "  no page crossing in {:?}", mode);
+        }
+        text
+    }
+}
@@ -1,9 +1,64 @@
+#[cfg(feature = "audio")]
+use crate::apu::Apu;
+#[cfg(feature = "debugger")]
+use crate::coverage::Coverage;
+use crate::hash::{fnv1a, fnv1a_continue};
+use crate::input::ControllerPorts;
+use crate::opcodes::{AddressingMode, Instruction, Opcode, OPCODES};
+use crate::rng::EmuRng;
+use crate::watch::{WriteEntry, WriteLog};
+use std::ops::RangeInclusive;
+
 // Define the status flags
-const CARRY: u8 = 0b0000_0001;
-const ZERO: u8 = 0b0000_0010;
-const INTERRUPT_DISABLE: u8 = 0b0000_0100;
-const OVERFLOW: u8 = 0b0100_0000;
-const NEGATIVE: u8 = 0b1000_0000;
+pub const CARRY: u8 = 0b0000_0001;
+pub const ZERO: u8 = 0b0000_0010;
+pub const INTERRUPT_DISABLE: u8 = 0b0000_0100;
+pub const DECIMAL: u8 = 0b0000_1000;
+pub const BREAK: u8 = 0b0001_0000;
+pub const UNUSED: u8 = 0b0010_0000;
+pub const OVERFLOW: u8 = 0b0100_0000;
+pub const NEGATIVE: u8 = 0b1000_0000;
+
+// JAM opcode that calls the host trap instead, when one is installed
+pub const HOST_TRAP_OPCODE: u8 = 0x02;
+
+// Host callback run by the trap opcode, semihosting style. PC already
+// points past the opcode when it runs.
+pub type HostTrap = Box<dyn FnMut(&mut Cpu6502)>;
+
+// The address space of a machine other than the NES, for running the core
+// against your own memory map. Reads take &self like Cpu6502::read, so a
+// device whose reads have side effects keeps its state in a Cell, the way
+// ControllerPorts does.
+pub trait CpuBus {
+    fn read(&self, addr: u16) -> u8;
+
+    // Read without side effects, for debuggers and tracing
+    fn peek(&self, addr: u16) -> u8 {
+        self.read(addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8);
+}
+
+// How a stack frame was pushed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    // A JSR return address
+    Subroutine,
+    // Status and return address pushed by BRK, NMI or IRQ
+    Interrupt,
+}
+
+// One entry of a guessed call stack
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackFrame {
+    pub kind: FrameKind,
+    // Stack address of the lowest byte of the frame
+    pub stack_addr: u16,
+    // Where RTS or RTI will continue
+    pub return_addr: u16,
+}
 
 // Define the CPU module and its implementation
 pub struct Cpu6502 {
@@ -15,8 +70,65 @@ pub struct Cpu6502 {
     pub pc: u16,
     pub status: u8,
 
+    // Address of the instruction currently executing
+    pub instruction_pc: u16,
+
     // Memory (64KB)
     pub memory: [u8; 65536],
+
+    // Total CPU cycles executed
+    pub cycles: u64,
+
+    // Set when a JAM opcode halts the CPU
+    pub jammed: bool,
+
+    // Honor the DECIMAL flag in ADC/SBC. The NES 2A03 has BCD arithmetic
+    // disconnected, so this is off by default; turn it on to use the core
+    // as a generic NMOS 6502.
+    pub decimal_mode: bool,
+
+    // Active write trackers, indexed by the handle from track_writes
+    write_logs: Vec<Option<WriteLog>>,
+
+    // Addresses held at a fixed value, as (address, value)
+    freezes: Vec<(u16, u8)>,
+
+    // Controllers on $4016/$4017. When None those addresses are plain
+    // memory, as on a bare 6502.
+    pub controllers: Option<ControllerPorts>,
+
+    // Audio unit on $4000-$4017, plain memory when None
+    #[cfg(feature = "audio")]
+    pub apu: Option<Apu>,
+
+    // Called for HOST_TRAP_OPCODE instead of jamming, when set
+    pub host_trap: Option<HostTrap>,
+
+    // Opcode coverage, recorded only while Some
+    #[cfg(feature = "debugger")]
+    pub coverage: Option<Box<Coverage>>,
+
+    // Source of all nondeterminism (power-on RAM contents, ...)
+    pub rng: EmuRng,
+
+    // When set, every read and write goes here instead of `memory` and the
+    // NES devices. Freezes and write tracking still work; state_hash,
+    // stack_contents and boot_headless only see `memory`.
+    pub bus: Option<Box<dyn CpuBus>>,
+}
+
+// Reading a write-only register returns whatever was last on the data
+// bus. That is not tracked; for the absolute addressing nearly all I/O
+// reads use, it is the high byte of the address.
+#[cfg(feature = "audio")]
+fn open_bus(addr: u16) -> u8 {
+    (addr >> 8) as u8
+}
+
+impl Default for Cpu6502 {
+    fn default() -> Self {
+        Cpu6502::new()
+    }
 }
 
 // Implementation of the CPU
@@ -29,10 +141,84 @@ impl Cpu6502 {
             sp: 0xFD,
             pc: 0x8000,
             status: 0x24,
+            instruction_pc: 0x8000,
             memory: [0; 65536],
+            cycles: 0,
+            jammed: false,
+            decimal_mode: false,
+            write_logs: Vec::new(),
+            freezes: Vec::new(),
+            #[cfg(feature = "debugger")]
+            coverage: None,
+            host_trap: None,
+            controllers: None,
+            #[cfg(feature = "audio")]
+            apu: None,
+            rng: EmuRng::default(),
+            bus: None,
         }
     }
 
+    // Create a CPU whose internal RAM ($0000-$07FF) is filled with random
+    // power-on contents derived from the given seed
+    pub fn with_seed(seed: u64) -> Self {
+        let mut cpu = Cpu6502::new();
+        cpu.rng = EmuRng::new(seed);
+        cpu.randomize_ram();
+        cpu
+    }
+
+    // Fill the internal RAM with values from the RNG, like a cold power-on
+    pub fn randomize_ram(&mut self) {
+        self.rng.fill(&mut self.memory[0x0000..0x0800]);
+    }
+
+    // Copy PRG-ROM into $8000-$FFFF, mirroring a 16KB ROM into both halves
+    pub fn load_prg_rom(&mut self, prg: &[u8]) {
+        for (i, byte) in self.memory[0x8000..].iter_mut().enumerate() {
+            *byte = prg.get(i % prg.len().max(1)).copied().unwrap_or(0);
+        }
+    }
+
+    // Map a flat program at `origin` and start running it, without needing
+    // an iNES image. The reset vector is pointed at `origin` unless the
+    // program supplies its own vectors.
+    pub fn boot_headless(&mut self, program: &[u8], origin: u16) {
+        let start = origin as usize;
+        let end = start + program.len();
+        assert!(
+            end <= self.memory.len(),
+            "program does not fit below $10000"
+        );
+        self.memory[start..end].copy_from_slice(program);
+        if end <= 0xFFFC {
+            self.write_word(0xFFFC, origin);
+        }
+        self.power_on();
+    }
+
+    // Start running from the reset vector with the power-on register state:
+    // A, X and Y cleared, SP at $FD and IRQs masked
+    pub fn power_on(&mut self) {
+        self.a = 0;
+        self.x = 0;
+        self.y = 0;
+        self.sp = 0xFD;
+        self.status = 0x34;
+        self.pc = self.read_word(0xFFFC);
+        self.cycles = 7;
+        self.jammed = false;
+    }
+
+    // Pull the RESET line: the stack pointer drops by 3 and IRQs are masked
+    pub fn reset(&mut self) {
+        self.sp = self.sp.wrapping_sub(3);
+        self.set_status_flag(INTERRUPT_DISABLE);
+        self.pc = self.read_word(0xFFFC);
+        self.cycles += 7;
+        self.jammed = false;
+    }
+
     // Set a status flag
     fn set_status_flag(&mut self, flag: u8) {
         self.status |= flag;
@@ -44,7 +230,7 @@ impl Cpu6502 {
     }
 
     // Check if a status flag is set
-    fn is_status_flag_set(&self, flag: u8) -> bool {
+    pub fn is_status_flag_set(&self, flag: u8) -> bool {
         self.status & flag != 0
     }
 
@@ -135,25 +321,87 @@ impl Cpu6502 {
     // Arithmetic instructions
     // Add with CARRY
     pub fn adc(&mut self, value: u8) {
+        if self.decimal_mode && self.is_status_flag_set(DECIMAL) {
+            self.adc_decimal(value);
+            return;
+        }
         let result = self.a as u16 + value as u16 + (self.status & CARRY) as u16;
-        self.clear_status_flag(CARRY);
+        self.clear_status_flag(CARRY | OVERFLOW);
         if result > 0xFF {
             self.set_status_flag(CARRY);
         }
+        // Overflow when both inputs have the same sign and the result differs
+        if (self.a ^ result as u8) & (value ^ result as u8) & NEGATIVE != 0 {
+            self.set_status_flag(OVERFLOW);
+        }
         self.a = result as u8;
         self.update_zero_and_negative_flags(self.a);
     }
 
     // Subtract with CARRY
     pub fn sbc(&mut self, value: u8) {
+        let decimal = self.decimal_mode && self.is_status_flag_set(DECIMAL);
+        let a = self.a;
+        let borrow = 1 - (self.status & CARRY);
         let value = value ^ 0xFF;
         let result = self.a as u16 + value as u16 + (self.status & CARRY) as u16;
-        self.clear_status_flag(CARRY);
+        self.clear_status_flag(CARRY | OVERFLOW);
         if result > 0xFF {
             self.set_status_flag(CARRY);
         }
+        if (self.a ^ result as u8) & (value ^ result as u8) & NEGATIVE != 0 {
+            self.set_status_flag(OVERFLOW);
+        }
         self.a = result as u8;
         self.update_zero_and_negative_flags(self.a);
+
+        // In decimal mode the flags match the binary subtraction on an
+        // NMOS 6502, only the accumulator gets the BCD correction
+        if decimal {
+            let subtrahend = value ^ 0xFF;
+            let mut lo = (a & 0x0F) as i16 - (subtrahend & 0x0F) as i16 - borrow as i16;
+            let mut hi = (a >> 4) as i16 - (subtrahend >> 4) as i16;
+            if lo < 0 {
+                lo -= 6;
+                hi -= 1;
+            }
+            if hi < 0 {
+                hi -= 6;
+            }
+            self.a = ((hi << 4) | (lo & 0x0F)) as u8;
+        }
+    }
+
+    // Add with CARRY in BCD, following NMOS 6502 flag behavior: Z comes from
+    // the binary sum, N and V from the intermediate high digit
+    fn adc_decimal(&mut self, value: u8) {
+        let carry = self.status & CARRY;
+        let binary = self.a.wrapping_add(value).wrapping_add(carry);
+
+        let mut lo = (self.a & 0x0F) + (value & 0x0F) + carry;
+        if lo > 9 {
+            lo += 6;
+        }
+        let mut hi = (self.a >> 4) + (value >> 4) + (lo > 0x0F) as u8;
+
+        self.clear_status_flag(CARRY | OVERFLOW | ZERO | NEGATIVE);
+        if binary == 0 {
+            self.set_status_flag(ZERO);
+        }
+        let intermediate = hi << 4;
+        if intermediate & NEGATIVE != 0 {
+            self.set_status_flag(NEGATIVE);
+        }
+        if !(self.a ^ value) & (self.a ^ intermediate) & NEGATIVE != 0 {
+            self.set_status_flag(OVERFLOW);
+        }
+        if hi > 9 {
+            hi += 6;
+        }
+        if hi > 0x0F {
+            self.set_status_flag(CARRY);
+        }
+        self.a = (hi << 4) | (lo & 0x0F);
     }
 
     // Stack Instructions
@@ -169,33 +417,230 @@ impl Cpu6502 {
     }
 
     pub fn php(&mut self) {
-        let status_with_b_and_u_flags = self.status | 0b0011_0000;
+        let status_with_b_and_u_flags = self.status | BREAK | UNUSED;
         // bit 4 and 5 set
         self.push(status_with_b_and_u_flags);
     }
 
     // Pull the status register from the stack
+    // The B flag only exists on the stack and the unused flag always reads 1
     pub fn plp(&mut self) {
-        let pulled_status = self.pop();
-        let unused_flag_mask = !0b0010_0000;
-        self.status = (self.status & unused_flag_mask) | (pulled_status & !unused_flag_mask);
+        self.pull_status();
     }
 
     // These functions are used to read and write to memory
+    // Every u16 address is inside the 64KB array, so plain memory is a
+    // single index. $4000-$401F is checked first and goes to the
+    // controllers and APU through read_io/write_io. With an APU attached
+    // its write-only registers read as open bus. An attached bus takes
+    // over all of it.
     //  Read a byte from memory
+    #[inline]
     pub fn read(&self, addr: u16) -> u8 {
-        *self.memory.get(addr as usize).unwrap_or(&0)
+        if let Some(bus) = &self.bus {
+            return bus.read(addr);
+        }
+        if addr & 0xFFE0 == 0x4000 {
+            return self.read_io(addr, false);
+        }
+        self.memory[addr as usize]
+    }
+
+    // Read without side effects, for debuggers and tracing
+    #[inline]
+    pub fn peek(&self, addr: u16) -> u8 {
+        if let Some(bus) = &self.bus {
+            return bus.peek(addr);
+        }
+        if addr & 0xFFE0 == 0x4000 {
+            return self.read_io(addr, true);
+        }
+        self.memory[addr as usize]
+    }
+
+    // $4000-$401F, routed to the attached devices
+    #[cold]
+    fn read_io(&self, addr: u16, peek: bool) -> u8 {
+        #[cfg(feature = "audio")]
+        if let Some(apu) = &self.apu {
+            match addr {
+                0x4015 if peek => return apu.peek_status(),
+                0x4015 => return apu.read_status(),
+                // The other APU registers, OAM DMA and the test registers
+                // are write-only
+                0x4000..=0x4014 | 0x4018..=0x401F => return open_bus(addr),
+                _ => {}
+            }
+        }
+        match (addr, &self.controllers) {
+            (0x4016 | 0x4017, Some(ports)) if peek => ports.peek(addr as usize & 1),
+            (0x4016 | 0x4017, Some(ports)) => {
+                let port = addr as usize & 1;
+                let value = ports.read(port);
+                ports.record_read(self.cycles, self.instruction_pc, port, value);
+                value
+            }
+            _ => self.memory[addr as usize],
+        }
     }
 
     // Write a byte to memory
+    #[inline]
     pub fn write(&mut self, addr: u16, data: u8) {
+        if !self.write_logs.is_empty() {
+            self.record_write(addr, data);
+        }
+        self.store(addr, data);
+        if !self.freezes.is_empty() {
+            self.reapply_freeze(addr);
+        }
+    }
+
+    // A write without tracking or freezes
+    #[inline]
+    fn store(&mut self, addr: u16, data: u8) {
+        if let Some(bus) = &mut self.bus {
+            bus.write(addr, data);
+            return;
+        }
         self.memory[addr as usize] = data;
+        if addr & 0xFFE0 == 0x4000 {
+            self.write_io(addr, data);
+        }
+    }
+
+    #[cold]
+    fn write_io(&mut self, addr: u16, data: u8) {
+        if addr == 0x4016 {
+            if let Some(ports) = &mut self.controllers {
+                ports.write(data);
+            }
+        }
+        #[cfg(feature = "audio")]
+        if let Some(apu) = &mut self.apu {
+            apu.write(addr, data);
+        }
+    }
+
+    // Address freezing, the way trainers and cheats hold a value in RAM
+    // Hold addr at value. The value is written now and restored after
+    // every CPU write to the address.
+    pub fn freeze(&mut self, addr: u16, value: u8) {
+        self.unfreeze(addr);
+        self.freezes.push((addr, value));
+        self.set_frozen(addr, value);
+    }
+
+    // Release a frozen address, returning whether it was frozen
+    pub fn unfreeze(&mut self, addr: u16) -> bool {
+        let before = self.freezes.len();
+        self.freezes.retain(|&(frozen, _)| frozen != addr);
+        self.freezes.len() != before
+    }
+
+    pub fn clear_freezes(&mut self) {
+        self.freezes.clear();
+    }
+
+    // Frozen addresses and their values
+    pub fn freezes(&self) -> &[(u16, u8)] {
+        &self.freezes
+    }
+
+    #[cold]
+    fn reapply_freeze(&mut self, addr: u16) {
+        if let Some(&(_, value)) = self.freezes.iter().find(|&&(frozen, _)| frozen == addr) {
+            self.set_frozen(addr, value);
+        }
+    }
+
+    // Frozen values go straight to memory, skipping the NES devices
+    fn set_frozen(&mut self, addr: u16, value: u8) {
+        match &mut self.bus {
+            Some(bus) => bus.write(addr, value),
+            None => self.memory[addr as usize] = value,
+        }
+    }
+
+    // Memory write tracking
+    // Start logging writes to a range, keeping at most `capacity` entries.
+    // Returns a handle for write_log and stop_tracking.
+    pub fn track_writes(&mut self, range: RangeInclusive<u16>, capacity: usize) -> usize {
+        let log = Some(WriteLog::new(range, capacity));
+        match self.write_logs.iter().position(Option::is_none) {
+            Some(handle) => {
+                self.write_logs[handle] = log;
+                handle
+            }
+            None => {
+                self.write_logs.push(log);
+                self.write_logs.len() - 1
+            }
+        }
+    }
+
+    // Look at a tracker's log
+    pub fn write_log(&self, handle: usize) -> Option<&WriteLog> {
+        self.write_logs.get(handle)?.as_ref()
+    }
+
+    // Stop tracking and hand back the collected log
+    pub fn stop_tracking(&mut self, handle: usize) -> Option<WriteLog> {
+        let log = self.write_logs.get_mut(handle)?.take();
+        while matches!(self.write_logs.last(), Some(None)) {
+            self.write_logs.pop();
+        }
+        log
+    }
+
+    #[cold]
+    fn record_write(&mut self, addr: u16, data: u8) {
+        let entry = WriteEntry {
+            cycle: self.cycles,
+            pc: self.instruction_pc,
+            addr,
+            old: match &self.bus {
+                Some(bus) => bus.peek(addr),
+                None => self.memory[addr as usize],
+            },
+            new: data,
+        };
+        for log in self.write_logs.iter_mut().flatten() {
+            log.record(entry);
+        }
+    }
+
+    // 64-bit FNV-1a hash of the registers, cycle count and memory, plus
+    // the controllers and APU when attached. Equal hashes on two runs mean
+    // the machines are (almost certainly) in the same state, which is what
+    // replay and netplay desync checks compare.
+    pub fn state_hash(&self) -> u64 {
+        let registers = [self.a, self.x, self.y, self.sp, self.status];
+        let mut hash = fnv1a(&registers);
+        hash = fnv1a_continue(hash, &self.pc.to_le_bytes());
+        hash = fnv1a_continue(hash, &self.cycles.to_le_bytes());
+        hash = fnv1a_continue(hash, &self.memory);
+        if let Some(ports) = &self.controllers {
+            hash = fnv1a_continue(hash, &ports.state_hash().to_le_bytes());
+        }
+        #[cfg(feature = "audio")]
+        if let Some(apu) = &self.apu {
+            hash = fnv1a_continue(hash, &apu.state_hash().to_le_bytes());
+        }
+        hash
     }
 
     // Read a 16-bit word from memory
     pub fn read_word(&self, addr: u16) -> u16 {
         let lo = self.read(addr) as u16;
-        let hi = self.read(addr + 1) as u16;
+        let hi = self.read(addr.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    // Read a 16-bit word without side effects
+    pub fn peek_word(&self, addr: u16) -> u16 {
+        let lo = self.peek(addr) as u16;
+        let hi = self.peek(addr.wrapping_add(1)) as u16;
         (hi << 8) | lo
     }
 
@@ -204,7 +649,7 @@ impl Cpu6502 {
         let lo = data as u8;
         let hi = (data >> 8) as u8;
         self.write(addr, lo);
-        self.write(addr + 1, hi);
+        self.write(addr.wrapping_add(1), hi);
     }
 
     // Stack operations (the stack is located at 0x0100-0x01FF)
@@ -213,6 +658,45 @@ impl Cpu6502 {
         self.sp = self.sp.wrapping_sub(1);
     }
 
+    // Bytes currently on the stack, from the top (last pushed) down to $01FF
+    pub fn stack_contents(&self) -> &[u8] {
+        &self.memory[0x0101 + self.sp as usize..0x0200]
+    }
+
+    // Guess the call stack from the stack contents, innermost frame first.
+    // A word is taken as a JSR return address when the three bytes before
+    // its target hold a JSR to it; otherwise three bytes whose first has the
+    // always-set bit 5 are taken as an interrupt frame. Data pushed with
+    // PHA is skipped a byte at a time, so the guess can be wrong.
+    pub fn backtrace(&self) -> Vec<StackFrame> {
+        let mut frames = Vec::new();
+        let mut at = self.sp as u16 + 1;
+        let word =
+            |at: u16| self.peek(0x0100 + at) as u16 | (self.peek(0x0100 + at + 1) as u16) << 8;
+        while at < 0xFF {
+            let pushed = word(at);
+            let call_site = pushed.wrapping_sub(2);
+            if self.peek(call_site) == 0x20 {
+                frames.push(StackFrame {
+                    kind: FrameKind::Subroutine,
+                    stack_addr: 0x0100 + at,
+                    return_addr: pushed.wrapping_add(1),
+                });
+                at += 2;
+            } else if at < 0xFE && self.peek(0x0100 + at) & UNUSED != 0 {
+                frames.push(StackFrame {
+                    kind: FrameKind::Interrupt,
+                    stack_addr: 0x0100 + at,
+                    return_addr: word(at + 1),
+                });
+                at += 3;
+            } else {
+                at += 1;
+            }
+        }
+        frames
+    }
+
     // Pop a byte from the stack
     pub fn pop(&mut self) -> u8 {
         self.sp = self.sp.wrapping_add(1);
@@ -234,7 +718,7 @@ impl Cpu6502 {
 
     // Status register operations
     pub fn pull_status(&mut self) {
-        self.status = self.pop();
+        self.status = (self.pop() & !BREAK) | UNUSED;
     }
 
     // Increment and Decrement
@@ -300,6 +784,14 @@ impl Cpu6502 {
         }
     }
 
+    // Arithmetic shift left on the accumulator
+    pub fn asl_accumulator(&mut self) {
+        let value = self.a;
+        self.a = value << 1;
+        self.update_zero_and_negative_flags(self.a);
+        self.set_carry(value & NEGATIVE != 0);
+    }
+
     // Logical shift right
     pub fn lsr(&mut self, addr: u16) {
         let value = self.read(addr);
@@ -313,6 +805,14 @@ impl Cpu6502 {
         }
     }
 
+    // Logical shift right on the accumulator
+    pub fn lsr_accumulator(&mut self) {
+        let value = self.a;
+        self.a = value >> 1;
+        self.update_zero_and_negative_flags(self.a);
+        self.set_carry(value & CARRY != 0);
+    }
+
     // Rotate left
     // The CARRY flag is shifted into bit 0 and bit 7 is shifted into the CARRY flag
     pub fn rol(&mut self, addr: u16) {
@@ -328,6 +828,14 @@ impl Cpu6502 {
         }
     }
 
+    // Rotate left on the accumulator
+    pub fn rol_accumulator(&mut self) {
+        let value = self.a;
+        self.a = (value << 1) | (self.status & CARRY);
+        self.update_zero_and_negative_flags(self.a);
+        self.set_carry(value & NEGATIVE != 0);
+    }
+
     // Rotate right
     // The CARRY flag is shifted into bit 7 and bit 0 is shifted into the CARRY flag
     pub fn ror(&mut self, addr: u16) {
@@ -343,6 +851,23 @@ impl Cpu6502 {
         }
     }
 
+    // Rotate right on the accumulator
+    pub fn ror_accumulator(&mut self) {
+        let value = self.a;
+        self.a = (value >> 1) | ((self.status & CARRY) << 7);
+        self.update_zero_and_negative_flags(self.a);
+        self.set_carry(value & CARRY != 0);
+    }
+
+    // Set or clear the CARRY flag
+    fn set_carry(&mut self, carry: bool) {
+        if carry {
+            self.set_status_flag(CARRY);
+        } else {
+            self.clear_status_flag(CARRY);
+        }
+    }
+
     // Flag operations
     // Clear CARRY flag
     pub fn clc(&mut self) {
@@ -359,6 +884,16 @@ impl Cpu6502 {
         self.clear_status_flag(OVERFLOW);
     }
 
+    // Clear DECIMAL flag
+    pub fn cld(&mut self) {
+        self.clear_status_flag(DECIMAL);
+    }
+
+    // Set DECIMAL flag (the NES ignores it, but it is still stored)
+    pub fn sed(&mut self) {
+        self.set_status_flag(DECIMAL);
+    }
+
     // Set CARRY flag to enable the CARRY
     pub fn sec(&mut self) {
         self.set_status_flag(CARRY);
@@ -472,18 +1007,20 @@ impl Cpu6502 {
 
     // Jump to subroutine
     pub fn jsr(&mut self, addr: u16) {
-        let return_addr = self.pc - 1;
+        let return_addr = self.pc.wrapping_sub(1);
         self.push_word(return_addr);
         self.pc = addr;
     }
 
     // Return from subroutine
     pub fn rts(&mut self) {
-        self.pc = self.pop_word() + 1;
+        self.pc = self.pop_word().wrapping_add(1);
     }
 
     // Interrupts
     pub fn brk(&mut self) {
+        #[cfg(feature = "log")]
+        log::debug!(target: "arness::cpu::int", "BRK at ${:04X}", self.pc);
         self.push_word(self.pc);
         self.php();
         self.sei();
@@ -494,22 +1031,35 @@ impl Cpu6502 {
     pub fn rti(&mut self) {
         self.pull_status();
         self.pc = self.pop_word();
+        #[cfg(feature = "log")]
+        log::trace!(target: "arness::cpu::int", "RTI to ${:04X}", self.pc);
+    }
+
+    // Push the status for a hardware interrupt (B flag clear)
+    fn push_interrupt_status(&mut self) {
+        self.push((self.status & !BREAK) | UNUSED);
     }
 
     // Non-Maskable Interrupt
     pub fn nmi(&mut self) {
+        #[cfg(feature = "log")]
+        log::debug!(target: "arness::cpu::int", "NMI at ${:04X}", self.pc);
         self.push_word(self.pc);
-        self.php();
+        self.push_interrupt_status();
         self.sei();
         self.pc = self.read_word(0xFFFA);
+        self.cycles += 7;
     }
 
     // Interrupt Request
     pub fn irq(&mut self) {
+        #[cfg(feature = "log")]
+        log::debug!(target: "arness::cpu::int", "IRQ at ${:04X}", self.pc);
         self.push_word(self.pc);
-        self.php();
+        self.push_interrupt_status();
         self.sei();
         self.pc = self.read_word(0xFFFE);
+        self.cycles += 7;
     }
 
     // These instructions perform bitwise operations on the accumulator and memory
@@ -535,4 +1085,704 @@ impl Cpu6502 {
     pub fn nop(&mut self) {
         // Do nothing
     }
+
+    // Instruction execution
+    // Fetch, decode and execute one instruction, returning the cycles it took
+    pub fn step(&mut self) -> u8 {
+        self.instruction_pc = self.pc;
+        let opcode = self.read(self.pc);
+        let op = &OPCODES[opcode as usize];
+        let operand_pc = self.pc.wrapping_add(1);
+        self.pc = self.pc.wrapping_add(op.size as u16);
+
+        let (addr, page_crossed) = self.operand_address(op.mode, operand_pc);
+        let mut cycles = op.cycles;
+        if page_crossed && op.page_cross_penalty {
+            cycles += 1;
+        }
+        #[cfg(feature = "debugger")]
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(opcode, page_crossed && op.page_cross_penalty);
+        }
+        cycles += self.execute(op, addr);
+
+        self.cycles += cycles as u64;
+        cycles
+    }
+
+    // Resolve the effective address of an operand and whether indexing
+    // crossed a page boundary
+    fn operand_address(&self, mode: AddressingMode, at: u16) -> (u16, bool) {
+        match mode {
+            AddressingMode::Implied | AddressingMode::Accumulator => (0, false),
+            AddressingMode::Immediate => (at, false),
+            AddressingMode::ZeroPage => (self.read(at) as u16, false),
+            AddressingMode::ZeroPageX => (self.read(at).wrapping_add(self.x) as u16, false),
+            AddressingMode::ZeroPageY => (self.read(at).wrapping_add(self.y) as u16, false),
+            AddressingMode::Absolute => (self.read_word(at), false),
+            AddressingMode::AbsoluteX => Self::indexed(self.read_word(at), self.x),
+            AddressingMode::AbsoluteY => Self::indexed(self.read_word(at), self.y),
+            AddressingMode::Indirect => {
+                // The high byte is fetched without carrying into the page
+                let ptr = self.read_word(at);
+                let lo = self.read(ptr) as u16;
+                let hi = self.read((ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF)) as u16;
+                ((hi << 8) | lo, false)
+            }
+            AddressingMode::IndirectX => {
+                let ptr = self.read(at).wrapping_add(self.x);
+                (self.read_zero_page_word(ptr), false)
+            }
+            AddressingMode::IndirectY => {
+                let base = self.read_zero_page_word(self.read(at));
+                Self::indexed(base, self.y)
+            }
+            AddressingMode::Relative => {
+                let offset = self.read(at) as i8 as i16;
+                (self.pc.wrapping_add(offset as u16), false)
+            }
+        }
+    }
+
+    // Add an index register to a base address
+    fn indexed(base: u16, index: u8) -> (u16, bool) {
+        let addr = base.wrapping_add(index as u16);
+        (addr, (base ^ addr) & 0xFF00 != 0)
+    }
+
+    // Read a 16-bit pointer from the zero page, wrapping within it
+    fn read_zero_page_word(&self, ptr: u8) -> u16 {
+        let lo = self.read(ptr as u16) as u16;
+        let hi = self.read(ptr.wrapping_add(1) as u16) as u16;
+        (hi << 8) | lo
+    }
+
+    // Take a branch if the condition holds, returning the extra cycles used
+    fn branch_if(&mut self, condition: bool, target: u16) -> u8 {
+        if !condition {
+            return 0;
+        }
+        let extra = if (self.pc ^ target) & 0xFF00 != 0 {
+            2
+        } else {
+            1
+        };
+        self.pc = target;
+        extra
+    }
+
+    // Execute a decoded instruction, returning any extra cycles (branches)
+    fn execute(&mut self, op: &Opcode, addr: u16) -> u8 {
+        use Instruction as I;
+        let accumulator = op.mode == AddressingMode::Accumulator;
+        match op.instruction {
+            // Loads and stores
+            I::Lda => self.lda_immediate(self.read(addr)),
+            I::Ldx => self.ldx_immediate(self.read(addr)),
+            I::Ldy => self.ldy_immediate(self.read(addr)),
+            I::Sta => self.sta(addr),
+            I::Stx => self.stx(addr),
+            I::Sty => self.sty(addr),
+
+            // Transfers
+            I::Tax => self.tax(),
+            I::Tay => self.tay(),
+            I::Txa => self.txa(),
+            I::Tya => self.tya(),
+            I::Tsx => self.tsx(),
+            I::Txs => self.txs(),
+
+            // Stack
+            I::Pha => self.pha(),
+            I::Pla => self.pla(),
+            I::Php => self.php(),
+            I::Plp => self.plp(),
+
+            // Arithmetic and logic
+            I::Adc => self.adc(self.read(addr)),
+            I::Sbc => self.sbc(self.read(addr)),
+            I::And => self.and(self.read(addr)),
+            I::Eor => self.eor(self.read(addr)),
+            I::Ora => self.ora(self.read(addr)),
+            I::Cmp => self.cmp(self.read(addr)),
+            I::Cpx => self.cpx(self.read(addr)),
+            I::Cpy => self.cpy(self.read(addr)),
+            I::Bit => self.bit(self.read(addr)),
+
+            // Increments and decrements
+            I::Inc => self.inc(addr),
+            I::Dec => self.dec(addr),
+            I::Inx => self.inx(),
+            I::Iny => self.iny(),
+            I::Dex => self.dex(),
+            I::Dey => self.dey(),
+
+            // Shifts and rotates
+            I::Asl if accumulator => self.asl_accumulator(),
+            I::Lsr if accumulator => self.lsr_accumulator(),
+            I::Rol if accumulator => self.rol_accumulator(),
+            I::Ror if accumulator => self.ror_accumulator(),
+            I::Asl => self.asl(addr),
+            I::Lsr => self.lsr(addr),
+            I::Rol => self.rol(addr),
+            I::Ror => self.ror(addr),
+
+            // Flags
+            I::Clc => self.clc(),
+            I::Cld => self.cld(),
+            I::Cli => self.cli(),
+            I::Clv => self.clv(),
+            I::Sec => self.sec(),
+            I::Sed => self.sed(),
+            I::Sei => self.sei(),
+
+            // Branches
+            I::Bcc => return self.branch_if(!self.is_status_flag_set(CARRY), addr),
+            I::Bcs => return self.branch_if(self.is_status_flag_set(CARRY), addr),
+            I::Beq => return self.branch_if(self.is_status_flag_set(ZERO), addr),
+            I::Bne => return self.branch_if(!self.is_status_flag_set(ZERO), addr),
+            I::Bmi => return self.branch_if(self.is_status_flag_set(NEGATIVE), addr),
+            I::Bpl => return self.branch_if(!self.is_status_flag_set(NEGATIVE), addr),
+            I::Bvs => return self.branch_if(self.is_status_flag_set(OVERFLOW), addr),
+            I::Bvc => return self.branch_if(!self.is_status_flag_set(OVERFLOW), addr),
+
+            // Jumps, subroutines and interrupts
+            I::Jmp => self.jmp(addr),
+            I::Jsr => self.jsr(addr),
+            I::Rts => self.rts(),
+            I::Rti => self.rti(),
+            I::Brk => {
+                // BRK skips a padding byte after the opcode
+                self.pc = self.pc.wrapping_add(1);
+                self.brk();
+            }
+            I::Nop => self.nop(),
+
+            // Unofficial instructions
+            I::Lax => {
+                self.lda_immediate(self.read(addr));
+                self.tax();
+            }
+            I::Sax => self.write(addr, self.a & self.x),
+            I::Dcp => {
+                self.dec(addr);
+                self.cmp(self.read(addr));
+            }
+            I::Isb => {
+                self.inc(addr);
+                self.sbc(self.read(addr));
+            }
+            I::Slo => {
+                self.asl(addr);
+                self.ora(self.read(addr));
+            }
+            I::Rla => {
+                self.rol(addr);
+                self.and(self.read(addr));
+            }
+            I::Sre => {
+                self.lsr(addr);
+                self.eor(self.read(addr));
+            }
+            I::Rra => {
+                self.ror(addr);
+                self.adc(self.read(addr));
+            }
+            I::Anc => {
+                self.and(self.read(addr));
+                self.set_carry(self.is_status_flag_set(NEGATIVE));
+            }
+            I::Alr => {
+                self.and(self.read(addr));
+                self.lsr_accumulator();
+            }
+            I::Arr => {
+                self.and(self.read(addr));
+                self.ror_accumulator();
+                let bit6 = self.a & 0b0100_0000 != 0;
+                let bit5 = self.a & 0b0010_0000 != 0;
+                self.set_carry(bit6);
+                if bit6 != bit5 {
+                    self.set_status_flag(OVERFLOW);
+                } else {
+                    self.clear_status_flag(OVERFLOW);
+                }
+            }
+            I::Axs => {
+                let value = self.read(addr);
+                let ax = self.a & self.x;
+                self.set_carry(ax >= value);
+                self.x = ax.wrapping_sub(value);
+                self.update_zero_and_negative_flags(self.x);
+            }
+            I::Xaa => {
+                // Unstable on hardware, this uses the common $EE magic constant
+                self.a = (self.a | 0xEE) & self.x & self.read(addr);
+                self.update_zero_and_negative_flags(self.a);
+            }
+            I::Las => {
+                let value = self.read(addr) & self.sp;
+                self.a = value;
+                self.x = value;
+                self.sp = value;
+                self.update_zero_and_negative_flags(value);
+            }
+            I::Ahx => self.write(addr, self.a & self.x & Self::high_byte_plus_one(addr)),
+            I::Shx => self.write(addr, self.x & Self::high_byte_plus_one(addr)),
+            I::Shy => self.write(addr, self.y & Self::high_byte_plus_one(addr)),
+            I::Tas => {
+                self.sp = self.a & self.x;
+                self.write(addr, self.sp & Self::high_byte_plus_one(addr));
+            }
+            I::Jam
+                if self.host_trap.is_some()
+                    && self.read(self.instruction_pc) == HOST_TRAP_OPCODE =>
+            {
+                // Hand control to the host, which may change any state.
+                // The trap is put back unless the callback installed another.
+                let mut trap = self.host_trap.take().unwrap();
+                trap(self);
+                self.host_trap.get_or_insert(trap);
+            }
+            I::Jam => {
+                // The CPU locks up on the opcode until reset
+                self.pc = self.pc.wrapping_sub(1);
+                self.jammed = true;
+            }
+        }
+        0
+    }
+
+    // The "high byte + 1" term used by the unstable SHx/AHX/TAS stores
+    fn high_byte_plus_one(addr: u16) -> u8 {
+        ((addr >> 8) as u8).wrapping_add(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // Run ADC or SBC in decimal mode, returning A and the C, N, V and Z
+    // flags in that order
+    fn decimal(op: fn(&mut Cpu6502, u8), a: u8, value: u8, carry: bool) -> (u8, [bool; 4]) {
+        let mut cpu = Cpu6502::new();
+        cpu.decimal_mode = true;
+        cpu.set_status_flag(DECIMAL);
+        if carry {
+            cpu.set_status_flag(CARRY);
+        }
+        cpu.a = a;
+        op(&mut cpu, value);
+        let flags = [CARRY, NEGATIVE, OVERFLOW, ZERO].map(|flag| cpu.is_status_flag_set(flag));
+        (cpu.a, flags)
+    }
+
+    #[test]
+    fn adc_decimal_nmos() {
+        let cases = [
+            // a, value, carry in, result, [C, N, V, Z]
+            (0x58, 0x46, true, 0x05, [true, true, true, false]),
+            (0x81, 0x92, false, 0x73, [true, false, true, false]),
+            // N comes from the uncorrected high digit and Z from the
+            // binary sum, so 99+01 gives 00 with N set and Z clear
+            (0x99, 0x01, false, 0x00, [true, true, false, false]),
+            (0x79, 0x00, true, 0x80, [false, true, true, false]),
+            (0x00, 0x00, false, 0x00, [false, false, false, true]),
+        ];
+        for (a, value, carry, result, flags) in cases {
+            assert_eq!(
+                decimal(Cpu6502::adc, a, value, carry),
+                (result, flags),
+                "{:02X}+{:02X}+{}",
+                a,
+                value,
+                carry as u8
+            );
+        }
+    }
+
+    #[test]
+    fn sbc_decimal_nmos() {
+        let cases = [
+            // a, value, carry in, result, [C, N, V, Z]
+            (0x32, 0x02, false, 0x29, [true, false, false, false]),
+            (0x12, 0x21, true, 0x91, [false, true, false, false]),
+            (0x50, 0x50, true, 0x00, [true, false, false, true]),
+            (0x00, 0x01, true, 0x99, [false, true, false, false]),
+        ];
+        for (a, value, carry, result, flags) in cases {
+            assert_eq!(
+                decimal(Cpu6502::sbc, a, value, carry),
+                (result, flags),
+                "{:02X}-{:02X}-{}",
+                a,
+                value,
+                !carry as u8
+            );
+        }
+    }
+
+    #[test]
+    fn state_hash_covers_controllers() {
+        let mut idle = Cpu6502::new();
+        idle.controllers = Some(ControllerPorts::new());
+        let mut held = Cpu6502::new();
+        held.controllers = Some(ControllerPorts::new());
+        held.controllers.as_mut().unwrap().set_buttons(0, 0x01);
+        assert_ne!(held.state_hash(), idle.state_hash());
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn state_hash_covers_the_apu() {
+        let mut quiet = Cpu6502::new();
+        quiet.apu = Some(Apu::new());
+        let mut playing = Cpu6502::new();
+        playing.apu = Some(Apu::new());
+        playing.apu.as_mut().unwrap().write(0x4015, 0x01);
+        assert_ne!(playing.state_hash(), quiet.state_hash());
+    }
+
+    // Load `program` at `origin`, let `setup` prepare registers and
+    // memory, then run one instruction and return the cycles it took
+    fn step_at(origin: u16, program: &[u8], setup: impl FnOnce(&mut Cpu6502)) -> (Cpu6502, u8) {
+        let mut cpu = Cpu6502::new();
+        let start = origin as usize;
+        cpu.memory[start..start + program.len()].copy_from_slice(program);
+        cpu.pc = origin;
+        setup(&mut cpu);
+        let cycles = cpu.step();
+        (cpu, cycles)
+    }
+
+    fn step_one(program: &[u8], setup: impl FnOnce(&mut Cpu6502)) -> (Cpu6502, u8) {
+        step_at(0x0200, program, setup)
+    }
+
+    #[test]
+    fn loads_set_zero_and_negative() {
+        let (cpu, cycles) = step_one(&[0xA9, 0x00], |_| {});
+        assert_eq!((cpu.a, cycles), (0x00, 2));
+        assert!(cpu.is_status_flag_set(ZERO) && !cpu.is_status_flag_set(NEGATIVE));
+
+        let (cpu, _) = step_one(&[0xA2, 0x80], |_| {});
+        assert_eq!(cpu.x, 0x80);
+        assert!(!cpu.is_status_flag_set(ZERO) && cpu.is_status_flag_set(NEGATIVE));
+    }
+
+    #[test]
+    fn adc_and_sbc_binary_flags() {
+        let cases = [
+            // opcode, a, operand, carry in, result, C, V
+            (0x69, 0x50, 0x50, false, 0xA0, false, true),
+            (0x69, 0xD0, 0x90, false, 0x60, true, true),
+            (0x69, 0xFF, 0x01, false, 0x00, true, false),
+            (0x69, 0x7F, 0x00, true, 0x80, false, true),
+            (0xE9, 0x50, 0xB0, true, 0xA0, false, true),
+            (0xE9, 0x50, 0xF0, true, 0x60, false, false),
+            (0xE9, 0xD0, 0x70, true, 0x60, true, true),
+            (0xE9, 0x00, 0x00, false, 0xFF, false, false),
+        ];
+        for (opcode, a, value, carry, result, c, v) in cases {
+            let (cpu, _) = step_one(&[opcode, value], |cpu| {
+                cpu.a = a;
+                cpu.set_carry(carry);
+            });
+            let flags = (
+                cpu.is_status_flag_set(CARRY),
+                cpu.is_status_flag_set(OVERFLOW),
+            );
+            assert_eq!(
+                (cpu.a, flags),
+                (result, (c, v)),
+                "{:02X} {:02X} {:02X}",
+                opcode,
+                a,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn compare_sets_carry_zero_and_negative() {
+        let (cpu, _) = step_one(&[0xC9, 0x10], |cpu| cpu.a = 0x10);
+        assert!(cpu.is_status_flag_set(CARRY) && cpu.is_status_flag_set(ZERO));
+        let (cpu, _) = step_one(&[0xC9, 0x20], |cpu| cpu.a = 0x10);
+        assert!(!cpu.is_status_flag_set(CARRY) && cpu.is_status_flag_set(NEGATIVE));
+    }
+
+    #[test]
+    fn indexed_reads_pay_for_page_crosses() {
+        // LDA $10FF,X
+        let (_, cycles) = step_one(&[0xBD, 0xFF, 0x10], |cpu| cpu.x = 0);
+        assert_eq!(cycles, 4);
+        let (cpu, cycles) = step_one(&[0xBD, 0xFF, 0x10], |cpu| {
+            cpu.x = 1;
+            cpu.memory[0x1100] = 0x42;
+        });
+        assert_eq!((cpu.a, cycles), (0x42, 5));
+        // Stores always take the extra cycle, crossing or not
+        let (_, cycles) = step_one(&[0x9D, 0x00, 0x10], |cpu| cpu.x = 0);
+        assert_eq!(cycles, 5);
+        // LDA ($10),Y with the pointer at $10FF
+        let (_, cycles) = step_one(&[0xB1, 0x10], |cpu| {
+            cpu.memory[0x10] = 0xFF;
+            cpu.memory[0x11] = 0x10;
+            cpu.y = 1;
+        });
+        assert_eq!(cycles, 6);
+    }
+
+    #[test]
+    fn branches_cost_more_when_taken_and_crossing() {
+        // BNE +4, with Z set (not taken) and clear (taken)
+        let (cpu, cycles) = step_one(&[0xD0, 0x04], |cpu| cpu.set_status_flag(ZERO));
+        assert_eq!((cpu.pc, cycles), (0x0202, 2));
+        let (cpu, cycles) = step_one(&[0xD0, 0x04], |_| {});
+        assert_eq!((cpu.pc, cycles), (0x0206, 3));
+        // From $02FD the branch lands on the next page
+        let (cpu, cycles) = step_at(0x02FD, &[0xD0, 0x04], |_| {});
+        assert_eq!((cpu.pc, cycles), (0x0303, 4));
+        // And backwards across a page
+        let (cpu, cycles) = step_at(0x0300, &[0xD0, 0xFC], |_| {});
+        assert_eq!((cpu.pc, cycles), (0x02FE, 4));
+    }
+
+    #[test]
+    fn plp_and_rti_ignore_b_and_keep_the_unused_bit() {
+        let (cpu, cycles) = step_one(&[0x28], |cpu| {
+            cpu.status = 0;
+            cpu.push(0xFF);
+        });
+        assert_eq!((cpu.status, cycles), (0xEF, 4));
+
+        let (cpu, cycles) = step_one(&[0x40], |cpu| {
+            cpu.status = 0;
+            cpu.push_word(0x1234);
+            cpu.push(0x10);
+        });
+        assert_eq!((cpu.status, cpu.pc, cycles), (0x20, 0x1234, 6));
+    }
+
+    #[test]
+    fn brk_pushes_b_and_irq_does_not() {
+        let (cpu, cycles) = step_one(&[0x00], |cpu| {
+            cpu.memory[0xFFFE..].copy_from_slice(&[0x00, 0x90])
+        });
+        assert_eq!((cpu.pc, cycles), (0x9000, 7));
+        assert_eq!(cpu.memory[0x0100 + cpu.sp as usize + 1] & 0x30, 0x30);
+        // The return address skips BRK's padding byte
+        assert_eq!(cpu.peek_word(0x0100 + cpu.sp as u16 + 2), 0x0202);
+
+        let mut cpu = Cpu6502::new();
+        cpu.irq();
+        assert_eq!(cpu.memory[0x0100 + cpu.sp as usize + 1] & 0x30, 0x20);
+        assert!(cpu.is_status_flag_set(INTERRUPT_DISABLE));
+    }
+
+    #[test]
+    fn jsr_and_rts_round_trip() {
+        let (mut cpu, cycles) = step_one(&[0x20, 0x00, 0x03], |cpu| cpu.memory[0x0300] = 0x60);
+        assert_eq!((cpu.pc, cycles), (0x0300, 6));
+        // JSR pushes the address of its last byte
+        assert_eq!(cpu.peek_word(0x0100 + cpu.sp as u16 + 1), 0x0202);
+        assert_eq!(cpu.step(), 6);
+        assert_eq!(cpu.pc, 0x0203);
+    }
+
+    #[test]
+    fn jsr_wraps_the_stack_pointer() {
+        let (cpu, _) = step_one(&[0x20, 0x00, 0x03], |cpu| cpu.sp = 0x00);
+        assert_eq!(cpu.sp, 0xFE);
+        assert_eq!((cpu.memory[0x0100], cpu.memory[0x01FF]), (0x02, 0x02));
+    }
+
+    #[test]
+    fn indirect_jmp_does_not_carry_into_the_page() {
+        let (cpu, cycles) = step_one(&[0x6C, 0xFF, 0x02], |cpu| {
+            cpu.memory[0x02FF] = 0x34;
+            cpu.memory[0x0200] = 0x6C;
+            cpu.memory[0x0300] = 0x99;
+        });
+        assert_eq!((cpu.pc, cycles), (0x6C34, 5));
+    }
+
+    #[test]
+    fn unofficial_read_modify_write() {
+        // DCP $10: decrement, then compare with A
+        let (cpu, cycles) = step_one(&[0xC7, 0x10], |cpu| {
+            cpu.memory[0x10] = 0x43;
+            cpu.a = 0x42;
+        });
+        assert_eq!((cpu.memory[0x10], cycles), (0x42, 5));
+        assert!(cpu.is_status_flag_set(ZERO) && cpu.is_status_flag_set(CARRY));
+        // LAX $10
+        let (cpu, _) = step_one(&[0xA7, 0x10], |cpu| cpu.memory[0x10] = 0x81);
+        assert_eq!((cpu.a, cpu.x), (0x81, 0x81));
+    }
+
+    #[test]
+    fn jam_halts_on_the_opcode() {
+        let (mut cpu, _) = step_one(&[0x02], |_| {});
+        assert!(cpu.jammed);
+        assert_eq!(cpu.pc, 0x0200);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0200);
+    }
+
+    #[test]
+    fn write_log_keeps_the_newest_entries_within_capacity() {
+        let mut cpu = Cpu6502::new();
+        let handle = cpu.track_writes(0x0010..=0x0011, 3);
+        for value in 0..5 {
+            cpu.write(0x0010, value);
+        }
+        cpu.write(0x0012, 0xFF);
+        let log = cpu.write_log(handle).unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.dropped(), 2);
+        let values: Vec<(u8, u8)> = log.entries().map(|e| (e.old, e.new)).collect();
+        assert_eq!(values, [(1, 2), (2, 3), (3, 4)]);
+
+        // Entries name the instruction that wrote
+        let (cpu, _) = step_one(&[0x85, 0x11], |cpu| {
+            cpu.a = 0x42;
+            cpu.track_writes(0x0000..=0x00FF, 1);
+        });
+        let entry = *cpu.write_log(0).unwrap().entries().next().unwrap();
+        assert_eq!((entry.pc, entry.addr, entry.new), (0x0200, 0x0011, 0x42));
+    }
+
+    #[test]
+    fn stopped_trackers_free_their_handles() {
+        let mut cpu = Cpu6502::new();
+        let first = cpu.track_writes(0x0000..=0x0000, 4);
+        let second = cpu.track_writes(0x0000..=0x0000, 4);
+        assert!(cpu.stop_tracking(first).is_some());
+        assert!(cpu.write_log(first).is_none());
+        assert_eq!(cpu.track_writes(0x0000..=0x0000, 4), first);
+        cpu.write(0x0000, 1);
+        assert_eq!(cpu.write_log(second).unwrap().len(), 1);
+        // A zero capacity log records nothing
+        let empty = cpu.track_writes(0x0000..=0xFFFF, 0);
+        cpu.write(0x0000, 2);
+        assert!(cpu.write_log(empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn frozen_address_ignores_cpu_writes() {
+        // INC $10, with $10 frozen at 5
+        let (mut cpu, _) = step_one(&[0xE6, 0x10], |cpu| cpu.freeze(0x0010, 5));
+        assert_eq!(cpu.memory[0x0010], 5);
+        cpu.write(0x0010, 0x99);
+        assert_eq!(cpu.read(0x0010), 5);
+        // Neighbours are unaffected
+        cpu.write(0x0011, 0x99);
+        assert_eq!(cpu.read(0x0011), 0x99);
+
+        // Freezing again changes the value, unfreezing lets writes through
+        cpu.freeze(0x0010, 7);
+        assert_eq!(cpu.freezes(), [(0x0010, 7)]);
+        assert!(cpu.unfreeze(0x0010));
+        assert!(!cpu.unfreeze(0x0010));
+        cpu.write(0x0010, 0x99);
+        assert_eq!(cpu.read(0x0010), 0x99);
+    }
+
+    // 64KB of RAM with a counter on $F000, like a memory-mapped timer
+    struct CountingBus {
+        ram: Vec<u8>,
+        counter: Cell<u8>,
+    }
+
+    impl CpuBus for CountingBus {
+        fn read(&self, addr: u16) -> u8 {
+            if addr == 0xF000 {
+                self.counter.set(self.counter.get() + 1);
+                return self.counter.get();
+            }
+            self.ram[addr as usize]
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            match addr {
+                0xF000 => self.counter.get(),
+                _ => self.ram[addr as usize],
+            }
+        }
+
+        fn write(&mut self, addr: u16, data: u8) {
+            self.ram[addr as usize] = data;
+        }
+    }
+
+    #[test]
+    fn an_attached_bus_replaces_memory() {
+        let mut ram = vec![0; 0x10000];
+        // LDA $F000; LDA $F000; STA $4016; STA $10
+        let program = [
+            0xAD, 0x00, 0xF0, 0xAD, 0x00, 0xF0, 0x8D, 0x16, 0x40, 0x85, 0x10,
+        ];
+        ram[0x0200..0x0200 + program.len()].copy_from_slice(&program);
+        let mut cpu = Cpu6502::new();
+        cpu.controllers = Some(ControllerPorts::default());
+        cpu.bus = Some(Box::new(CountingBus {
+            ram,
+            counter: Cell::new(0),
+        }));
+        cpu.pc = 0x0200;
+        for _ in 0..4 {
+            cpu.step();
+        }
+
+        assert_eq!(cpu.a, 2);
+        assert_eq!(cpu.peek(0xF000), 2);
+        assert_eq!((cpu.read(0x4016), cpu.read(0x0010)), (2, 2));
+        assert_eq!(cpu.memory[0x0010], 0);
+    }
+
+    #[test]
+    fn freezes_and_write_logs_follow_the_bus() {
+        let mut cpu = Cpu6502::new();
+        cpu.bus = Some(Box::new(CountingBus {
+            ram: vec![0x11; 0x10000],
+            counter: Cell::new(0),
+        }));
+        let handle = cpu.track_writes(0x0010..=0x0010, 4);
+        cpu.freeze(0x0010, 0x99);
+        cpu.write(0x0010, 0x42);
+
+        assert_eq!(cpu.read(0x0010), 0x99);
+        let entry = cpu.write_log(handle).unwrap().entries().next().unwrap();
+        assert_eq!((entry.old, entry.new), (0x99, 0x42));
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn write_only_apu_registers_read_as_open_bus() {
+        let mut cpu = Cpu6502::new();
+        cpu.apu = Some(Apu::new());
+        cpu.write(0x4000, 0xBF);
+        cpu.write(0x4011, 0x12);
+        assert_eq!((cpu.read(0x4000), cpu.peek(0x4011)), (0x40, 0x40));
+        assert_eq!((cpu.read(0x4014), cpu.read(0x401F)), (0x40, 0x40));
+
+        let apu = cpu.apu.as_ref().unwrap();
+        assert_eq!(apu.peek_reg(0x4000), Some(0xBF));
+        assert_eq!(apu.peek_reg(0x4011), Some(0x12));
+        assert_eq!(apu.peek_reg(0x4016), None);
+
+        // $4015 still reads the status
+        cpu.write(0x4015, 0x01);
+        cpu.write(0x4003, 0x08);
+        assert_eq!(cpu.read(0x4015), 0x01);
+        assert_eq!(cpu.apu.as_ref().unwrap().peek_reg(0x4015), Some(0x01));
+
+        // Without an APU the range is plain memory
+        let mut bare = Cpu6502::new();
+        bare.write(0x4000, 0xBF);
+        assert_eq!(bare.read(0x4000), 0xBF);
+    }
 }
@@ -0,0 +1,923 @@
+// A ready-to-use console: loads a ROM, wires the cartridge, controllers and
+// APU (with the audio feature) to the CPU, and runs it a frame or an
+// instruction at a time, so front-ends do not each have to rebuild the
+// stepping loop and frame accounting.
+//
+// The CPU doubles as the bus and there is no PPU yet, so frames are
+// counted by CPU time, starting at the pre-render line, and the
+// framebuffer stays blank.
+#[cfg(feature = "audio")]
+use crate::apu::{Apu, Channel, Waveform, DMC_DMA_CYCLES};
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::cpu6502::Cpu6502;
+#[cfg(feature = "audio")]
+use crate::cpu6502::INTERRUPT_DISABLE;
+use crate::frame::Frame;
+use crate::input::{ControllerPorts, ControllerRead};
+use crate::observe::RAM_SIZE;
+use crate::region::{self, Region, RegionChoice, RegionSource};
+use std::collections::VecDeque;
+
+pub struct Emulator {
+    cpu: Cpu6502,
+    cartridge: Option<Cartridge>,
+    // File name of the loaded ROM, used as a region hint
+    file_name: Option<String>,
+    // Seed for the power-on RAM contents, zeroed RAM when None
+    seed: Option<u64>,
+    region: RegionChoice,
+    // Region forced by the host instead of detected from the ROM
+    region_override: Option<Region>,
+    frame: Frame,
+    frame_count: u64,
+    // CPU cycle count and frame count when frame timing was last rebased,
+    // at power-on or on a region change
+    start_cycle: u64,
+    start_frame: u64,
+    // Host-paced running: see run_host_frame. `speed` scales emulated time
+    // against real time and `credit` carries the CPU cycles owed to the
+    // next host frame.
+    paused: bool,
+    speed: f64,
+    credit: f64,
+    // Controller input set by the host but not yet seen by the game, one
+    // entry per frame of delay, newest at the back: see set_input_delay
+    input_queue: VecDeque<[u8; 2]>,
+    #[cfg(feature = "audio")]
+    audio: AudioOutput,
+}
+
+// Default audio output rate
+#[cfg(feature = "audio")]
+pub const DEFAULT_SAMPLE_RATE: f64 = 48_000.0;
+
+// APU output resampled for the host
+#[cfg(feature = "audio")]
+struct AudioOutput {
+    // Output rate in Hz and the samples produced but not yet taken
+    sample_rate: f64,
+    samples: Vec<f32>,
+    // Running sum and count of APU output within the current sample, and
+    // the fractional CPU cycles into it
+    sum: f32,
+    count: u32,
+    phase: f64,
+    // The last sample produced, repeated while paused
+    level: f32,
+    // Host display rate the output is locked to, if any
+    host_frame_rate: Option<f64>,
+    // Samples produced in the frame in progress and in the last full one
+    frame_samples: usize,
+    last_frame_samples: usize,
+    // Per-channel levels at each output sample, indexed by Channel; empty
+    // unless enabled with set_waveform_capacity
+    waveforms: Vec<Waveform>,
+}
+
+#[cfg(feature = "audio")]
+impl AudioOutput {
+    fn new() -> Self {
+        AudioOutput {
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            samples: Vec::new(),
+            sum: 0.0,
+            count: 0,
+            phase: 0.0,
+            level: 0.0,
+            host_frame_rate: None,
+            frame_samples: 0,
+            last_frame_samples: 0,
+            waveforms: Vec::new(),
+        }
+    }
+
+    // Drop the partly averaged sample, as a new frame starts
+    fn restart(&mut self) {
+        self.sum = 0.0;
+        self.count = 0;
+        self.phase = 0.0;
+        self.frame_samples = 0;
+    }
+}
+
+// Receives everything a simple front-end needs at the end of each frame,
+// so it can integrate with one callback instead of polling the accessors.
+// `audio` holds the samples produced since audio was last taken (always
+// empty without the audio feature) and `input` the buttons held on
+// controllers 0 and 1 during the frame.
+pub trait FrameSink {
+    fn frame(&mut self, video: &Frame, audio: &[f32], input: [u8; 2]);
+}
+
+impl<F: FnMut(&Frame, &[f32], [u8; 2])> FrameSink for F {
+    fn frame(&mut self, video: &Frame, audio: &[f32], input: [u8; 2]) {
+        self(video, audio, input)
+    }
+}
+
+// What a run_frames callback sees after each frame
+pub struct FrameContext<'a> {
+    // Frames completed since power-on, including this one
+    pub frame: u64,
+    // CPU cycles the frame took
+    pub cycles: u64,
+    pub ram: &'a [u8; RAM_SIZE],
+    pub framebuffer: &'a Frame,
+    pub cpu: &'a Cpu6502,
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Emulator::new()
+    }
+}
+
+impl Emulator {
+    pub fn new() -> Self {
+        Emulator {
+            cpu: Cpu6502::new(),
+            cartridge: None,
+            file_name: None,
+            seed: None,
+            region: RegionChoice {
+                region: Region::Ntsc,
+                source: RegionSource::Default,
+            },
+            region_override: None,
+            frame: Frame::default(),
+            frame_count: 0,
+            start_cycle: 0,
+            start_frame: 0,
+            paused: false,
+            speed: 1.0,
+            credit: 0.0,
+            input_queue: VecDeque::new(),
+            #[cfg(feature = "audio")]
+            audio: AudioOutput::new(),
+        }
+    }
+
+    // Load an iNES image and power the console on. The region is detected
+    // from the header unless set_region has forced one.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), CartridgeError> {
+        self.load_rom_named(rom, None)
+    }
+
+    // Like load_rom, also using the file name for region detection when
+    // the header does not say
+    pub fn load_rom_named(
+        &mut self,
+        rom: &[u8],
+        file_name: Option<&str>,
+    ) -> Result<(), CartridgeError> {
+        let cart = Cartridge::from_ines(rom)?;
+        if cart.mapper != 0 {
+            return Err(CartridgeError::UnsupportedMapper(cart.mapper));
+        }
+
+        self.region = match self.region_override {
+            Some(region) => RegionChoice {
+                region,
+                source: RegionSource::Override,
+            },
+            None => region::detect(&cart, file_name),
+        };
+        self.file_name = file_name.map(str::to_string);
+        self.cartridge = Some(cart);
+        self.power_on();
+        Ok(())
+    }
+
+    // Power-cycle the loaded cartridge: RAM, registers, the APU and the
+    // controllers all start over. A host trap and coverage recording
+    // attached through cpu_mut are kept, and so is the controller read log
+    // setting.
+    pub fn power_on(&mut self) {
+        let old = std::mem::replace(
+            &mut self.cpu,
+            match self.seed {
+                Some(seed) => Cpu6502::with_seed(seed),
+                None => Cpu6502::new(),
+            },
+        );
+        self.cpu.host_trap = old.host_trap;
+        #[cfg(feature = "debugger")]
+        {
+            self.cpu.coverage = old.coverage;
+        }
+        let mut ports = ControllerPorts::new();
+        ports.set_read_log(old.controllers.is_some_and(|old| old.read_log_enabled()));
+        self.cpu.controllers = Some(ports);
+        self.input_queue
+            .iter_mut()
+            .for_each(|input| *input = [0; 2]);
+        #[cfg(feature = "audio")]
+        {
+            let mut apu = Apu::new();
+            apu.set_region(self.region.region);
+            self.cpu.apu = Some(apu);
+            self.audio.restart();
+        }
+        if let Some(cart) = &self.cartridge {
+            if let Some(trainer) = &cart.trainer {
+                self.cpu.memory[0x7000..0x7000 + trainer.len()].copy_from_slice(trainer);
+            }
+            self.cpu.load_prg_rom(&cart.prg_rom);
+        }
+        self.cpu.power_on();
+        self.frame_count = 0;
+        self.start_cycle = self.cpu.cycles;
+        self.start_frame = 0;
+    }
+
+    // Press the reset button. RAM and the controllers are kept, the APU
+    // goes quiet as if $4015 had been cleared, and the frame in progress
+    // restarts.
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        #[cfg(feature = "audio")]
+        {
+            if let Some(apu) = &mut self.cpu.apu {
+                apu.write(0x4015, 0);
+            }
+            self.audio.restart();
+        }
+        self.start_cycle = self.cpu.cycles;
+        self.start_frame = self.frame_count;
+    }
+
+    // Force a region, or go back to detection with None. Takes effect
+    // immediately and on later loads; the current frame restarts at the
+    // new length.
+    pub fn set_region(&mut self, region: Option<Region>) {
+        self.region_override = region;
+        self.region = match (region, &self.cartridge) {
+            (Some(region), _) => RegionChoice {
+                region,
+                source: RegionSource::Override,
+            },
+            (None, Some(cart)) => region::detect(cart, self.file_name.as_deref()),
+            (None, None) => RegionChoice {
+                region: Region::Ntsc,
+                source: RegionSource::Default,
+            },
+        };
+        self.start_cycle = self.cpu.cycles;
+        self.start_frame = self.frame_count;
+        #[cfg(feature = "audio")]
+        if let Some(apu) = &mut self.cpu.apu {
+            apu.set_region(self.region.region);
+        }
+    }
+
+    // Fill RAM from this seed at the next power-on instead of zeroing it
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+    }
+
+    pub fn region(&self) -> Region {
+        self.region.region
+    }
+
+    // The region in use and why it was picked
+    pub fn region_choice(&self) -> RegionChoice {
+        self.region
+    }
+
+    // Run one instruction, plus the interrupt it triggers, returning the
+    // cycles it took
+    pub fn step_instruction(&mut self) -> u8 {
+        let start = self.cpu.cycles;
+        self.cpu.step();
+        #[cfg(feature = "audio")]
+        {
+            let irq = self.cpu.apu.as_ref().is_some_and(Apu::irq_pending);
+            if irq && !self.cpu.is_status_flag_set(INTERRUPT_DISABLE) {
+                self.cpu.irq();
+            }
+            self.clock_apu(self.cpu.cycles - start);
+        }
+        if self.cpu.cycles >= self.frame_end() {
+            self.frame_count += 1;
+            if let Some(&newest) = self.input_queue.back() {
+                self.input_queue.push_back(newest);
+                let [port0, port1] = self.input_queue.pop_front().unwrap();
+                self.apply_controller_state(0, port0);
+                self.apply_controller_state(1, port1);
+            }
+            #[cfg(feature = "audio")]
+            {
+                self.audio.last_frame_samples = std::mem::take(&mut self.audio.frame_samples);
+            }
+        }
+        (self.cpu.cycles - start) as u8
+    }
+
+    // Run the APU alongside the CPU, averaging its output into samples.
+    // DMC sample fetches stall the CPU, so they add to its cycle count and
+    // the APU keeps running through the stall. The stall is charged after
+    // the instruction that caused it rather than mid-instruction, and
+    // always as DMC_DMA_CYCLES, so timing-sensitive code can drift by a
+    // few cycles per fetch.
+    #[cfg(feature = "audio")]
+    fn clock_apu(&mut self, cycles: u64) {
+        // Taken out of the CPU so DMC fetches can read memory
+        let Some(mut apu) = self.cpu.apu.take() else {
+            return;
+        };
+        let cycles_per_sample = self.cycles_per_sample();
+        let out = &mut self.audio;
+        let mut remaining = cycles;
+        while remaining > 0 {
+            remaining -= 1;
+            apu.tick();
+            if let Some(addr) = apu.dmc_dma_request() {
+                // Always the worst-case stall; see DMC_DMA_CYCLES
+                apu.dmc_dma_complete(self.cpu.peek(addr));
+                self.cpu.cycles += DMC_DMA_CYCLES;
+                remaining += DMC_DMA_CYCLES;
+            }
+            out.sum += apu.sample();
+            out.count += 1;
+            out.phase += 1.0;
+            if out.phase >= cycles_per_sample {
+                out.level = out.sum / out.count as f32;
+                out.samples.push(out.level);
+                out.frame_samples += 1;
+                for (waveform, &channel) in out.waveforms.iter_mut().zip(&Channel::ALL) {
+                    waveform.push(apu.channel_output(channel));
+                }
+                out.sum = 0.0;
+                out.count = 0;
+                out.phase -= cycles_per_sample;
+            }
+        }
+        self.cpu.apu = Some(apu);
+    }
+
+    // CPU cycles per output sample. Away from full speed each sample covers
+    // more or less emulated time, which stretches or squeezes the audio so
+    // it still fills real time.
+    #[cfg(feature = "audio")]
+    fn cycles_per_sample(&self) -> f64 {
+        self.region().cpu_cycles_per_frame() / self.samples_per_frame() * self.speed
+    }
+
+    // Lock audio to a host display rate: each emulated frame then makes
+    // sample_rate / host_rate samples on average (e.g. 800 at 48 kHz and
+    // 60 Hz) instead of following the console's own frame rate. A host
+    // that runs one frame per vsync then fills its audio buffer at exactly
+    // the rate it drains, with no long-run drift, at the cost of a pitch
+    // change too small to hear (0.16% for NTSC on a 60 Hz display). None
+    // goes back to the console rate. Panics unless the rate is finite and
+    // above zero.
+    #[cfg(feature = "audio")]
+    pub fn set_host_frame_rate(&mut self, frame_rate: Option<f64>) {
+        if let Some(rate) = frame_rate {
+            assert!(
+                rate.is_finite() && rate > 0.0,
+                "host frame rate must be finite and above zero, got {}",
+                rate
+            );
+        }
+        self.audio.host_frame_rate = frame_rate;
+    }
+
+    // Average samples each emulated frame produces at full speed. The
+    // fraction is carried between frames, so counts alternate around it.
+    #[cfg(feature = "audio")]
+    pub fn samples_per_frame(&self) -> f64 {
+        let frame_rate = self
+            .audio
+            .host_frame_rate
+            .unwrap_or_else(|| self.region().frame_rate());
+        self.audio.sample_rate / frame_rate
+    }
+
+    // Samples produced by the last finished frame, which is the frame
+    // run_frame just ran
+    #[cfg(feature = "audio")]
+    pub fn samples_this_frame(&self) -> usize {
+        self.audio.last_frame_samples
+    }
+
+    // Set the audio output rate in Hz
+    #[cfg(feature = "audio")]
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.audio.sample_rate = sample_rate;
+    }
+
+    #[cfg(feature = "audio")]
+    pub fn sample_rate(&self) -> f64 {
+        self.audio.sample_rate
+    }
+
+    // Keep the last `samples` levels of every channel, taken at the audio
+    // sample rate, for visualizers. Zero turns recording off.
+    #[cfg(feature = "audio")]
+    pub fn set_waveform_capacity(&mut self, samples: usize) {
+        self.audio.waveforms = match samples {
+            0 => Vec::new(),
+            _ => Channel::ALL
+                .iter()
+                .map(|_| Waveform::new(samples))
+                .collect(),
+        };
+    }
+
+    // Recent levels of a channel, if recording is on
+    #[cfg(feature = "audio")]
+    pub fn waveform(&self, channel: Channel) -> Option<&Waveform> {
+        self.audio.waveforms.get(channel as usize)
+    }
+
+    // Take the audio produced since the last call, as mono samples in
+    // 0.0-1.0 at sample_rate
+    #[cfg(feature = "audio")]
+    pub fn take_audio(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.audio.samples)
+    }
+
+    // Run until the current frame ends, returning the cycles it took. A
+    // jammed CPU stops the frame early.
+    pub fn run_frame(&mut self) -> u64 {
+        let start = self.cpu.cycles;
+        let frame = self.frame_count;
+        while self.frame_count == frame && !self.cpu.jammed {
+            self.step_instruction();
+        }
+        self.cpu.cycles - start
+    }
+
+    // Run a frame and hand its picture, audio and input to the sink,
+    // returning the cycles it took
+    pub fn run_frame_into(&mut self, sink: &mut impl FrameSink) -> u64 {
+        let cycles = self.run_frame();
+        let input = [self.controller_state(0), self.controller_state(1)];
+        #[cfg(feature = "audio")]
+        {
+            sink.frame(&self.frame, &self.audio.samples, input);
+            self.audio.samples.clear();
+        }
+        #[cfg(not(feature = "audio"))]
+        sink.frame(&self.frame, &[], input);
+        cycles
+    }
+
+    // Run up to `frames` frames, calling `callback` exactly once after each
+    // with read-only access to RAM and the picture: the loop bots and
+    // benchmarks keep rebuilding around run_frame. A jammed CPU ends the
+    // run without a callback for the unfinished frame. Returns the frames
+    // completed.
+    pub fn run_frames(&mut self, frames: u64, mut callback: impl FnMut(&FrameContext)) -> u64 {
+        for done in 0..frames {
+            let before = self.frame_count;
+            let cycles = self.run_frame();
+            if self.frame_count == before {
+                return done;
+            }
+            callback(&FrameContext {
+                frame: self.frame_count,
+                cycles,
+                ram: self.cpu.memory[..RAM_SIZE].try_into().unwrap(),
+                framebuffer: &self.frame,
+                cpu: &self.cpu,
+            });
+        }
+        frames
+    }
+
+    // Run until vertical blank next starts, where many hosts present, and
+    // return the cycles it took for pacing. This stops partway through the
+    // frame run_frame would finish. The point comes from CPU time, so no
+    // NMI is raised until there is a PPU.
+    pub fn run_until_vblank(&mut self) -> u64 {
+        let start = self.cpu.cycles;
+        let vblank = self.next_vblank();
+        while self.cpu.cycles < vblank && !self.cpu.jammed {
+            self.step_instruction();
+        }
+        self.cpu.cycles - start
+    }
+
+    // Stop or restart emulation in run_host_frame. Other ways of running
+    // ignore the pause, so advance_frame and step_instruction still work.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.credit = 0.0;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    // Emulated time per real time: below 1.0 is slow motion, above it fast
+    // forward. Audio is stretched to match. Panics unless the speed is
+    // finite and above zero.
+    pub fn set_speed(&mut self, speed: f64) {
+        assert!(
+            speed.is_finite() && speed > 0.0,
+            "speed must be finite and above zero, got {}",
+            speed
+        );
+        self.speed = speed;
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    // Emulate one host frame, for hosts that call this at the region's
+    // frame rate (see FramePacer). At full speed that is one frame; in
+    // slow motion a fraction of one, so a frame completes every few calls.
+    // While paused nothing runs and the audio holds its last level for a
+    // frame's worth of samples, so the output neither underruns nor
+    // clicks. Returns the CPU cycles run.
+    pub fn run_host_frame(&mut self) -> u64 {
+        let frame_cycles = self.region().cpu_cycles_per_frame();
+        if self.paused {
+            #[cfg(feature = "audio")]
+            {
+                let cycles_per_sample = frame_cycles / self.samples_per_frame();
+                let out = &mut self.audio;
+                out.phase += frame_cycles;
+                while out.phase >= cycles_per_sample {
+                    out.samples.push(out.level);
+                    out.phase -= cycles_per_sample;
+                }
+            }
+            return 0;
+        }
+        let start = self.cpu.cycles;
+        self.credit += frame_cycles * self.speed;
+        while self.credit > 0.0 && !self.cpu.jammed {
+            self.credit -= self.step_instruction() as f64;
+        }
+        self.cpu.cycles - start
+    }
+
+    // Run exactly one frame, paused or not: the frame-advance button
+    pub fn advance_frame(&mut self) -> u64 {
+        self.credit = 0.0;
+        self.run_frame()
+    }
+
+    // Frames completed since power-on
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // The last finished picture
+    pub fn framebuffer(&self) -> &Frame {
+        &self.frame
+    }
+
+    // Set the buttons held on controller 0 or 1, using the input::BUTTON_*
+    // bits. Other ports are ignored. With an input delay the game sees
+    // them that many frames later.
+    pub fn set_controller_state(&mut self, port: usize, buttons: u8) {
+        match self.input_queue.back_mut() {
+            Some(input) if port < 2 => input[port] = buttons,
+            Some(_) => {}
+            None => self.apply_controller_state(port, buttons),
+        }
+    }
+
+    fn apply_controller_state(&mut self, port: usize, buttons: u8) {
+        if let Some(ports) = &mut self.cpu.controllers {
+            ports.set_buttons(port, buttons);
+        }
+    }
+
+    // Log every $4016/$4017 read with its cycle and returned bit, for
+    // debugging input that gets dropped. Turning it off discards the log.
+    pub fn set_controller_read_log(&mut self, enabled: bool) {
+        if let Some(ports) = &mut self.cpu.controllers {
+            ports.set_read_log(enabled);
+        }
+    }
+
+    // Hand over the controller reads logged since the last call
+    pub fn take_controller_reads(&mut self) -> Vec<ControllerRead> {
+        self.cpu
+            .controllers
+            .as_mut()
+            .map(ControllerPorts::take_read_log)
+            .unwrap_or_default()
+    }
+
+    // Hold back controller input by a number of frames (0 by default), so
+    // delay-based netplay gets the same delay on every host: input set
+    // during frame N reaches the game at the start of frame N + frames.
+    // Input still waiting when the delay changes is dropped.
+    pub fn set_input_delay(&mut self, frames: usize) {
+        let held = [self.controller_state(0), self.controller_state(1)];
+        self.input_queue = VecDeque::from(vec![held; frames]);
+    }
+
+    pub fn input_delay(&self) -> usize {
+        self.input_queue.len()
+    }
+
+    // The buttons currently held on a controller port, as the game sees
+    // them
+    pub fn controller_state(&self, port: usize) -> u8 {
+        self.cpu
+            .controllers
+            .as_ref()
+            .map_or(0, |ports| ports.buttons(port))
+    }
+
+    // The buttons the game latched from a controller port when it last
+    // strobed, which can lag the held buttons by a frame
+    pub fn latched_controller_state(&self, port: usize) -> u8 {
+        self.cpu
+            .controllers
+            .as_ref()
+            .map_or(0, |ports| ports.latched(port))
+    }
+
+    pub fn cpu(&self) -> &Cpu6502 {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu6502 {
+        &mut self.cpu
+    }
+
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.cartridge.as_ref()
+    }
+
+    // Cycle count at which the next vertical blank starts
+    fn next_vblank(&self) -> u64 {
+        let region = self.region();
+        let vblank = |frames: u64| {
+            let cycles = frames as f64 * region.cpu_cycles_per_frame();
+            self.start_cycle + (cycles + region.vblank_start_cycles()) as u64
+        };
+        let frames = self.frame_count - self.start_frame;
+        match vblank(frames) {
+            cycle if cycle > self.cpu.cycles => cycle,
+            _ => vblank(frames + 1),
+        }
+    }
+
+    // Cycle count at which the current frame ends
+    fn frame_end(&self) -> u64 {
+        let frames = self.frame_count - self.start_frame + 1;
+        let cycles = frames as f64 * self.region().cpu_cycles_per_frame();
+        self.start_cycle + cycles as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FRAME_WIDTH;
+    use crate::input::{BUTTON_A, BUTTON_START};
+
+    // A one-bank NROM image running `program` from $8000
+    fn nrom(program: &[u8]) -> Vec<u8> {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x3FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+        rom
+    }
+
+    // JMP $8000
+    const SPIN: [u8; 3] = [0x4C, 0x00, 0x80];
+
+    #[test]
+    fn reset_keeps_ram_and_restarts_the_frame() {
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&nrom(&SPIN)).unwrap();
+        emulator.cpu_mut().write(0x0010, 0x55);
+        for _ in 0..1000 {
+            emulator.step_instruction();
+        }
+        emulator.reset();
+        assert_eq!(emulator.cpu().peek(0x0010), 0x55);
+        let cycles = emulator.run_frame();
+        let frame = Region::Ntsc.cpu_cycles_per_frame() as u64;
+        assert!((frame..frame + 3).contains(&cycles), "{}", cycles);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn reset_silences_the_apu() {
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&nrom(&SPIN)).unwrap();
+        let cpu = emulator.cpu_mut();
+        cpu.write(0x4015, 0x0F);
+        cpu.write(0x4003, 0x08);
+        cpu.write(0x400F, 0x08);
+        assert_eq!(cpu.apu.as_ref().unwrap().peek_status() & 0x0F, 0x09);
+        emulator.reset();
+        assert_eq!(emulator.cpu().apu.as_ref().unwrap().peek_status() & 0x1F, 0);
+    }
+
+    #[test]
+    fn run_frame_into_hands_over_each_frame() {
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&nrom(&SPIN)).unwrap();
+        emulator.set_controller_state(1, BUTTON_START);
+        let mut calls = Vec::new();
+        let mut sink = |video: &Frame, audio: &[f32], input: [u8; 2]| {
+            calls.push((video.width, audio.len(), input));
+        };
+        emulator.run_frame_into(&mut sink);
+        emulator.run_frame_into(&mut sink);
+
+        assert_eq!(calls.len(), 2);
+        for &(width, samples, input) in &calls {
+            assert_eq!((width, input), (FRAME_WIDTH, [0, BUTTON_START]));
+            if cfg!(feature = "audio") {
+                // 48 kHz at about 60 frames a second
+                assert!((798..=801).contains(&samples), "{}", samples);
+            }
+        }
+    }
+
+    #[test]
+    fn slow_motion_spreads_a_frame_over_host_frames() {
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&nrom(&SPIN)).unwrap();
+        emulator.set_speed(0.25);
+        for _ in 0..8 {
+            emulator.run_host_frame();
+            // Every host frame still gets a frame's worth of audio
+            #[cfg(feature = "audio")]
+            {
+                let samples = emulator.take_audio().len();
+                assert!((798..=801).contains(&samples), "{}", samples);
+            }
+        }
+        assert_eq!(emulator.frame_count(), 2);
+    }
+
+    #[test]
+    fn pause_holds_the_cpu_but_not_the_audio() {
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&nrom(&SPIN)).unwrap();
+        emulator.run_host_frame();
+        emulator.set_paused(true);
+        let cycles = emulator.cpu().cycles;
+        assert_eq!(emulator.run_host_frame(), 0);
+        assert_eq!(emulator.cpu().cycles, cycles);
+        #[cfg(feature = "audio")]
+        {
+            emulator.take_audio();
+            emulator.run_host_frame();
+            let silence = emulator.take_audio();
+            assert!((798..=801).contains(&silence.len()));
+            assert!(silence.iter().all(|&level| level == silence[0]));
+        }
+
+        // Frame advance runs one whole frame while paused
+        emulator.advance_frame();
+        assert_eq!(emulator.frame_count(), 2);
+        assert!(emulator.paused());
+    }
+
+    #[test]
+    #[should_panic(expected = "speed must be finite and above zero, got 0")]
+    fn rejects_a_zero_speed() {
+        Emulator::new().set_speed(0.0);
+    }
+
+    #[test]
+    fn run_until_vblank_stops_inside_the_frame() {
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&nrom(&SPIN)).unwrap();
+        let near = |cycles: u64, expected: f64| (cycles as f64 - expected).abs() < 4.0;
+
+        let to_vblank = Region::Ntsc.vblank_start_cycles();
+        let cycles = emulator.run_until_vblank();
+        assert!(near(cycles, to_vblank), "{}", cycles);
+        assert_eq!(emulator.frame_count(), 0);
+
+        // The rest of the frame is vertical blank
+        let cycles = emulator.run_frame();
+        assert!(near(cycles, 29_780.5 - to_vblank), "{}", cycles);
+        assert_eq!(emulator.frame_count(), 1);
+
+        // From one vertical blank to the next is a whole frame
+        emulator.run_until_vblank();
+        let cycles = emulator.run_until_vblank();
+        assert!(near(cycles, 29_780.5), "{}", cycles);
+        assert_eq!(emulator.frame_count(), 2);
+    }
+
+    #[test]
+    fn run_frames_calls_back_once_per_frame() {
+        let mut emulator = Emulator::new();
+        // INC $10; JMP $8000
+        emulator
+            .load_rom(&nrom(&[0xE6, 0x10, 0x4C, 0x00, 0x80]))
+            .unwrap();
+        let mut seen = Vec::new();
+        let frames = emulator.run_frames(3, |ctx| {
+            assert_eq!(ctx.framebuffer.width, FRAME_WIDTH);
+            assert_eq!(ctx.ram[0x10], ctx.cpu.peek(0x0010));
+            seen.push((ctx.frame, ctx.cycles));
+        });
+        assert_eq!(frames, 3);
+        assert_eq!(
+            seen.iter().map(|&(frame, _)| frame).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        // Frames end on instruction boundaries, so they can be a few
+        // cycles long or short
+        assert!(
+            seen.iter().all(|&(_, cycles)| cycles.abs_diff(29_780) <= 8),
+            "{:?}",
+            seen
+        );
+
+        // A jam ends the run without a callback
+        emulator.load_rom(&nrom(&[0x02])).unwrap();
+        assert_eq!(emulator.run_frames(3, |_| panic!("no frame finished")), 0);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn host_frame_rate_locks_samples_per_frame() {
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&nrom(&SPIN)).unwrap();
+        // 48 kHz at the NTSC rate is about 798.7 samples a frame
+        let mut counts = Vec::new();
+        for _ in 0..6 {
+            emulator.run_frame();
+            counts.push(emulator.samples_this_frame());
+        }
+        assert!(
+            counts.iter().all(|n| (797..=800).contains(n)),
+            "{:?}",
+            counts
+        );
+
+        emulator.set_host_frame_rate(Some(60.0));
+        assert_eq!(emulator.samples_per_frame(), 800.0);
+        emulator.take_audio();
+        for _ in 0..60 {
+            emulator.run_frame();
+            let samples = emulator.samples_this_frame();
+            assert!((799..=801).contains(&samples), "{}", samples);
+        }
+        // A second of frames is a second of audio, give or take the sample
+        // in progress and frame edges landing mid-instruction
+        let total = emulator.take_audio().len();
+        assert!(total.abs_diff(48_000) <= 2, "{}", total);
+    }
+
+    #[test]
+    fn input_delay_holds_input_back_whole_frames() {
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&nrom(&SPIN)).unwrap();
+        emulator.set_input_delay(2);
+        assert_eq!(emulator.input_delay(), 2);
+
+        emulator.set_controller_state(0, BUTTON_START);
+        emulator.run_frame();
+        assert_eq!(emulator.controller_state(0), 0);
+        emulator.set_controller_state(1, BUTTON_START);
+        emulator.run_frame();
+        assert_eq!(emulator.controller_state(0), BUTTON_START);
+        assert_eq!(emulator.controller_state(1), 0);
+        emulator.run_frame();
+        assert_eq!(emulator.controller_state(1), BUTTON_START);
+
+        // Without a delay input applies at once
+        emulator.set_input_delay(0);
+        emulator.set_controller_state(0, 0);
+        assert_eq!(emulator.controller_state(0), 0);
+    }
+
+    #[test]
+    fn logs_controller_reads_with_their_cycles() {
+        // Strobe, then read each port once
+        let program = [
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, 0xAD, 0x16, 0x40, 0xAD,
+            0x17, 0x40, 0x4C, 0x10, 0x80,
+        ];
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&nrom(&program)).unwrap();
+        emulator.set_controller_read_log(true);
+        // The setting survives a power cycle
+        emulator.power_on();
+        emulator.set_controller_state(0, BUTTON_A);
+        emulator.run_frame();
+
+        let reads = emulator.take_controller_reads();
+        assert_eq!(reads.len(), 2);
+        assert_eq!((reads[0].pc, reads[0].port, reads[0].bit), (0x800A, 0, 1));
+        assert_eq!((reads[1].pc, reads[1].port, reads[1].bit), (0x800D, 1, 0));
+        assert_eq!(reads[1].cycle - reads[0].cycle, 4);
+        assert!(!reads[0].strobe);
+        assert!(emulator.take_controller_reads().is_empty());
+    }
+}
@@ -0,0 +1,260 @@
+// An Emulator running on its own thread, for GUI hosts that want to keep
+// emulation off the UI thread. The host sends Commands and receives Events;
+// the thread calls Emulator::run_host_frame at the region's frame rate, so
+// the speed setting gives slow motion, and it sleeps while paused.
+//
+// Loading and saving states will join the commands once the emulator has
+// save states.
+use crate::cartridge::CartridgeError;
+use crate::emulator::Emulator;
+use crate::frame::Frame;
+use crate::pacer::FramePacer;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::JoinHandle;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    LoadRom(Vec<u8>),
+    Reset,
+    SetInput { port: usize, buttons: u8 },
+    TogglePause,
+    // Run one frame while paused, answered with Event::FrameReady
+    AdvanceFrame,
+    // See Emulator::set_speed; ignored unless finite and above zero
+    SetSpeed(f64),
+    // Answered with Event::Screenshot
+    Screenshot,
+    Quit,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    // A host frame ran, with the frame count after it and the audio it
+    // produced. In slow motion the count only moves every few events.
+    FrameReady { frame: u64, audio: Vec<f32> },
+    RomLoaded(Result<(), CartridgeError>),
+    Screenshot(Frame),
+    Paused(bool),
+}
+
+// Sends commands to the emulator thread. Cheap to clone, so several parts
+// of a host can hold one.
+#[derive(Clone, Debug)]
+pub struct CommandSender(Sender<Command>);
+
+impl CommandSender {
+    // Returns false once the thread has stopped
+    pub fn send(&self, command: Command) -> bool {
+        self.0.send(command).is_ok()
+    }
+}
+
+// Handle to the running thread. Dropping it stops the thread and waits for
+// it to finish.
+pub struct EmulatorThread {
+    commands: CommandSender,
+    events: Receiver<Event>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl EmulatorThread {
+    // Start a thread running the emulator `boot` builds. It is built on the
+    // new thread, so it may hold hooks that cannot be sent between threads.
+    pub fn spawn(boot: impl FnOnce() -> Emulator + Send + 'static) -> Self {
+        let (commands, command_rx) = mpsc::channel();
+        let (event_tx, events) = mpsc::channel();
+        let handle = std::thread::spawn(move || run(boot(), command_rx, event_tx));
+        EmulatorThread {
+            commands: CommandSender(commands),
+            events,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn sender(&self) -> CommandSender {
+        self.commands.clone()
+    }
+
+    pub fn send(&self, command: Command) -> bool {
+        self.commands.send(command)
+    }
+
+    // Events in the order they happened. Drain it every frame: a frame's
+    // audio waits here until it is received.
+    pub fn events(&self) -> &Receiver<Event> {
+        &self.events
+    }
+}
+
+impl Drop for EmulatorThread {
+    fn drop(&mut self) {
+        self.commands.send(Command::Quit);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run(mut emulator: Emulator, commands: Receiver<Command>, events: Sender<Event>) {
+    let mut pacer = FramePacer::new(emulator.region());
+    loop {
+        // Apply every waiting command, sleeping on the channel while paused
+        loop {
+            let command = match emulator.paused() {
+                true => commands.recv().map_err(|_| TryRecvError::Disconnected),
+                false => commands.try_recv(),
+            };
+            let event = match command {
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) | Ok(Command::Quit) => return,
+                Ok(Command::LoadRom(rom)) => Event::RomLoaded(emulator.load_rom(&rom)),
+                Ok(Command::Reset) => {
+                    emulator.reset();
+                    continue;
+                }
+                Ok(Command::SetInput { port, buttons }) => {
+                    emulator.set_controller_state(port, buttons);
+                    continue;
+                }
+                Ok(Command::TogglePause) => {
+                    emulator.set_paused(!emulator.paused());
+                    pacer.reset();
+                    Event::Paused(emulator.paused())
+                }
+                Ok(Command::AdvanceFrame) => {
+                    emulator.advance_frame();
+                    frame_ready(&mut emulator)
+                }
+                Ok(Command::SetSpeed(speed)) => {
+                    if speed.is_finite() && speed > 0.0 {
+                        emulator.set_speed(speed);
+                    }
+                    continue;
+                }
+                Ok(Command::Screenshot) => Event::Screenshot(emulator.framebuffer().clone()),
+            };
+            let _ = events.send(event);
+        }
+
+        if pacer.frame_rate() != emulator.region().frame_rate() {
+            pacer = FramePacer::new(emulator.region());
+        }
+        emulator.run_host_frame();
+        let _ = events.send(frame_ready(&mut emulator));
+        pacer.wait();
+    }
+}
+
+// The FrameReady event for what just ran, taking its audio
+fn frame_ready(emulator: &mut Emulator) -> Event {
+    Event::FrameReady {
+        frame: emulator.frame_count(),
+        #[cfg(feature = "audio")]
+        audio: emulator.take_audio(),
+        #[cfg(not(feature = "audio"))]
+        audio: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // A one-bank NROM image spinning at $8000
+    fn nrom() -> Vec<u8> {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        prg[0x3FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+        rom
+    }
+
+    fn next(thread: &EmulatorThread) -> Event {
+        thread
+            .events()
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+    }
+
+    // The next event that is not a finished frame
+    fn next_other(thread: &EmulatorThread) -> Event {
+        loop {
+            match next(thread) {
+                Event::FrameReady { .. } => continue,
+                event => return event,
+            }
+        }
+    }
+
+    fn next_frame(thread: &EmulatorThread) -> u64 {
+        match next(thread) {
+            Event::FrameReady { frame, .. } => frame,
+            event => panic!("expected a frame, got {:?}", event),
+        }
+    }
+
+    fn boot() -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&nrom()).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn runs_frames_and_answers_commands() {
+        let thread = EmulatorThread::spawn(boot);
+        let Event::FrameReady { frame, audio } = next(&thread) else {
+            panic!("expected a frame first");
+        };
+        assert_eq!(frame, 1);
+        assert_eq!(audio.is_empty(), cfg!(not(feature = "audio")));
+
+        assert!(thread.send(Command::Screenshot));
+        assert_eq!(next_other(&thread), Event::Screenshot(Frame::default()));
+
+        // Commands are still handled while paused
+        thread.send(Command::LoadRom(vec![0; 16]));
+        thread.send(Command::TogglePause);
+        assert!(matches!(next_other(&thread), Event::RomLoaded(Err(_))));
+        assert_eq!(next_other(&thread), Event::Paused(true));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(
+            thread.events().try_recv().is_err(),
+            "no frames while paused"
+        );
+
+        thread.send(Command::AdvanceFrame);
+        thread.send(Command::AdvanceFrame);
+        let advanced = next_frame(&thread);
+        assert_eq!(next_frame(&thread), advanced + 1);
+
+        thread.sender().send(Command::TogglePause);
+        assert_eq!(next_other(&thread), Event::Paused(false));
+        assert!(matches!(next(&thread), Event::FrameReady { .. }));
+    }
+
+    #[test]
+    fn slow_motion_repeats_frame_counts() {
+        let thread = EmulatorThread::spawn(boot);
+        thread.send(Command::SetSpeed(0.5));
+        thread.send(Command::SetSpeed(f64::NAN));
+        let frames: Vec<u64> = (0..6).map(|_| next_frame(&thread)).collect();
+        // The first host frame may have run at full speed
+        assert!(
+            frames.windows(2).any(|pair| pair[0] == pair[1]),
+            "{:?}",
+            frames
+        );
+        assert!(frames.last().unwrap() - frames[0] <= 3, "{:?}", frames);
+    }
+
+    #[test]
+    fn stops_when_dropped() {
+        let thread = EmulatorThread::spawn(Emulator::new);
+        let sender = thread.sender();
+        drop(thread);
+        assert!(!sender.send(Command::Reset));
+    }
+}
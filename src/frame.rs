@@ -0,0 +1,295 @@
+// NES picture dimensions
+pub const FRAME_WIDTH: usize = 256;
+pub const FRAME_HEIGHT: usize = 240;
+
+// A rectangle inside a frame, in pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// Output scaling for front-ends that cannot scale cheaply themselves
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScaleOptions {
+    // Nearest-neighbour scale factor, 1 or more
+    pub factor: usize,
+    // Brightness kept on the last row of each scaled line, out of 256, to
+    // imitate CRT scanlines. None leaves every row at full brightness.
+    pub scanlines: Option<u8>,
+}
+
+impl Default for ScaleOptions {
+    fn default() -> Self {
+        ScaleOptions {
+            factor: 2,
+            scanlines: None,
+        }
+    }
+}
+
+// A video frame stored as RGBA8 pixels, row by row
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame::new(FRAME_WIDTH, FRAME_HEIGHT)
+    }
+}
+
+impl Frame {
+    // Create a black frame of the given size
+    pub fn new(width: usize, height: usize) -> Self {
+        Frame {
+            width,
+            height,
+            pixels: vec![0; width * height * 4],
+        }
+    }
+
+    // Read the RGBA value of a pixel
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * self.width + x) * 4;
+        [
+            self.pixels[i],
+            self.pixels[i + 1],
+            self.pixels[i + 2],
+            self.pixels[i + 3],
+        ]
+    }
+
+    // Write the RGBA value of a pixel
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgba: [u8; 4]) {
+        let i = (y * self.width + x) * 4;
+        self.pixels[i..i + 4].copy_from_slice(&rgba);
+    }
+
+    // Bytes of a single row
+    fn row(&self, y: usize) -> &[u8] {
+        let stride = self.width * 4;
+        &self.pixels[y * stride..(y + 1) * stride]
+    }
+
+    // Bounding box of every pixel that differs from the previous frame.
+    // Returns None when the frames are identical and the whole frame when
+    // the sizes differ, so encoders only need to re-send the returned area.
+    pub fn diff_rect(&self, previous: &Frame) -> Option<Rect> {
+        if self.width != previous.width || self.height != previous.height {
+            return Some(Rect {
+                x: 0,
+                y: 0,
+                width: self.width,
+                height: self.height,
+            });
+        }
+
+        // Find the first and last rows that changed
+        let top = (0..self.height).find(|&y| self.row(y) != previous.row(y))?;
+        let bottom = (top..self.height)
+            .rev()
+            .find(|&y| self.row(y) != previous.row(y))
+            .unwrap_or(top);
+
+        // Narrow the columns down within the changed rows
+        let mut left = self.width;
+        let mut right = 0;
+        for y in top..=bottom {
+            let (cur, prev) = (self.row(y), previous.row(y));
+            if cur == prev {
+                continue;
+            }
+            let changed = |x: &usize| cur[x * 4..x * 4 + 4] != prev[x * 4..x * 4 + 4];
+            if let Some(x) = (0..left).find(changed) {
+                left = x;
+            }
+            if let Some(x) = (right..self.width).rev().find(changed) {
+                right = x;
+            }
+        }
+
+        Some(Rect {
+            x: left,
+            y: top,
+            width: right - left + 1,
+            height: bottom - top + 1,
+        })
+    }
+
+    // A copy enlarged by the given options
+    pub fn scaled(&self, options: &ScaleOptions) -> Frame {
+        let mut out = Frame::new(0, 0);
+        self.scale_into(options, &mut out);
+        out
+    }
+
+    // Scale into an existing frame, reusing its buffer between calls
+    pub fn scale_into(&self, options: &ScaleOptions, out: &mut Frame) {
+        let factor = options.factor.max(1);
+        out.width = self.width * factor;
+        out.height = self.height * factor;
+        out.pixels.resize(out.width * out.height * 4, 0);
+
+        let stride = out.width * 4;
+        for y in 0..self.height {
+            // Build the first output row of this line, then copy it down
+            let first = y * factor * stride;
+            for (x, rgba) in self.row(y).chunks_exact(4).enumerate() {
+                for i in 0..factor {
+                    let at = first + (x * factor + i) * 4;
+                    out.pixels[at..at + 4].copy_from_slice(rgba);
+                }
+            }
+            for i in 1..factor {
+                out.pixels
+                    .copy_within(first..first + stride, first + i * stride);
+            }
+
+            // Darken the last row of the line, leaving alpha alone
+            if let Some(level) = options.scanlines.filter(|_| factor > 1) {
+                let last = first + (factor - 1) * stride;
+                for rgba in out.pixels[last..last + stride].chunks_exact_mut(4) {
+                    for channel in &mut rgba[..3] {
+                        *channel = (*channel as u16 * level as u16 / 256) as u8;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+    fn rect(x: usize, y: usize, width: usize, height: usize) -> Option<Rect> {
+        Some(Rect {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    #[test]
+    fn identical_frames_have_no_diff() {
+        let frame = Frame::default();
+        assert_eq!(frame.diff_rect(&frame.clone()), None);
+    }
+
+    #[test]
+    fn single_pixel_diff() {
+        let previous = Frame::default();
+        let mut frame = previous.clone();
+        frame.set_pixel(100, 50, WHITE);
+        assert_eq!(frame.diff_rect(&previous), rect(100, 50, 1, 1));
+        // Alpha alone counts as a change
+        let mut frame = previous.clone();
+        frame.set_pixel(3, 4, [0, 0, 0, 1]);
+        assert_eq!(frame.diff_rect(&previous), rect(3, 4, 1, 1));
+    }
+
+    #[test]
+    fn diff_covers_every_changed_pixel_up_to_the_edges() {
+        let previous = Frame::default();
+        let mut frame = previous.clone();
+        frame.set_pixel(0, 10, WHITE);
+        frame.set_pixel(FRAME_WIDTH - 1, 20, WHITE);
+        assert_eq!(frame.diff_rect(&previous), rect(0, 10, FRAME_WIDTH, 11));
+
+        let mut corners = previous.clone();
+        corners.set_pixel(0, 0, WHITE);
+        corners.set_pixel(FRAME_WIDTH - 1, FRAME_HEIGHT - 1, WHITE);
+        assert_eq!(
+            corners.diff_rect(&previous),
+            rect(0, 0, FRAME_WIDTH, FRAME_HEIGHT)
+        );
+
+        // Columns come from all changed rows, not just the first and last
+        let mut spread = previous.clone();
+        spread.set_pixel(50, 0, WHITE);
+        spread.set_pixel(10, 5, WHITE);
+        spread.set_pixel(60, 9, WHITE);
+        assert_eq!(spread.diff_rect(&previous), rect(10, 0, 51, 10));
+    }
+
+    #[test]
+    fn different_sizes_diff_the_whole_frame() {
+        let frame = Frame::new(16, 8);
+        assert_eq!(frame.diff_rect(&Frame::new(8, 16)), rect(0, 0, 16, 8));
+        assert_eq!(frame.diff_rect(&Frame::new(16, 9)), rect(0, 0, 16, 8));
+    }
+
+    // A 2x2 frame with a different colour in each pixel
+    fn quad() -> Frame {
+        let mut frame = Frame::new(2, 2);
+        frame.set_pixel(0, 0, [200, 0, 0, 255]);
+        frame.set_pixel(1, 0, [0, 200, 0, 255]);
+        frame.set_pixel(0, 1, [0, 0, 200, 255]);
+        frame.set_pixel(1, 1, WHITE);
+        frame
+    }
+
+    #[test]
+    fn scales_by_repeating_pixels() {
+        let out = quad().scaled(&ScaleOptions {
+            factor: 3,
+            scanlines: None,
+        });
+        assert_eq!((out.width, out.height), (6, 6));
+        for y in 0..6 {
+            for x in 0..6 {
+                assert_eq!(out.pixel(x, y), quad().pixel(x / 3, y / 3), "{},{}", x, y);
+            }
+        }
+        // A factor of 0 is treated as 1
+        let same = quad().scaled(&ScaleOptions {
+            factor: 0,
+            scanlines: None,
+        });
+        assert_eq!(same, quad());
+    }
+
+    #[test]
+    fn scanlines_darken_the_last_row_of_each_line() {
+        let options = |factor| ScaleOptions {
+            factor,
+            scanlines: Some(128),
+        };
+        let out = quad().scaled(&options(2));
+        assert_eq!(out.pixel(0, 0), [200, 0, 0, 255]);
+        assert_eq!(out.pixel(1, 1), [100, 0, 0, 255]);
+        assert_eq!(out.pixel(3, 3), [127, 127, 127, 255]);
+        assert_eq!(out.pixel(3, 2), WHITE);
+
+        let out = quad().scaled(&options(3));
+        for y in 0..3 {
+            let dark = y == 2;
+            let expected = if dark {
+                [0, 100, 0, 255]
+            } else {
+                [0, 200, 0, 255]
+            };
+            assert_eq!(out.pixel(4, y), expected, "row {}", y);
+        }
+
+        // Nothing to darken at 1x
+        assert_eq!(quad().scaled(&options(1)), quad());
+    }
+
+    #[test]
+    fn scale_into_reuses_a_larger_buffer() {
+        let mut out = Frame::new(64, 64);
+        quad().scale_into(&ScaleOptions::default(), &mut out);
+        assert_eq!((out.width, out.height, out.pixels.len()), (4, 4, 4 * 4 * 4));
+        assert_eq!(out.pixel(3, 0), [0, 200, 0, 255]);
+    }
+}
@@ -0,0 +1,38 @@
+// FNV-1a, a fast non-cryptographic hash used for machine state and ROM
+// identity. Results must stay stable: verify_run prints state hashes for
+// comparison across runs, FrameHistory and the determinism audit compare
+// them frame by frame, and test_roms pins downloaded ROMs by content hash.
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_continue(FNV_OFFSET, bytes)
+}
+
+// Keep hashing after `hash`, so data in several pieces hashes the same as
+// the pieces joined together
+pub fn fnv1a_continue(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+// fnv1a as a std::hash::Hasher, for state made of types that derive Hash
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv1aHasher(u64);
+
+impl Default for Fnv1aHasher {
+    fn default() -> Self {
+        Fnv1aHasher(FNV_OFFSET)
+    }
+}
+
+impl std::hash::Hasher for Fnv1aHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0 = fnv1a_continue(self.0, bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,239 @@
+// Rolling per-frame record of machine state hashes and inputs. Two runs
+// that should be identical (a replay and its recording, or two netplay
+// peers) can compare histories to find the first frame where they split.
+use crate::emulator::Emulator;
+use std::collections::VecDeque;
+
+// State of one frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameRecord {
+    pub frame: u64,
+    // Hash of the machine state at the end of the frame
+    pub state_hash: u64,
+    // Controller buttons used for the frame
+    pub input: u8,
+}
+
+// The first frame two histories disagree on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub ours: FrameRecord,
+    pub theirs: FrameRecord,
+}
+
+impl Divergence {
+    // True when the inputs differ, so the desync came from input delivery
+    // rather than from nondeterministic emulation
+    pub fn input_differs(&self) -> bool {
+        self.ours.input != self.theirs.input
+    }
+}
+
+// Bounded history keeping the most recent frames
+#[derive(Clone, Debug)]
+pub struct FrameHistory {
+    capacity: usize,
+    records: VecDeque<FrameRecord>,
+}
+
+impl FrameHistory {
+    pub fn new(capacity: usize) -> Self {
+        FrameHistory {
+            capacity,
+            records: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    // Add a frame, dropping the oldest once the window is full
+    pub fn record(&mut self, frame: u64, state_hash: u64, input: u8) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(FrameRecord {
+            frame,
+            state_hash,
+            input,
+        });
+    }
+
+    // Records from oldest to newest
+    pub fn records(&self) -> impl Iterator<Item = &FrameRecord> {
+        self.records.iter()
+    }
+
+    // The record for a frame, if it is still in the window
+    pub fn get(&self, frame: u64) -> Option<&FrameRecord> {
+        let index = self
+            .records
+            .binary_search_by_key(&frame, |record| record.frame)
+            .ok()?;
+        self.records.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    // The earliest frame present in both histories whose hash or input
+    // differs. Frames only one side still holds are skipped, so the windows
+    // do not need to line up.
+    pub fn find_divergence(&self, other: &FrameHistory) -> Option<Divergence> {
+        self.records.iter().find_map(|&ours| {
+            let &theirs = other.get(ours.frame)?;
+            (ours != theirs).then_some(Divergence { ours, theirs })
+        })
+    }
+}
+
+// Determinism audit: boot the console twice with `boot`, run both copies
+// in lockstep with the same controller 0 input, one entry per frame, and
+// compare state hashes after every frame. Any divergence points at
+// nondeterminism such as uninitialised buffers or host-time dependence.
+pub fn audit_determinism(boot: impl Fn() -> Emulator, inputs: &[u8]) -> Option<Divergence> {
+    let mut runs = [boot(), boot()];
+    for (frame, &buttons) in inputs.iter().enumerate() {
+        let [ours, theirs] = runs.each_mut().map(|emulator| {
+            emulator.set_controller_state(0, buttons);
+            emulator.run_frame();
+            FrameRecord {
+                frame: frame as u64,
+                state_hash: emulator.cpu().state_hash(),
+                input: emulator.controller_state(0),
+            }
+        });
+        if ours != theirs {
+            return Some(Divergence { ours, theirs });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // A one-bank NROM image running `program` from $8000
+    fn nrom(program: &[u8]) -> Vec<u8> {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x3FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+        rom
+    }
+
+    // Strobe controller 1 and store its first button (A) at $00, forever
+    const READ_A: [u8; 18] = [
+        0xA9, 0x01, // LDA #1
+        0x8D, 0x16, 0x40, // STA $4016
+        0xA9, 0x00, // LDA #0
+        0x8D, 0x16, 0x40, // STA $4016
+        0xAD, 0x16, 0x40, // LDA $4016
+        0x85, 0x00, // STA $00
+        0x4C, 0x00, 0x80, // JMP $8000
+    ];
+
+    fn boot(seed: Option<u64>) -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.set_seed(seed);
+        emulator.load_rom(&nrom(&READ_A)).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn audit_passes_a_deterministic_run_with_input() {
+        let inputs = [0, 1, 1, 0, 0x81, 0];
+        assert_eq!(audit_determinism(|| boot(Some(7)), &inputs), None);
+    }
+
+    #[test]
+    fn audit_finds_the_first_frame_that_differs() {
+        // Each boot gets different power-on RAM
+        let seed = Cell::new(0);
+        let boot = || {
+            seed.set(seed.get() + 1);
+            boot(Some(seed.get()))
+        };
+        let divergence = audit_determinism(boot, &[0x08, 0]).unwrap();
+        assert_eq!(divergence.ours.frame, 0);
+        assert_eq!(divergence.ours.input, 0x08);
+        assert!(!divergence.input_differs());
+    }
+
+    // A history of `frames` frames whose hash is the frame number, except
+    // where `change` says otherwise
+    fn history(capacity: usize, frames: u64, change: Option<(u64, u64)>) -> FrameHistory {
+        let mut history = FrameHistory::new(capacity);
+        for frame in 0..frames {
+            let hash = match change {
+                Some((at, hash)) if at == frame => hash,
+                _ => frame,
+            };
+            history.record(frame, hash, 0);
+        }
+        history
+    }
+
+    #[test]
+    fn identical_histories_do_not_diverge() {
+        let ours = history(8, 5, None);
+        assert_eq!(ours.find_divergence(&history(8, 5, None)), None);
+        assert_eq!(FrameHistory::new(8).find_divergence(&ours), None);
+    }
+
+    #[test]
+    fn divergence_at_the_first_frame() {
+        let ours = history(8, 5, None);
+        let theirs = history(8, 5, Some((0, 99)));
+        let divergence = ours.find_divergence(&theirs).unwrap();
+        assert_eq!(divergence.ours.frame, 0);
+        assert_eq!(divergence.theirs.state_hash, 99);
+    }
+
+    #[test]
+    fn divergence_after_the_window_wraps() {
+        // 20 frames through a window of 8 keeps frames 12-19
+        let ours = history(8, 20, None);
+        assert_eq!(ours.len(), 8);
+        assert_eq!(ours.records().next().unwrap().frame, 12);
+        let theirs = history(8, 20, Some((15, 99)));
+        assert_eq!(ours.find_divergence(&theirs).unwrap().ours.frame, 15);
+        // A difference that has already left the window is not seen
+        let old = history(8, 20, Some((3, 99)));
+        assert_eq!(ours.find_divergence(&old), None);
+    }
+
+    #[test]
+    fn histories_of_different_lengths_compare_the_overlap() {
+        let short = history(8, 3, None);
+        let long = history(32, 10, Some((6, 99)));
+        assert_eq!(short.find_divergence(&long), None);
+        assert_eq!(long.find_divergence(&short), None);
+        // Windows that do not line up still meet on shared frames
+        let late = history(4, 10, None);
+        assert_eq!(late.find_divergence(&long).unwrap().ours.frame, 6);
+        assert_eq!(long.find_divergence(&late).unwrap().theirs.frame, 6);
+    }
+
+    #[test]
+    fn input_differences_are_reported() {
+        let mut ours = FrameHistory::new(4);
+        let mut theirs = FrameHistory::new(4);
+        ours.record(0, 1, 0x01);
+        theirs.record(0, 1, 0x02);
+        assert!(ours.find_divergence(&theirs).unwrap().input_differs());
+    }
+}
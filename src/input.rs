@@ -0,0 +1,241 @@
+// Standard controllers on $4016/$4017. Writing 1 then 0 to bit 0 of
+// $4016 latches the buttons; each read then returns the next button in
+// the order A, B, Select, Start, Up, Down, Left, Right, and 1s after that.
+use crate::hash::fnv1a;
+use std::cell::{Cell, RefCell};
+
+// Button bits, in the order the controller shifts them out
+pub const BUTTON_A: u8 = 0b0000_0001;
+pub const BUTTON_B: u8 = 0b0000_0010;
+pub const BUTTON_SELECT: u8 = 0b0000_0100;
+pub const BUTTON_START: u8 = 0b0000_1000;
+pub const BUTTON_UP: u8 = 0b0001_0000;
+pub const BUTTON_DOWN: u8 = 0b0010_0000;
+pub const BUTTON_LEFT: u8 = 0b0100_0000;
+pub const BUTTON_RIGHT: u8 = 0b1000_0000;
+
+// Upper bits of a controller read come from the last byte on the data
+// bus, which is the high byte of $4016/$4017
+const OPEN_BUS: u8 = 0x40;
+
+// One logged read of $4016/$4017
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ControllerRead {
+    // CPU cycle count when the reading instruction started
+    pub cycle: u64,
+    // Address of the reading instruction
+    pub pc: u16,
+    pub port: u8,
+    // Bit 0 of the value returned
+    pub bit: u8,
+    // Whether the strobe was held, so the read reported A without shifting
+    pub strobe: bool,
+}
+
+// Both controller ports. Reads shift the controllers, so the shift
+// registers are cells and a read through &Cpu6502 still advances them.
+#[derive(Clone, Debug, Default)]
+pub struct ControllerPorts {
+    buttons: [u8; 2],
+    shift: [Cell<u8>; 2],
+    // The buttons at the last strobe, which is what the game reads
+    latched: [u8; 2],
+    strobe: bool,
+    // Every read in order, while logging is on
+    read_log: Option<RefCell<Vec<ControllerRead>>>,
+}
+
+impl ControllerPorts {
+    pub fn new() -> Self {
+        ControllerPorts::default()
+    }
+
+    // Set the buttons held on a port (0 or 1). Other ports have nothing
+    // plugged in, so the call is ignored.
+    pub fn set_buttons(&mut self, port: usize, buttons: u8) {
+        let Some(held) = self.buttons.get_mut(port) else {
+            return;
+        };
+        *held = buttons;
+        if self.strobe {
+            self.shift[port].set(buttons);
+            self.latched[port] = buttons;
+        }
+    }
+
+    // The buttons held on a port, or none for a port that doesn't exist
+    pub fn buttons(&self, port: usize) -> u8 {
+        self.buttons.get(port).copied().unwrap_or(0)
+    }
+
+    // The buttons the game last latched on a port, for input overlays and
+    // checking a movie against what the game saw. Changes to the held
+    // buttons only show up here at the next strobe.
+    pub fn latched(&self, port: usize) -> u8 {
+        self.latched.get(port).copied().unwrap_or(0)
+    }
+
+    // A write to $4016
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 != 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    // A read of $4016 (port 0) or $4017 (port 1)
+    pub fn read(&self, port: usize) -> u8 {
+        if self.strobe {
+            // While strobing, the controller keeps reloading and reports A
+            return OPEN_BUS | self.buttons[port] & 1;
+        }
+        let shift = self.shift[port].get();
+        self.shift[port].set(shift >> 1 | 0x80);
+        OPEN_BUS | shift & 1
+    }
+
+    // What a read would return, without shifting
+    pub fn peek(&self, port: usize) -> u8 {
+        if self.strobe {
+            OPEN_BUS | self.buttons[port] & 1
+        } else {
+            OPEN_BUS | self.shift[port].get() & 1
+        }
+    }
+
+    // Turn the read log on or off, for lining input-dropping bugs up with
+    // hardware captures. Turning it off discards what was logged.
+    pub fn set_read_log(&mut self, enabled: bool) {
+        self.read_log = enabled.then(RefCell::default);
+    }
+
+    pub fn read_log_enabled(&self) -> bool {
+        self.read_log.is_some()
+    }
+
+    // Hand over the reads logged so far, leaving logging on
+    pub fn take_read_log(&mut self) -> Vec<ControllerRead> {
+        self.read_log
+            .as_ref()
+            .map(RefCell::take)
+            .unwrap_or_default()
+    }
+
+    // Log a read the CPU just made, when logging is on
+    pub fn record_read(&self, cycle: u64, pc: u16, port: usize, value: u8) {
+        if let Some(log) = &self.read_log {
+            log.borrow_mut().push(ControllerRead {
+                cycle,
+                pc,
+                port: port as u8,
+                bit: value & 1,
+                strobe: self.strobe,
+            });
+        }
+    }
+
+    // Hash of the held buttons, shift registers and strobe, folded into
+    // Cpu6502::state_hash
+    pub fn state_hash(&self) -> u64 {
+        let state = [
+            self.buttons[0],
+            self.buttons[1],
+            self.shift[0].get(),
+            self.shift[1].get(),
+            self.strobe as u8,
+        ];
+        fnv1a(&state)
+    }
+
+    fn latch(&mut self) {
+        for (shift, &buttons) in self.shift.iter().zip(&self.buttons) {
+            shift.set(buttons);
+        }
+        self.latched = self.buttons;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Strobe, then read all eight buttons off a port
+    fn read_all(ports: &ControllerPorts, port: usize) -> u8 {
+        (0..8).fold(0, |bits, i| bits | (ports.read(port) & 1) << i)
+    }
+
+    #[test]
+    fn reads_buttons_in_order() {
+        let mut ports = ControllerPorts::new();
+        ports.set_buttons(0, BUTTON_A | BUTTON_START | BUTTON_RIGHT);
+        ports.set_buttons(1, BUTTON_B);
+        ports.write(1);
+        ports.write(0);
+        assert_eq!(read_all(&ports, 0), BUTTON_A | BUTTON_START | BUTTON_RIGHT);
+        assert_eq!(read_all(&ports, 1), BUTTON_B);
+        // Past the eighth read the shift register is all 1s
+        assert_eq!(ports.read(0), OPEN_BUS | 1);
+    }
+
+    #[test]
+    fn ignores_ports_that_do_not_exist() {
+        let mut ports = ControllerPorts::new();
+        ports.set_buttons(2, BUTTON_A);
+        ports.set_buttons(usize::MAX, BUTTON_B);
+        assert_eq!(ports.buttons(2), 0);
+        assert_eq!(ports.buttons(0), 0);
+        assert_eq!(ports.buttons(1), 0);
+    }
+
+    #[test]
+    fn latched_buttons_change_only_on_strobe() {
+        let mut ports = ControllerPorts::new();
+        ports.set_buttons(0, BUTTON_A);
+        assert_eq!(ports.latched(0), 0);
+        ports.write(1);
+        ports.write(0);
+        ports.set_buttons(0, BUTTON_B);
+        assert_eq!(ports.latched(0), BUTTON_A);
+        assert_eq!(read_all(&ports, 0), BUTTON_A);
+
+        // While the strobe is held the latch follows the buttons
+        ports.write(1);
+        ports.set_buttons(0, BUTTON_UP);
+        assert_eq!(ports.latched(0), BUTTON_UP);
+        assert_eq!(ports.latched(2), 0);
+    }
+
+    #[test]
+    fn read_log_records_reads_only_while_enabled() {
+        let mut ports = ControllerPorts::new();
+        ports.record_read(5, 0x8000, 0, 0x41);
+        assert!(ports.take_read_log().is_empty());
+
+        ports.set_read_log(true);
+        ports.write(1);
+        ports.record_read(10, 0x8003, 1, 0x40);
+        ports.write(0);
+        ports.record_read(14, 0x8006, 0, 0x41);
+        assert_eq!(
+            ports.take_read_log(),
+            [
+                ControllerRead {
+                    cycle: 10,
+                    pc: 0x8003,
+                    port: 1,
+                    bit: 0,
+                    strobe: true,
+                },
+                ControllerRead {
+                    cycle: 14,
+                    pc: 0x8006,
+                    port: 0,
+                    bit: 1,
+                    strobe: false,
+                },
+            ]
+        );
+        assert!(ports.read_log_enabled());
+        assert!(ports.take_read_log().is_empty());
+    }
+}
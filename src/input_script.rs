@@ -0,0 +1,266 @@
+// A small language for scripted controller input, so tests and demos can
+// get through menus without hand-writing frame arrays:
+//
+//     # Skip the title screen, then walk right
+//     wait 60
+//     press Start for 2, wait 30
+//     hold Right
+//     press A+B 3
+//     wait 120
+//     release Right
+//
+// Commands are separated by newlines, commas or semicolons, and `#` starts
+// a comment. Button names are case-insensitive and joined with `+`.
+//
+//     press BUTTONS [for] [FRAMES]  hold buttons for FRAMES frames (1)
+//     wait FRAMES                   hold only the held buttons
+//     hold BUTTONS                  keep buttons down until released
+//     release BUTTONS               let go of held buttons
+//
+// Two presses of the same button in a row merge into one long press; put
+// a `wait 1` between them when the game needs to see the release.
+use crate::input::{
+    BUTTON_A, BUTTON_B, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT, BUTTON_SELECT, BUTTON_START,
+    BUTTON_UP,
+};
+use std::fmt;
+
+// Reasons a script cannot be parsed. Lines count from 1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScriptError {
+    UnknownCommand { line: usize, command: String },
+    UnknownButton { line: usize, name: String },
+    // A command that needs buttons has none
+    MissingButtons { line: usize },
+    // A frame count is missing, zero or not a number
+    BadFrameCount { line: usize },
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::UnknownCommand { line, command } => {
+                write!(f, "line {}: unknown command '{}'", line, command)
+            }
+            ScriptError::UnknownButton { line, name } => {
+                write!(f, "line {}: unknown button '{}'", line, name)
+            }
+            ScriptError::MissingButtons { line } => {
+                write!(f, "line {}: expected button names", line)
+            }
+            ScriptError::BadFrameCount { line } => {
+                write!(f, "line {}: expected a frame count above zero", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    Press(u8, u32),
+    Wait(u32),
+    Hold(u8),
+    Release(u8),
+}
+
+// A parsed or built input script for one controller
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputScript {
+    steps: Vec<Step>,
+}
+
+impl InputScript {
+    pub fn new() -> Self {
+        InputScript::default()
+    }
+
+    // Parse the text form described at the top of this file
+    pub fn parse(text: &str) -> Result<InputScript, ScriptError> {
+        let mut script = InputScript::new();
+        for (index, line) in text.lines().enumerate() {
+            let line_no = index + 1;
+            let code = line.split('#').next().unwrap_or("");
+            for command in code.split([',', ';']) {
+                let mut words = command.split_whitespace();
+                let Some(name) = words.next() else {
+                    continue;
+                };
+                let rest: Vec<&str> = words.collect();
+                let step = match name.to_ascii_lowercase().as_str() {
+                    "press" => {
+                        let buttons = parse_buttons(rest.first().copied(), line_no)?;
+                        let count = match &rest[1.min(rest.len())..] {
+                            [] => 1,
+                            ["for", count] | [count] => parse_count(count, line_no)?,
+                            _ => return Err(ScriptError::BadFrameCount { line: line_no }),
+                        };
+                        Step::Press(buttons, count)
+                    }
+                    "wait" => match rest[..] {
+                        [count] => Step::Wait(parse_count(count, line_no)?),
+                        _ => return Err(ScriptError::BadFrameCount { line: line_no }),
+                    },
+                    "hold" => Step::Hold(parse_buttons(rest.first().copied(), line_no)?),
+                    "release" => Step::Release(parse_buttons(rest.first().copied(), line_no)?),
+                    _ => {
+                        return Err(ScriptError::UnknownCommand {
+                            line: line_no,
+                            command: name.to_string(),
+                        })
+                    }
+                };
+                script.steps.push(step);
+            }
+        }
+        Ok(script)
+    }
+
+    // Hold `buttons` (plus anything held) for `frames` frames
+    pub fn press(mut self, buttons: u8, frames: u32) -> Self {
+        self.steps.push(Step::Press(buttons, frames));
+        self
+    }
+
+    // Let `frames` frames pass with only the held buttons down
+    pub fn wait(mut self, frames: u32) -> Self {
+        self.steps.push(Step::Wait(frames));
+        self
+    }
+
+    // Keep `buttons` down until they are released
+    pub fn hold(mut self, buttons: u8) -> Self {
+        self.steps.push(Step::Hold(buttons));
+        self
+    }
+
+    pub fn release(mut self, buttons: u8) -> Self {
+        self.steps.push(Step::Release(buttons));
+        self
+    }
+
+    // The controller state for every frame, ready for
+    // Emulator::set_controller_state
+    pub fn frames(&self) -> Vec<u8> {
+        let mut frames = Vec::new();
+        let mut held = 0;
+        for &step in &self.steps {
+            match step {
+                Step::Press(buttons, count) => {
+                    frames.extend(std::iter::repeat_n(held | buttons, count as usize))
+                }
+                Step::Wait(count) => frames.extend(std::iter::repeat_n(held, count as usize)),
+                Step::Hold(buttons) => held |= buttons,
+                Step::Release(buttons) => held &= !buttons,
+            }
+        }
+        frames
+    }
+}
+
+fn parse_buttons(word: Option<&str>, line: usize) -> Result<u8, ScriptError> {
+    let word = word.ok_or(ScriptError::MissingButtons { line })?;
+    let mut buttons = 0;
+    for name in word.split('+') {
+        buttons |= match name.to_ascii_lowercase().as_str() {
+            "a" => BUTTON_A,
+            "b" => BUTTON_B,
+            "select" => BUTTON_SELECT,
+            "start" => BUTTON_START,
+            "up" => BUTTON_UP,
+            "down" => BUTTON_DOWN,
+            "left" => BUTTON_LEFT,
+            "right" => BUTTON_RIGHT,
+            _ => {
+                return Err(ScriptError::UnknownButton {
+                    line,
+                    name: name.to_string(),
+                })
+            }
+        };
+    }
+    Ok(buttons)
+}
+
+fn parse_count(word: &str, line: usize) -> Result<u32, ScriptError> {
+    match word.parse() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(ScriptError::BadFrameCount { line }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_example_at_the_top_of_the_file() {
+        let text = "# Skip the title screen, then walk right
+                    wait 60
+                    press Start for 2, wait 30
+                    hold Right
+                    press A+B 3
+                    wait 120
+                    release Right";
+        let built = InputScript::new()
+            .wait(60)
+            .press(BUTTON_START, 2)
+            .wait(30)
+            .hold(BUTTON_RIGHT)
+            .press(BUTTON_A | BUTTON_B, 3)
+            .wait(120)
+            .release(BUTTON_RIGHT);
+        assert_eq!(InputScript::parse(text).unwrap(), built);
+        assert_eq!(built.frames().len(), 60 + 2 + 30 + 3 + 120);
+    }
+
+    #[test]
+    fn held_buttons_stay_down_across_frames() {
+        let script =
+            InputScript::parse("hold right; press a; wait 2; release right; wait 1").unwrap();
+        let right = BUTTON_RIGHT;
+        assert_eq!(script.frames(), [right | BUTTON_A, right, right, 0]);
+    }
+
+    #[test]
+    fn press_counts_are_optional() {
+        let script = InputScript::parse("press select\npress up+down for 2").unwrap();
+        assert_eq!(
+            script.frames(),
+            [
+                BUTTON_SELECT,
+                BUTTON_UP | BUTTON_DOWN,
+                BUTTON_UP | BUTTON_DOWN
+            ]
+        );
+        assert_eq!(
+            InputScript::parse("  # nothing\n\n;,").unwrap().frames(),
+            []
+        );
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let error = |text| InputScript::parse(text).unwrap_err();
+        assert_eq!(
+            error("wait 1\njump 2"),
+            ScriptError::UnknownCommand {
+                line: 2,
+                command: "jump".to_string()
+            }
+        );
+        assert_eq!(
+            error("press A+Turbo"),
+            ScriptError::UnknownButton {
+                line: 1,
+                name: "Turbo".to_string()
+            }
+        );
+        assert_eq!(error("\n\nhold"), ScriptError::MissingButtons { line: 3 });
+        assert_eq!(error("wait 0"), ScriptError::BadFrameCount { line: 1 });
+        assert_eq!(error("wait"), ScriptError::BadFrameCount { line: 1 });
+        assert_eq!(error("press A for"), ScriptError::BadFrameCount { line: 1 });
+        assert_eq!(error("press A 2 3"), ScriptError::BadFrameCount { line: 1 });
+    }
+}
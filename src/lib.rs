@@ -0,0 +1,33 @@
+#[cfg(feature = "audio")]
+pub mod apu; // Audio processing unit
+#[cfg(feature = "assembler")]
+pub mod assembler; // Mini 6502 assembler
+pub mod cartridge; // iNES cartridge loading
+#[cfg(feature = "debugger")]
+pub mod coverage; // Opcode coverage tracking
+pub mod cpu6502; // 6502 CPU core
+pub mod emulator; // Console facade
+pub mod emulator_thread; // Emulator on a dedicated thread
+pub mod frame; // Video frame buffer
+pub mod hash; // FNV-1a hashing
+#[cfg(feature = "debugger")]
+pub mod history; // Frame hash history for desync hunting
+pub mod input; // Controller ports
+pub mod input_script; // Scripted controller input
+pub mod observe; // Observation helpers for agents
+pub mod opcodes; // Opcode metadata table
+pub mod pacer; // Real-time frame pacing and sync metrics
+pub mod patch; // IPS/BPS soft-patching
+#[cfg(feature = "debugger")]
+pub mod ram_export; // Symbol-annotated RAM snapshots
+pub mod region; // NTSC/PAL/Dendy timing
+#[cfg(feature = "retro")]
+pub mod retro; // libretro core adapter
+pub mod rng; // Deterministic random number generator
+#[cfg(feature = "online-tests")]
+pub mod test_roms; // Test ROM downloader and cache
+#[cfg(feature = "debugger")]
+pub mod trace; // Instruction tracing and disassembly
+#[cfg(feature = "video-filters")]
+pub mod video; // Video post-processing chain
+pub mod watch; // Memory write tracking
@@ -1,10 +1,372 @@
-mod cpu6502;    // Import the cpu module
+use arness::cartridge::CartridgeError;
+use arness::cpu6502::Cpu6502;
+use arness::emulator::Emulator;
+use arness::history::audit_determinism;
+use arness::input_script::InputScript;
+use arness::patch;
+use arness::ram_export::ram_json;
+use arness::region::{Region, RegionSource};
+use arness::trace::{SymbolTable, TraceFormat, Tracer};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::process::ExitCode;
 
-// Import the Cpu6502 struct from the cpu module
-fn main() {
-    let mut cpu6502 = cpu6502::Cpu6502::new();
+const USAGE: &str = "usage: arness <rom.nes> [options]
+  --frames N        run for N frames worth of CPU time (default 60)
+  --cycles N        run for N CPU cycles
+  --until-done      run until a blargg-style test ROM reports its result
+  --region NAME     ntsc, pal, dendy or auto (default auto)
+  --pc ADDR         start at ADDR (hex) instead of the reset vector, with P=$24
+  --patch FILE      apply an IPS or BPS patch to the ROM when loading
+  --seed N          randomize RAM at power-on with seed N
+  --input FILE      play an input script on controller 1, one step per frame
+  --trace FILE      write an instruction trace to FILE
+  --trace-format F  raw, nestest or symbols (default raw)
+  --trace-range R   only trace PCs in R, e.g. C000-C0FF
+  --symbols FILE    load labels (ld65 -Ln or name = $addr) for tracing
+  --coverage FILE   write an opcode coverage report to FILE
+  --break ADDR      stop point for --ram-json (hex, may be repeated)
+  --ram-json FILE   append a symbol-annotated RAM snapshot to FILE at each
+                    breakpoint, or once after the run without breakpoints
+  --audit           run twice with the same input and compare state hashes
+                    every frame
+  --dump-ram FILE   write internal RAM ($0000-$07FF) to FILE after the run
+  --dump-state      print the CPU registers after the run";
 
-    // Example usage: Load the value 0x10 into the accumulator
-    cpu6502.lda_immediate(0x10);
-    println!("Accumulator: {}", cpu6502.a);
+// Test ROMs report their status at $6000 once this signature is present
+const TEST_STATUS: u16 = 0x6000;
+const TEST_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEST_RUNNING: u8 = 0x80;
+const TEST_NEEDS_RESET: u8 = 0x81;
+
+// Command line options
+struct Options {
+    rom_path: String,
+    frames: Option<u64>,
+    cycles: Option<u64>,
+    until_done: bool,
+    region: Option<Region>,
+    start_pc: Option<u16>,
+    seed: Option<u64>,
+    patch_path: Option<String>,
+    input_path: Option<String>,
+    trace_path: Option<String>,
+    trace_format: TraceFormat,
+    trace_range: Option<(u16, u16)>,
+    symbols_path: Option<String>,
+    coverage_path: Option<String>,
+    breakpoints: Vec<u16>,
+    ram_json_path: Option<String>,
+    audit: bool,
+    dump_ram_path: Option<String>,
+    dump_state: bool,
+}
+
+// Parse the command line, returning an error message on bad input
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        rom_path: String::new(),
+        frames: None,
+        cycles: None,
+        until_done: false,
+        region: None,
+        start_pc: None,
+        seed: None,
+        patch_path: None,
+        input_path: None,
+        trace_path: None,
+        trace_format: TraceFormat::Raw,
+        trace_range: None,
+        symbols_path: None,
+        coverage_path: None,
+        breakpoints: Vec::new(),
+        ram_json_path: None,
+        audit: false,
+        dump_ram_path: None,
+        dump_state: false,
+    };
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value", name))
+        };
+        match arg.as_str() {
+            "--frames" => options.frames = Some(parse_number(&value(arg)?)?),
+            "--cycles" => options.cycles = Some(parse_number(&value(arg)?)?),
+            "--until-done" => options.until_done = true,
+            "--region" => {
+                let name = value(arg)?;
+                options.region = match name.as_str() {
+                    "auto" => None,
+                    _ => Some(Region::from_name(&name).ok_or(format!("unknown region {}", name))?),
+                };
+            }
+            "--pc" => options.start_pc = Some(parse_address(&value(arg)?)?),
+            "--seed" => options.seed = Some(parse_number(&value(arg)?)?),
+            "--patch" => options.patch_path = Some(value(arg)?),
+            "--input" => options.input_path = Some(value(arg)?),
+            "--trace" => options.trace_path = Some(value(arg)?),
+            "--trace-format" => {
+                let name = value(arg)?;
+                options.trace_format = TraceFormat::from_name(&name)
+                    .ok_or(format!("unknown trace format {}", name))?;
+            }
+            "--trace-range" => {
+                let range = value(arg)?;
+                let (start, end) = range
+                    .split_once('-')
+                    .ok_or(format!("bad range {}", range))?;
+                options.trace_range = Some((parse_address(start)?, parse_address(end)?));
+            }
+            "--symbols" => options.symbols_path = Some(value(arg)?),
+            "--coverage" => options.coverage_path = Some(value(arg)?),
+            "--break" => options.breakpoints.push(parse_address(&value(arg)?)?),
+            "--ram-json" => options.ram_json_path = Some(value(arg)?),
+            "--audit" => options.audit = true,
+            "--dump-ram" => options.dump_ram_path = Some(value(arg)?),
+            "--dump-state" => options.dump_state = true,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if options.rom_path.is_empty() => options.rom_path = arg.clone(),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    if options.rom_path.is_empty() {
+        return Err("no ROM given".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_number(text: &str) -> Result<u64, String> {
+    text.parse().map_err(|_| format!("bad number {}", text))
+}
+
+// Parse a hex address, with or without a leading $
+fn parse_address(text: &str) -> Result<u16, String> {
+    let hex = text.trim_start_matches('$');
+    u16::from_str_radix(hex, 16).map_err(|_| format!("bad address {}", text))
+}
+
+// Read the blargg test status, if the ROM has started reporting one
+fn test_status(cpu: &Cpu6502) -> Option<u8> {
+    let signature = [
+        cpu.peek(TEST_STATUS + 1),
+        cpu.peek(TEST_STATUS + 2),
+        cpu.peek(TEST_STATUS + 3),
+    ];
+    (signature == TEST_SIGNATURE).then(|| cpu.peek(TEST_STATUS))
+}
+
+// Read the zero-terminated text a test ROM writes after its status
+fn test_output(cpu: &Cpu6502) -> String {
+    let mut text = String::new();
+    let mut addr = TEST_STATUS + 4;
+    while addr < 0x8000 && cpu.peek(addr) != 0 {
+        text.push(cpu.peek(addr) as char);
+        addr += 1;
+    }
+    text
+}
+
+// Load the ROM into a console set up as the options ask
+fn load(options: &Options) -> Result<Emulator, String> {
+    let mut rom = std::fs::read(&options.rom_path)
+        .map_err(|e| format!("cannot read {}: {}", options.rom_path, e))?;
+    if let Some(path) = &options.patch_path {
+        let patch = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        rom = patch::apply(&rom, &patch).map_err(|e| CartridgeError::Patch(e).to_string())?;
+    }
+    let file_name = std::path::Path::new(&options.rom_path)
+        .file_name()
+        .and_then(|name| name.to_str());
+
+    let mut emulator = Emulator::new();
+    emulator.set_region(options.region);
+    emulator.set_seed(options.seed);
+    emulator
+        .load_rom_named(&rom, file_name)
+        .map_err(|e| e.to_string())?;
+    // Jumping straight in skips the reset sequence, so IRQs start
+    // unmasked the way nestest's automation mode expects (P=$24)
+    if let Some(pc) = options.start_pc {
+        let cpu = emulator.cpu_mut();
+        cpu.pc = pc;
+        cpu.status = 0x24;
+    }
+    Ok(emulator)
+}
+
+fn run(options: &Options) -> Result<u8, String> {
+    let mut emulator = load(options)?;
+    if let Some(mismatch) = emulator.cartridge().and_then(|cart| cart.size_mismatch) {
+        eprintln!("warning: {}", mismatch);
+    }
+    let choice = emulator.region_choice();
+    if !matches!(
+        choice.source,
+        RegionSource::Default | RegionSource::Override
+    ) {
+        eprintln!("region: {:?} (from {:?})", choice.region, choice.source);
+    }
+
+    // Controller input for each frame, from the start of the run
+    let inputs = match &options.input_path {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("cannot read {}: {}", path, e))?;
+            InputScript::parse(&text)
+                .map_err(|e| format!("{}: {}", path, e))?
+                .frames()
+        }
+        None => Vec::new(),
+    };
+
+    if options.audit {
+        let frames = options.frames.unwrap_or(60) as usize;
+        let inputs: Vec<u8> = (0..frames)
+            .map(|frame| inputs.get(frame).copied().unwrap_or(0))
+            .collect();
+        let boot = || load(options).expect("ROM loaded once already");
+        return match audit_determinism(boot, &inputs) {
+            Some(divergence) => {
+                eprintln!(
+                    "runs diverge at frame {} (state {:016x} vs {:016x})",
+                    divergence.ours.frame, divergence.ours.state_hash, divergence.theirs.state_hash
+                );
+                Ok(1)
+            }
+            None => {
+                println!("deterministic over {} frames", frames);
+                Ok(0)
+            }
+        };
+    }
+
+    if options.coverage_path.is_some() {
+        emulator.cpu_mut().coverage = Some(Box::default());
+    }
+
+    let mut tracer = Tracer::new(options.trace_format);
+    tracer.pc_filter = options.trace_range.map(|(start, end)| start..=end);
+    if let Some(path) = &options.symbols_path {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        tracer.symbols = SymbolTable::parse(&text);
+    }
+    let mut trace_out = match &options.trace_path {
+        Some(path) => Some(BufWriter::new(
+            File::create(path).map_err(|e| format!("cannot create {}: {}", path, e))?,
+        )),
+        None => None,
+    };
+    let mut ram_json_out = match &options.ram_json_path {
+        Some(path) => Some(BufWriter::new(
+            File::create(path).map_err(|e| format!("cannot create {}: {}", path, e))?,
+        )),
+        None => None,
+    };
+
+    // Work out when to stop
+    let cycles_per_frame = emulator.region().cpu_cycles_per_frame();
+    let start = emulator.cpu().cycles;
+    let done = |emulator: &Emulator| match (options.cycles, options.frames) {
+        (Some(cycles), _) => emulator.cpu().cycles >= start + cycles,
+        (None, Some(frames)) => emulator.frame_count() >= frames,
+        (None, None) if options.until_done => false,
+        (None, None) => emulator.frame_count() >= 60,
+    };
+
+    let mut exit_code = 0;
+    let mut reset_at = None;
+    while !done(&emulator) {
+        let buttons = inputs.get(emulator.frame_count() as usize);
+        emulator.set_controller_state(0, buttons.copied().unwrap_or(0));
+        let cpu = emulator.cpu();
+        if let Some(out) = ram_json_out.as_mut() {
+            if options.breakpoints.contains(&cpu.pc) {
+                writeln!(out, "{}", ram_json(cpu, &tracer.symbols)).map_err(|e| e.to_string())?;
+            }
+        }
+        if let Some(out) = trace_out.as_mut() {
+            if let Some(line) = tracer.line(cpu) {
+                writeln!(out, "{}", line).map_err(|e| e.to_string())?;
+            }
+        }
+        emulator.step_instruction();
+        let cpu = emulator.cpu();
+        if cpu.jammed {
+            eprintln!("CPU jammed at ${:04X}", cpu.pc);
+            exit_code = 1;
+            break;
+        }
+
+        if !options.until_done {
+            continue;
+        }
+        match test_status(cpu) {
+            Some(TEST_RUNNING) | None => {}
+            Some(TEST_NEEDS_RESET) => {
+                // The ROM wants the reset button pressed after at least 100ms
+                let due = *reset_at.get_or_insert(cpu.cycles + (cycles_per_frame * 6.0) as u64);
+                if cpu.cycles >= due {
+                    reset_at = None;
+                    emulator.reset();
+                }
+            }
+            Some(status) => {
+                print!("{}", test_output(cpu));
+                println!("result: {}", status);
+                exit_code = status;
+                break;
+            }
+        }
+    }
+
+    let cpu = emulator.cpu();
+    if let Some(out) = trace_out.as_mut() {
+        out.flush().map_err(|e| e.to_string())?;
+    }
+    if let Some(out) = ram_json_out.as_mut() {
+        if options.breakpoints.is_empty() {
+            writeln!(out, "{}", ram_json(cpu, &tracer.symbols)).map_err(|e| e.to_string())?;
+        }
+        out.flush().map_err(|e| e.to_string())?;
+    }
+    if let (Some(path), Some(coverage)) = (&options.coverage_path, &cpu.coverage) {
+        std::fs::write(path, coverage.report())
+            .map_err(|e| format!("cannot write {}: {}", path, e))?;
+    }
+    if let Some(path) = &options.dump_ram_path {
+        std::fs::write(path, &cpu.memory[0x0000..0x0800])
+            .map_err(|e| format!("cannot write {}: {}", path, e))?;
+    }
+    if options.dump_state {
+        println!(
+            "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            cpu.pc, cpu.a, cpu.x, cpu.y, cpu.status, cpu.sp, cpu.cycles
+        );
+    }
+    Ok(exit_code)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&options) {
+        Ok(code) => ExitCode::from(code),
+        Err(message) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        }
+    }
 }
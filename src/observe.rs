@@ -0,0 +1,66 @@
+// Observation transforms for agents and machine-learning harnesses:
+// grayscale, downsampled frames and RAM byte vectors. Every function
+// writes into a caller-owned buffer, so a loop that reuses its buffers
+// does not allocate after the first frame.
+use crate::cpu6502::Cpu6502;
+use crate::frame::Frame;
+
+// Size of the console's internal RAM
+pub const RAM_SIZE: usize = 0x0800;
+
+// One byte of luminance per pixel
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GrayFrame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl GrayFrame {
+    pub fn new() -> Self {
+        GrayFrame::default()
+    }
+
+    // Convert a frame to grayscale, averaging each `factor` x `factor`
+    // block into one pixel. A factor of 1 keeps the full size; leftover
+    // rows and columns that do not fill a block are dropped.
+    pub fn capture(&mut self, frame: &Frame, factor: usize) {
+        let factor = factor.max(1);
+        self.width = frame.width / factor;
+        self.height = frame.height / factor;
+        self.pixels.resize(self.width * self.height, 0);
+
+        let area = (factor * factor) as u32;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let mut sum = 0u32;
+                for dy in 0..factor {
+                    let row = (y * factor + dy) * frame.width;
+                    for dx in 0..factor {
+                        let i = (row + x * factor + dx) * 4;
+                        sum += luma(&frame.pixels[i..i + 3]) as u32;
+                    }
+                }
+                self.pixels[y * self.width + x] = (sum / area) as u8;
+            }
+        }
+    }
+}
+
+// ITU-R BT.601 luma from RGB
+#[inline]
+pub fn luma(rgb: &[u8]) -> u8 {
+    ((rgb[0] as u32 * 77 + rgb[1] as u32 * 150 + rgb[2] as u32 * 29) >> 8) as u8
+}
+
+// Copy internal RAM ($0000-$07FF)
+pub fn ram_into(cpu: &Cpu6502, out: &mut [u8; RAM_SIZE]) {
+    out.copy_from_slice(&cpu.memory[..RAM_SIZE]);
+}
+
+// Gather the bytes at chosen addresses, in order, e.g. a game's player
+// position and score variables
+pub fn bytes_into(cpu: &Cpu6502, addrs: &[u16], out: &mut Vec<u8>) {
+    out.clear();
+    out.extend(addrs.iter().map(|&addr| cpu.peek(addr)));
+}
@@ -0,0 +1,508 @@
+// Opcode metadata for the 6502 (official and unofficial instructions),
+// shared by the CPU core, the disassembler and the assembler. Tools
+// outside the crate can use it instead of keeping their own copy.
+
+// How an instruction finds its operand
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+impl AddressingMode {
+    // Instruction size in bytes, including the opcode
+    pub const fn size(self) -> u8 {
+        match self {
+            AddressingMode::Implied | AddressingMode::Accumulator => 1,
+            AddressingMode::Absolute
+            | AddressingMode::AbsoluteX
+            | AddressingMode::AbsoluteY
+            | AddressingMode::Indirect => 3,
+            _ => 2,
+        }
+    }
+}
+
+// The operation an opcode performs, independent of its addressing mode.
+// The CPU core dispatches on this instead of comparing mnemonic strings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Adc,
+    Ahx,
+    Alr,
+    Anc,
+    And,
+    Arr,
+    Asl,
+    Axs,
+    Bcc,
+    Bcs,
+    Beq,
+    Bit,
+    Bmi,
+    Bne,
+    Bpl,
+    Brk,
+    Bvc,
+    Bvs,
+    Clc,
+    Cld,
+    Cli,
+    Clv,
+    Cmp,
+    Cpx,
+    Cpy,
+    Dcp,
+    Dec,
+    Dex,
+    Dey,
+    Eor,
+    Inc,
+    Inx,
+    Iny,
+    Isb,
+    Jam,
+    Jmp,
+    Jsr,
+    Las,
+    Lax,
+    Lda,
+    Ldx,
+    Ldy,
+    Lsr,
+    Nop,
+    Ora,
+    Pha,
+    Php,
+    Pla,
+    Plp,
+    Rla,
+    Rol,
+    Ror,
+    Rra,
+    Rti,
+    Rts,
+    Sax,
+    Sbc,
+    Sec,
+    Sed,
+    Sei,
+    Shx,
+    Shy,
+    Slo,
+    Sre,
+    Sta,
+    Stx,
+    Sty,
+    Tas,
+    Tax,
+    Tay,
+    Tsx,
+    Txa,
+    Txs,
+    Tya,
+    Xaa,
+}
+
+impl Instruction {
+    // Look up a mnemonic, at compile time when building OPCODES
+    pub const fn from_mnemonic(mnemonic: &str) -> Option<Instruction> {
+        Some(match mnemonic.as_bytes() {
+            b"ADC" => Instruction::Adc,
+            b"AHX" => Instruction::Ahx,
+            b"ALR" => Instruction::Alr,
+            b"ANC" => Instruction::Anc,
+            b"AND" => Instruction::And,
+            b"ARR" => Instruction::Arr,
+            b"ASL" => Instruction::Asl,
+            b"AXS" => Instruction::Axs,
+            b"BCC" => Instruction::Bcc,
+            b"BCS" => Instruction::Bcs,
+            b"BEQ" => Instruction::Beq,
+            b"BIT" => Instruction::Bit,
+            b"BMI" => Instruction::Bmi,
+            b"BNE" => Instruction::Bne,
+            b"BPL" => Instruction::Bpl,
+            b"BRK" => Instruction::Brk,
+            b"BVC" => Instruction::Bvc,
+            b"BVS" => Instruction::Bvs,
+            b"CLC" => Instruction::Clc,
+            b"CLD" => Instruction::Cld,
+            b"CLI" => Instruction::Cli,
+            b"CLV" => Instruction::Clv,
+            b"CMP" => Instruction::Cmp,
+            b"CPX" => Instruction::Cpx,
+            b"CPY" => Instruction::Cpy,
+            b"DCP" => Instruction::Dcp,
+            b"DEC" => Instruction::Dec,
+            b"DEX" => Instruction::Dex,
+            b"DEY" => Instruction::Dey,
+            b"EOR" => Instruction::Eor,
+            b"INC" => Instruction::Inc,
+            b"INX" => Instruction::Inx,
+            b"INY" => Instruction::Iny,
+            b"ISB" => Instruction::Isb,
+            b"JAM" => Instruction::Jam,
+            b"JMP" => Instruction::Jmp,
+            b"JSR" => Instruction::Jsr,
+            b"LAS" => Instruction::Las,
+            b"LAX" => Instruction::Lax,
+            b"LDA" => Instruction::Lda,
+            b"LDX" => Instruction::Ldx,
+            b"LDY" => Instruction::Ldy,
+            b"LSR" => Instruction::Lsr,
+            b"NOP" => Instruction::Nop,
+            b"ORA" => Instruction::Ora,
+            b"PHA" => Instruction::Pha,
+            b"PHP" => Instruction::Php,
+            b"PLA" => Instruction::Pla,
+            b"PLP" => Instruction::Plp,
+            b"RLA" => Instruction::Rla,
+            b"ROL" => Instruction::Rol,
+            b"ROR" => Instruction::Ror,
+            b"RRA" => Instruction::Rra,
+            b"RTI" => Instruction::Rti,
+            b"RTS" => Instruction::Rts,
+            b"SAX" => Instruction::Sax,
+            b"SBC" => Instruction::Sbc,
+            b"SEC" => Instruction::Sec,
+            b"SED" => Instruction::Sed,
+            b"SEI" => Instruction::Sei,
+            b"SHX" => Instruction::Shx,
+            b"SHY" => Instruction::Shy,
+            b"SLO" => Instruction::Slo,
+            b"SRE" => Instruction::Sre,
+            b"STA" => Instruction::Sta,
+            b"STX" => Instruction::Stx,
+            b"STY" => Instruction::Sty,
+            b"TAS" => Instruction::Tas,
+            b"TAX" => Instruction::Tax,
+            b"TAY" => Instruction::Tay,
+            b"TSX" => Instruction::Tsx,
+            b"TXA" => Instruction::Txa,
+            b"TXS" => Instruction::Txs,
+            b"TYA" => Instruction::Tya,
+            b"XAA" => Instruction::Xaa,
+            _ => return None,
+        })
+    }
+}
+
+// Everything the decoder needs to know about one opcode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Opcode {
+    pub mnemonic: &'static str,
+    pub instruction: Instruction,
+    pub mode: AddressingMode,
+    pub size: u8,
+    // Base cycle count, without page-cross or branch penalties
+    pub cycles: u8,
+    // Takes one extra cycle when the indexed address crosses a page
+    pub page_cross_penalty: bool,
+    pub official: bool,
+}
+
+const fn op(
+    mnemonic: &'static str,
+    mode: AddressingMode,
+    cycles: u8,
+    page_cross_penalty: bool,
+    official: bool,
+) -> Opcode {
+    let Some(instruction) = Instruction::from_mnemonic(mnemonic) else {
+        panic!("unknown mnemonic in the opcode table");
+    };
+    Opcode {
+        mnemonic,
+        instruction,
+        mode,
+        size: mode.size(),
+        cycles,
+        page_cross_penalty,
+        official,
+    }
+}
+
+use AddressingMode::*;
+
+// Find the opcode byte for a mnemonic and addressing mode, preferring the
+// official encoding when an unofficial duplicate exists (e.g. SBC #imm)
+pub fn find(mnemonic: &str, mode: AddressingMode) -> Option<u8> {
+    let matching = |official: bool| {
+        OPCODES
+            .iter()
+            .position(|op| op.mnemonic == mnemonic && op.mode == mode && op.official == official)
+    };
+    matching(true).or_else(|| matching(false)).map(|i| i as u8)
+}
+
+// Indexed by opcode byte
+pub static OPCODES: [Opcode; 256] = [
+    op("BRK", Implied, 7, false, true),     // $00
+    op("ORA", IndirectX, 6, false, true),   // $01
+    op("JAM", Implied, 2, false, false),    // $02
+    op("SLO", IndirectX, 8, false, false),  // $03
+    op("NOP", ZeroPage, 3, false, false),   // $04
+    op("ORA", ZeroPage, 3, false, true),    // $05
+    op("ASL", ZeroPage, 5, false, true),    // $06
+    op("SLO", ZeroPage, 5, false, false),   // $07
+    op("PHP", Implied, 3, false, true),     // $08
+    op("ORA", Immediate, 2, false, true),   // $09
+    op("ASL", Accumulator, 2, false, true), // $0A
+    op("ANC", Immediate, 2, false, false),  // $0B
+    op("NOP", Absolute, 4, false, false),   // $0C
+    op("ORA", Absolute, 4, false, true),    // $0D
+    op("ASL", Absolute, 6, false, true),    // $0E
+    op("SLO", Absolute, 6, false, false),   // $0F
+    op("BPL", Relative, 2, false, true),    // $10
+    op("ORA", IndirectY, 5, true, true),    // $11
+    op("JAM", Implied, 2, false, false),    // $12
+    op("SLO", IndirectY, 8, false, false),  // $13
+    op("NOP", ZeroPageX, 4, false, false),  // $14
+    op("ORA", ZeroPageX, 4, false, true),   // $15
+    op("ASL", ZeroPageX, 6, false, true),   // $16
+    op("SLO", ZeroPageX, 6, false, false),  // $17
+    op("CLC", Implied, 2, false, true),     // $18
+    op("ORA", AbsoluteY, 4, true, true),    // $19
+    op("NOP", Implied, 2, false, false),    // $1A
+    op("SLO", AbsoluteY, 7, false, false),  // $1B
+    op("NOP", AbsoluteX, 4, true, false),   // $1C
+    op("ORA", AbsoluteX, 4, true, true),    // $1D
+    op("ASL", AbsoluteX, 7, false, true),   // $1E
+    op("SLO", AbsoluteX, 7, false, false),  // $1F
+    op("JSR", Absolute, 6, false, true),    // $20
+    op("AND", IndirectX, 6, false, true),   // $21
+    op("JAM", Implied, 2, false, false),    // $22
+    op("RLA", IndirectX, 8, false, false),  // $23
+    op("BIT", ZeroPage, 3, false, true),    // $24
+    op("AND", ZeroPage, 3, false, true),    // $25
+    op("ROL", ZeroPage, 5, false, true),    // $26
+    op("RLA", ZeroPage, 5, false, false),   // $27
+    op("PLP", Implied, 4, false, true),     // $28
+    op("AND", Immediate, 2, false, true),   // $29
+    op("ROL", Accumulator, 2, false, true), // $2A
+    op("ANC", Immediate, 2, false, false),  // $2B
+    op("BIT", Absolute, 4, false, true),    // $2C
+    op("AND", Absolute, 4, false, true),    // $2D
+    op("ROL", Absolute, 6, false, true),    // $2E
+    op("RLA", Absolute, 6, false, false),   // $2F
+    op("BMI", Relative, 2, false, true),    // $30
+    op("AND", IndirectY, 5, true, true),    // $31
+    op("JAM", Implied, 2, false, false),    // $32
+    op("RLA", IndirectY, 8, false, false),  // $33
+    op("NOP", ZeroPageX, 4, false, false),  // $34
+    op("AND", ZeroPageX, 4, false, true),   // $35
+    op("ROL", ZeroPageX, 6, false, true),   // $36
+    op("RLA", ZeroPageX, 6, false, false),  // $37
+    op("SEC", Implied, 2, false, true),     // $38
+    op("AND", AbsoluteY, 4, true, true),    // $39
+    op("NOP", Implied, 2, false, false),    // $3A
+    op("RLA", AbsoluteY, 7, false, false),  // $3B
+    op("NOP", AbsoluteX, 4, true, false),   // $3C
+    op("AND", AbsoluteX, 4, true, true),    // $3D
+    op("ROL", AbsoluteX, 7, false, true),   // $3E
+    op("RLA", AbsoluteX, 7, false, false),  // $3F
+    op("RTI", Implied, 6, false, true),     // $40
+    op("EOR", IndirectX, 6, false, true),   // $41
+    op("JAM", Implied, 2, false, false),    // $42
+    op("SRE", IndirectX, 8, false, false),  // $43
+    op("NOP", ZeroPage, 3, false, false),   // $44
+    op("EOR", ZeroPage, 3, false, true),    // $45
+    op("LSR", ZeroPage, 5, false, true),    // $46
+    op("SRE", ZeroPage, 5, false, false),   // $47
+    op("PHA", Implied, 3, false, true),     // $48
+    op("EOR", Immediate, 2, false, true),   // $49
+    op("LSR", Accumulator, 2, false, true), // $4A
+    op("ALR", Immediate, 2, false, false),  // $4B
+    op("JMP", Absolute, 3, false, true),    // $4C
+    op("EOR", Absolute, 4, false, true),    // $4D
+    op("LSR", Absolute, 6, false, true),    // $4E
+    op("SRE", Absolute, 6, false, false),   // $4F
+    op("BVC", Relative, 2, false, true),    // $50
+    op("EOR", IndirectY, 5, true, true),    // $51
+    op("JAM", Implied, 2, false, false),    // $52
+    op("SRE", IndirectY, 8, false, false),  // $53
+    op("NOP", ZeroPageX, 4, false, false),  // $54
+    op("EOR", ZeroPageX, 4, false, true),   // $55
+    op("LSR", ZeroPageX, 6, false, true),   // $56
+    op("SRE", ZeroPageX, 6, false, false),  // $57
+    op("CLI", Implied, 2, false, true),     // $58
+    op("EOR", AbsoluteY, 4, true, true),    // $59
+    op("NOP", Implied, 2, false, false),    // $5A
+    op("SRE", AbsoluteY, 7, false, false),  // $5B
+    op("NOP", AbsoluteX, 4, true, false),   // $5C
+    op("EOR", AbsoluteX, 4, true, true),    // $5D
+    op("LSR", AbsoluteX, 7, false, true),   // $5E
+    op("SRE", AbsoluteX, 7, false, false),  // $5F
+    op("RTS", Implied, 6, false, true),     // $60
+    op("ADC", IndirectX, 6, false, true),   // $61
+    op("JAM", Implied, 2, false, false),    // $62
+    op("RRA", IndirectX, 8, false, false),  // $63
+    op("NOP", ZeroPage, 3, false, false),   // $64
+    op("ADC", ZeroPage, 3, false, true),    // $65
+    op("ROR", ZeroPage, 5, false, true),    // $66
+    op("RRA", ZeroPage, 5, false, false),   // $67
+    op("PLA", Implied, 4, false, true),     // $68
+    op("ADC", Immediate, 2, false, true),   // $69
+    op("ROR", Accumulator, 2, false, true), // $6A
+    op("ARR", Immediate, 2, false, false),  // $6B
+    op("JMP", Indirect, 5, false, true),    // $6C
+    op("ADC", Absolute, 4, false, true),    // $6D
+    op("ROR", Absolute, 6, false, true),    // $6E
+    op("RRA", Absolute, 6, false, false),   // $6F
+    op("BVS", Relative, 2, false, true),    // $70
+    op("ADC", IndirectY, 5, true, true),    // $71
+    op("JAM", Implied, 2, false, false),    // $72
+    op("RRA", IndirectY, 8, false, false),  // $73
+    op("NOP", ZeroPageX, 4, false, false),  // $74
+    op("ADC", ZeroPageX, 4, false, true),   // $75
+    op("ROR", ZeroPageX, 6, false, true),   // $76
+    op("RRA", ZeroPageX, 6, false, false),  // $77
+    op("SEI", Implied, 2, false, true),     // $78
+    op("ADC", AbsoluteY, 4, true, true),    // $79
+    op("NOP", Implied, 2, false, false),    // $7A
+    op("RRA", AbsoluteY, 7, false, false),  // $7B
+    op("NOP", AbsoluteX, 4, true, false),   // $7C
+    op("ADC", AbsoluteX, 4, true, true),    // $7D
+    op("ROR", AbsoluteX, 7, false, true),   // $7E
+    op("RRA", AbsoluteX, 7, false, false),  // $7F
+    op("NOP", Immediate, 2, false, false),  // $80
+    op("STA", IndirectX, 6, false, true),   // $81
+    op("NOP", Immediate, 2, false, false),  // $82
+    op("SAX", IndirectX, 6, false, false),  // $83
+    op("STY", ZeroPage, 3, false, true),    // $84
+    op("STA", ZeroPage, 3, false, true),    // $85
+    op("STX", ZeroPage, 3, false, true),    // $86
+    op("SAX", ZeroPage, 3, false, false),   // $87
+    op("DEY", Implied, 2, false, true),     // $88
+    op("NOP", Immediate, 2, false, false),  // $89
+    op("TXA", Implied, 2, false, true),     // $8A
+    op("XAA", Immediate, 2, false, false),  // $8B
+    op("STY", Absolute, 4, false, true),    // $8C
+    op("STA", Absolute, 4, false, true),    // $8D
+    op("STX", Absolute, 4, false, true),    // $8E
+    op("SAX", Absolute, 4, false, false),   // $8F
+    op("BCC", Relative, 2, false, true),    // $90
+    op("STA", IndirectY, 6, false, true),   // $91
+    op("JAM", Implied, 2, false, false),    // $92
+    op("AHX", IndirectY, 6, false, false),  // $93
+    op("STY", ZeroPageX, 4, false, true),   // $94
+    op("STA", ZeroPageX, 4, false, true),   // $95
+    op("STX", ZeroPageY, 4, false, true),   // $96
+    op("SAX", ZeroPageY, 4, false, false),  // $97
+    op("TYA", Implied, 2, false, true),     // $98
+    op("STA", AbsoluteY, 5, false, true),   // $99
+    op("TXS", Implied, 2, false, true),     // $9A
+    op("TAS", AbsoluteY, 5, false, false),  // $9B
+    op("SHY", AbsoluteX, 5, false, false),  // $9C
+    op("STA", AbsoluteX, 5, false, true),   // $9D
+    op("SHX", AbsoluteY, 5, false, false),  // $9E
+    op("AHX", AbsoluteY, 5, false, false),  // $9F
+    op("LDY", Immediate, 2, false, true),   // $A0
+    op("LDA", IndirectX, 6, false, true),   // $A1
+    op("LDX", Immediate, 2, false, true),   // $A2
+    op("LAX", IndirectX, 6, false, false),  // $A3
+    op("LDY", ZeroPage, 3, false, true),    // $A4
+    op("LDA", ZeroPage, 3, false, true),    // $A5
+    op("LDX", ZeroPage, 3, false, true),    // $A6
+    op("LAX", ZeroPage, 3, false, false),   // $A7
+    op("TAY", Implied, 2, false, true),     // $A8
+    op("LDA", Immediate, 2, false, true),   // $A9
+    op("TAX", Implied, 2, false, true),     // $AA
+    op("LAX", Immediate, 2, false, false),  // $AB
+    op("LDY", Absolute, 4, false, true),    // $AC
+    op("LDA", Absolute, 4, false, true),    // $AD
+    op("LDX", Absolute, 4, false, true),    // $AE
+    op("LAX", Absolute, 4, false, false),   // $AF
+    op("BCS", Relative, 2, false, true),    // $B0
+    op("LDA", IndirectY, 5, true, true),    // $B1
+    op("JAM", Implied, 2, false, false),    // $B2
+    op("LAX", IndirectY, 5, true, false),   // $B3
+    op("LDY", ZeroPageX, 4, false, true),   // $B4
+    op("LDA", ZeroPageX, 4, false, true),   // $B5
+    op("LDX", ZeroPageY, 4, false, true),   // $B6
+    op("LAX", ZeroPageY, 4, false, false),  // $B7
+    op("CLV", Implied, 2, false, true),     // $B8
+    op("LDA", AbsoluteY, 4, true, true),    // $B9
+    op("TSX", Implied, 2, false, true),     // $BA
+    op("LAS", AbsoluteY, 4, true, false),   // $BB
+    op("LDY", AbsoluteX, 4, true, true),    // $BC
+    op("LDA", AbsoluteX, 4, true, true),    // $BD
+    op("LDX", AbsoluteY, 4, true, true),    // $BE
+    op("LAX", AbsoluteY, 4, true, false),   // $BF
+    op("CPY", Immediate, 2, false, true),   // $C0
+    op("CMP", IndirectX, 6, false, true),   // $C1
+    op("NOP", Immediate, 2, false, false),  // $C2
+    op("DCP", IndirectX, 8, false, false),  // $C3
+    op("CPY", ZeroPage, 3, false, true),    // $C4
+    op("CMP", ZeroPage, 3, false, true),    // $C5
+    op("DEC", ZeroPage, 5, false, true),    // $C6
+    op("DCP", ZeroPage, 5, false, false),   // $C7
+    op("INY", Implied, 2, false, true),     // $C8
+    op("CMP", Immediate, 2, false, true),   // $C9
+    op("DEX", Implied, 2, false, true),     // $CA
+    op("AXS", Immediate, 2, false, false),  // $CB
+    op("CPY", Absolute, 4, false, true),    // $CC
+    op("CMP", Absolute, 4, false, true),    // $CD
+    op("DEC", Absolute, 6, false, true),    // $CE
+    op("DCP", Absolute, 6, false, false),   // $CF
+    op("BNE", Relative, 2, false, true),    // $D0
+    op("CMP", IndirectY, 5, true, true),    // $D1
+    op("JAM", Implied, 2, false, false),    // $D2
+    op("DCP", IndirectY, 8, false, false),  // $D3
+    op("NOP", ZeroPageX, 4, false, false),  // $D4
+    op("CMP", ZeroPageX, 4, false, true),   // $D5
+    op("DEC", ZeroPageX, 6, false, true),   // $D6
+    op("DCP", ZeroPageX, 6, false, false),  // $D7
+    op("CLD", Implied, 2, false, true),     // $D8
+    op("CMP", AbsoluteY, 4, true, true),    // $D9
+    op("NOP", Implied, 2, false, false),    // $DA
+    op("DCP", AbsoluteY, 7, false, false),  // $DB
+    op("NOP", AbsoluteX, 4, true, false),   // $DC
+    op("CMP", AbsoluteX, 4, true, true),    // $DD
+    op("DEC", AbsoluteX, 7, false, true),   // $DE
+    op("DCP", AbsoluteX, 7, false, false),  // $DF
+    op("CPX", Immediate, 2, false, true),   // $E0
+    op("SBC", IndirectX, 6, false, true),   // $E1
+    op("NOP", Immediate, 2, false, false),  // $E2
+    op("ISB", IndirectX, 8, false, false),  // $E3
+    op("CPX", ZeroPage, 3, false, true),    // $E4
+    op("SBC", ZeroPage, 3, false, true),    // $E5
+    op("INC", ZeroPage, 5, false, true),    // $E6
+    op("ISB", ZeroPage, 5, false, false),   // $E7
+    op("INX", Implied, 2, false, true),     // $E8
+    op("SBC", Immediate, 2, false, true),   // $E9
+    op("NOP", Implied, 2, false, true),     // $EA
+    op("SBC", Immediate, 2, false, false),  // $EB
+    op("CPX", Absolute, 4, false, true),    // $EC
+    op("SBC", Absolute, 4, false, true),    // $ED
+    op("INC", Absolute, 6, false, true),    // $EE
+    op("ISB", Absolute, 6, false, false),   // $EF
+    op("BEQ", Relative, 2, false, true),    // $F0
+    op("SBC", IndirectY, 5, true, true),    // $F1
+    op("JAM", Implied, 2, false, false),    // $F2
+    op("ISB", IndirectY, 8, false, false),  // $F3
+    op("NOP", ZeroPageX, 4, false, false),  // $F4
+    op("SBC", ZeroPageX, 4, false, true),   // $F5
+    op("INC", ZeroPageX, 6, false, true),   // $F6
+    op("ISB", ZeroPageX, 6, false, false),  // $F7
+    op("SED", Implied, 2, false, true),     // $F8
+    op("SBC", AbsoluteY, 4, true, true),    // $F9
+    op("NOP", Implied, 2, false, false),    // $FA
+    op("ISB", AbsoluteY, 7, false, false),  // $FB
+    op("NOP", AbsoluteX, 4, true, false),   // $FC
+    op("SBC", AbsoluteX, 4, true, true),    // $FD
+    op("INC", AbsoluteX, 7, false, true),   // $FE
+    op("ISB", AbsoluteX, 7, false, false),  // $FF
+];
@@ -0,0 +1,217 @@
+// Real-time frame pacing for simple front-ends. Sleeping alone overshoots
+// by up to a scheduler tick, so the pacer sleeps until shortly before the
+// deadline and spins for the rest.
+use crate::region::Region;
+use std::time::{Duration, Instant};
+
+// How long before the deadline to stop sleeping and start spinning
+pub const DEFAULT_SPIN: Duration = Duration::from_millis(2);
+
+// Keeps frames at the region's exact rate (60.0988 Hz NTSC, 50.007 Hz PAL)
+#[derive(Clone, Debug)]
+pub struct FramePacer {
+    frame_rate: f64,
+    spin: Duration,
+    start: Instant,
+    frames: u64,
+}
+
+impl FramePacer {
+    pub fn new(region: Region) -> Self {
+        FramePacer::with_rate(region.frame_rate())
+    }
+
+    // Pace at an arbitrary rate, e.g. for fast-forward. Panics unless the
+    // rate is finite and above zero.
+    pub fn with_rate(frame_rate: f64) -> Self {
+        check_rate("frame rate", frame_rate);
+        FramePacer {
+            frame_rate,
+            spin: DEFAULT_SPIN,
+            start: Instant::now(),
+            frames: 0,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        self.frame_rate
+    }
+
+    // Change how much of each wait is spent spinning
+    pub fn set_spin(&mut self, spin: Duration) {
+        self.spin = spin;
+    }
+
+    // Start counting frames from now
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+        self.frames = 0;
+    }
+
+    // The time the next frame is due. Deadlines are computed from the start
+    // time rather than by adding a rounded period, so they never drift.
+    pub fn next_deadline(&self) -> Instant {
+        self.start + Duration::from_secs_f64((self.frames + 1) as f64 / self.frame_rate)
+    }
+
+    // Wait until the next frame is due. If the caller has fallen more than a
+    // frame behind, the schedule restarts from now instead of rushing to
+    // catch up. Returns how late the frame was.
+    pub fn wait(&mut self) -> Duration {
+        let deadline = self.next_deadline();
+        let now = Instant::now();
+        if now < deadline {
+            let remaining = deadline - now;
+            if remaining > self.spin {
+                std::thread::sleep(remaining - self.spin);
+            }
+            while Instant::now() < deadline {
+                std::hint::spin_loop();
+            }
+        }
+        let late = Instant::now().saturating_duration_since(deadline);
+        self.frames += 1;
+        if late.as_secs_f64() * self.frame_rate > 1.0 {
+            self.reset();
+        }
+        late
+    }
+}
+
+// A zero, negative or NaN rate would make every deadline and drift
+// meaningless (or divide by zero), so it is a caller bug
+fn check_rate(name: &str, rate: f64) {
+    assert!(
+        rate.is_finite() && rate > 0.0,
+        "{} must be finite and above zero, got {}",
+        name,
+        rate
+    );
+}
+
+// Drift between emulated output and wall time, for dynamic rate control.
+// Front-ends report frames and audio samples as they are produced; a
+// positive drift means the emulator is ahead of real time.
+#[derive(Clone, Debug)]
+pub struct SyncMetrics {
+    frame_rate: f64,
+    sample_rate: f64,
+    start: Instant,
+    frames: u64,
+    samples: u64,
+}
+
+impl SyncMetrics {
+    // Panics unless both rates are finite and above zero
+    pub fn new(frame_rate: f64, sample_rate: f64) -> Self {
+        check_rate("frame rate", frame_rate);
+        check_rate("sample rate", sample_rate);
+        SyncMetrics {
+            frame_rate,
+            sample_rate,
+            start: Instant::now(),
+            frames: 0,
+            samples: 0,
+        }
+    }
+
+    // Start measuring from now
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+        self.frames = 0;
+        self.samples = 0;
+    }
+
+    pub fn record_frame(&mut self) {
+        self.frames += 1;
+    }
+
+    pub fn record_samples(&mut self, count: usize) {
+        self.samples += count as u64;
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    // Frames produced minus frames due by now
+    pub fn video_drift(&self) -> f64 {
+        self.video_drift_at(Instant::now())
+    }
+
+    pub fn video_drift_at(&self, now: Instant) -> f64 {
+        self.frames as f64 - self.elapsed(now) * self.frame_rate
+    }
+
+    // Samples produced minus samples due by now
+    pub fn audio_drift(&self) -> f64 {
+        self.audio_drift_at(Instant::now())
+    }
+
+    pub fn audio_drift_at(&self, now: Instant) -> f64 {
+        self.samples as f64 - self.elapsed(now) * self.sample_rate
+    }
+
+    // Ratio of generated to ideal audio samples per emulated frame. A
+    // front-end pacing video to the display can resample audio by this
+    // factor to keep the audio buffer level steady.
+    pub fn audio_ratio(&self) -> f64 {
+        if self.frames == 0 {
+            return 1.0;
+        }
+        let ideal = self.frames as f64 * self.sample_rate / self.frame_rate;
+        self.samples as f64 / ideal
+    }
+
+    fn elapsed(&self, now: Instant) -> f64 {
+        now.saturating_duration_since(self.start).as_secs_f64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_region_rates() {
+        assert_eq!(
+            FramePacer::new(Region::Pal).frame_rate(),
+            Region::Pal.frame_rate()
+        );
+        assert_eq!(SyncMetrics::new(60.0, 48_000.0).audio_ratio(), 1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "frame rate must be finite and above zero, got 0")]
+    fn pacer_rejects_zero() {
+        FramePacer::with_rate(0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "frame rate must be finite and above zero, got -60")]
+    fn pacer_rejects_negative_rates() {
+        FramePacer::with_rate(-60.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "frame rate must be finite and above zero, got NaN")]
+    fn pacer_rejects_nan() {
+        FramePacer::with_rate(f64::NAN);
+    }
+
+    #[test]
+    #[should_panic(expected = "frame rate must be finite")]
+    fn metrics_reject_a_bad_frame_rate() {
+        SyncMetrics::new(f64::INFINITY, 48_000.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "sample rate must be finite and above zero, got 0")]
+    fn metrics_reject_a_bad_sample_rate() {
+        SyncMetrics::new(60.0, 0.0);
+    }
+}
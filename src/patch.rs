@@ -0,0 +1,451 @@
+// Soft-patching of ROM images with IPS and BPS patches, so translations and
+// romhacks can be played without modifying files on disk
+use std::fmt;
+
+// Largest BPS output accepted. Far above any NES image, but it stops a tiny
+// patch from declaring a huge target and filling it with TargetCopy.
+const MAX_BPS_TARGET: usize = 64 * 1024 * 1024;
+
+// Reasons a patch cannot be applied
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    // Neither an IPS ("PATCH") nor a BPS ("BPS1") patch
+    UnknownFormat,
+    // The patch ends in the middle of a record
+    Truncated,
+    // A BPS action reads or writes outside the source or target
+    OutOfBounds,
+    // The BPS patch was made for a different ROM
+    SourceChecksum { expected: u32, actual: u32 },
+    // Applying the BPS patch did not produce the expected ROM
+    TargetChecksum { expected: u32, actual: u32 },
+    // The BPS patch file itself is corrupt
+    PatchChecksum { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "unknown patch format"),
+            PatchError::Truncated => write!(f, "patch is truncated"),
+            PatchError::OutOfBounds => write!(f, "patch accesses data out of bounds"),
+            PatchError::SourceChecksum { expected, actual } => write!(
+                f,
+                "patch is for a different ROM (CRC32 {:08X}, ROM has {:08X})",
+                expected, actual
+            ),
+            PatchError::TargetChecksum { expected, actual } => write!(
+                f,
+                "patched ROM has CRC32 {:08X}, expected {:08X}",
+                actual, expected
+            ),
+            PatchError::PatchChecksum { expected, actual } => write!(
+                f,
+                "patch is corrupt (CRC32 {:08X}, expected {:08X})",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+// Apply an IPS or BPS patch, detected from its header
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+// Apply an IPS patch. Records past the end of the ROM grow it, and the
+// optional truncation length after "EOF" is honored.
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(b"PATCH") {
+        return Err(PatchError::UnknownFormat);
+    }
+    let mut out = rom.to_vec();
+    let mut reader = Reader::new(&patch[5..]);
+
+    loop {
+        let header = reader.take(3)?;
+        if header == b"EOF" {
+            if let Ok(length) = reader.take(3) {
+                out.truncate(be_u24(length));
+            }
+            return Ok(out);
+        }
+        let offset = be_u24(header);
+        let size = be_u16(reader.take(2)?);
+        let data = if size == 0 {
+            // Run-length encoded record
+            let count = be_u16(reader.take(2)?);
+            vec![reader.byte()?; count]
+        } else {
+            reader.take(size)?.to_vec()
+        };
+        if out.len() < offset + data.len() {
+            out.resize(offset + data.len(), 0);
+        }
+        out[offset..offset + data.len()].copy_from_slice(&data);
+    }
+}
+
+// Apply a BPS patch, validating the source, target and patch checksums
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(b"BPS1") {
+        return Err(PatchError::UnknownFormat);
+    }
+    if patch.len() < 4 + 12 {
+        return Err(PatchError::Truncated);
+    }
+    let footer = patch.len() - 12;
+    let checksum = |at: usize| u32::from_le_bytes(patch[at..at + 4].try_into().unwrap());
+    let (source_crc, target_crc, patch_crc) =
+        (checksum(footer), checksum(footer + 4), checksum(footer + 8));
+
+    let actual = crc32(&patch[..footer + 8]);
+    if actual != patch_crc {
+        return Err(PatchError::PatchChecksum {
+            expected: patch_crc,
+            actual,
+        });
+    }
+    let actual = crc32(rom);
+    if actual != source_crc {
+        return Err(PatchError::SourceChecksum {
+            expected: source_crc,
+            actual,
+        });
+    }
+
+    let mut reader = Reader::new(&patch[4..footer]);
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.take(metadata_size)?;
+    if target_size > MAX_BPS_TARGET {
+        return Err(PatchError::OutOfBounds);
+    }
+    if source_size != rom.len() {
+        return Err(PatchError::SourceChecksum {
+            expected: source_crc,
+            actual: crc32(rom),
+        });
+    }
+
+    // The declared size is untrusted, so only reserve what a patch of this
+    // size plausibly produces; the buffer still grows if it needs more
+    let mut target = Vec::with_capacity(target_size.min(rom.len() + patch.len() * 4));
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+    while !reader.is_empty() {
+        let data = reader.varint()?;
+        // Checked before copying so a small patch cannot make huge output
+        let length = (data >> 2) + 1;
+        if length > target_size - target.len() {
+            return Err(PatchError::OutOfBounds);
+        }
+        match data & 3 {
+            // SourceRead: copy from the same position in the source
+            0 => {
+                let start = target.len();
+                let bytes = rom
+                    .get(start..start.saturating_add(length))
+                    .ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+            }
+            // TargetRead: literal bytes from the patch
+            1 => target.extend_from_slice(reader.take(length)?),
+            // SourceCopy: copy from a relative position in the source
+            2 => {
+                source_offset = offset(source_offset, reader.signed_varint()?)?;
+                let start = usize::try_from(source_offset).map_err(|_| PatchError::OutOfBounds)?;
+                let bytes = rom
+                    .get(start..start.saturating_add(length))
+                    .ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+                source_offset += length as isize;
+            }
+            // TargetCopy: copy already written output, byte by byte since
+            // the ranges may overlap
+            _ => {
+                target_offset = offset(target_offset, reader.signed_varint()?)?;
+                for _ in 0..length {
+                    let at = usize::try_from(target_offset).map_err(|_| PatchError::OutOfBounds)?;
+                    let byte = *target.get(at).ok_or(PatchError::OutOfBounds)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    let actual = crc32(&target);
+    if target.len() != target_size || actual != target_crc {
+        return Err(PatchError::TargetChecksum {
+            expected: target_crc,
+            actual,
+        });
+    }
+    Ok(target)
+}
+
+// Move a BPS copy position, rejecting positions that cannot exist
+fn offset(position: isize, delta: isize) -> Result<isize, PatchError> {
+    position.checked_add(delta).ok_or(PatchError::OutOfBounds)
+}
+
+// Standard CRC-32 (IEEE, reflected), as used by BPS and ROM databases
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn be_u16(bytes: &[u8]) -> usize {
+    (bytes[0] as usize) << 8 | bytes[1] as usize
+}
+
+fn be_u24(bytes: &[u8]) -> usize {
+    (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize
+}
+
+// Cursor over patch bytes
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], PatchError> {
+        let slice = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(count))
+            .ok_or(PatchError::Truncated)?;
+        self.pos += count;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.take(1)?[0])
+    }
+
+    // BPS variable-length number
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut data = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let x = self.byte()?;
+            data = ((x & 0x7F) as usize)
+                .checked_mul(shift)
+                .and_then(|value| data.checked_add(value))
+                .ok_or(PatchError::OutOfBounds)?;
+            if x & 0x80 != 0 {
+                return Ok(data);
+            }
+            shift = shift.checked_mul(128).ok_or(PatchError::OutOfBounds)?;
+            data = data.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+        }
+    }
+
+    // BPS relative offset: the low bit is the sign
+    fn signed_varint(&mut self) -> Result<isize, PatchError> {
+        let data = self.varint()?;
+        let value = (data >> 1) as isize;
+        Ok(if data & 1 != 0 { -value } else { value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(out: &mut Vec<u8>, mut data: usize) {
+        loop {
+            let x = (data & 0x7F) as u8;
+            data >>= 7;
+            if data == 0 {
+                out.push(0x80 | x);
+                return;
+            }
+            out.push(x);
+            data -= 1;
+        }
+    }
+
+    fn signed(out: &mut Vec<u8>, delta: isize) {
+        varint(out, delta.unsigned_abs() << 1 | (delta < 0) as usize);
+    }
+
+    // A BPS patch with the given actions and checksums from the arguments
+    fn bps(source: &[u8], target_size: usize, actions: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, target_size);
+        varint(&mut patch, 0);
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    // Action header: length in the upper bits, kind in the low two
+    fn action(out: &mut Vec<u8>, kind: usize, length: usize) {
+        varint(out, (length - 1) << 2 | kind);
+    }
+
+    const SOURCE: [u8; 4] = [1, 2, 3, 4];
+    const TARGET: [u8; 9] = [1, 2, 9, 9, 3, 4, 1, 2, 9];
+
+    // SourceRead 2, TargetRead [9, 9], SourceCopy 2 from +2, TargetCopy 3
+    // from the start: every action kind once
+    fn actions() -> Vec<u8> {
+        let mut actions = Vec::new();
+        action(&mut actions, 0, 2);
+        action(&mut actions, 1, 2);
+        actions.extend_from_slice(&[9, 9]);
+        action(&mut actions, 2, 2);
+        signed(&mut actions, 2);
+        action(&mut actions, 3, 3);
+        signed(&mut actions, 0);
+        actions
+    }
+
+    #[test]
+    fn ips_records_rle_and_truncation() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 2, 0, 2, 0xAA, 0xBB]);
+        // RLE: four $99 at offset 6, past the end of the ROM
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 4, 0x99]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(
+            apply(&[0; 8], &patch).unwrap(),
+            [0, 0, 0xAA, 0xBB, 0, 0, 0x99, 0x99, 0x99, 0x99]
+        );
+        patch.extend_from_slice(&[0, 0, 5]);
+        assert_eq!(apply(&[0; 8], &patch).unwrap(), [0, 0, 0xAA, 0xBB, 0]);
+    }
+
+    #[test]
+    fn ips_rejects_cut_off_records() {
+        assert_eq!(apply(&[0; 4], b"PATCH"), Err(PatchError::Truncated));
+        assert_eq!(
+            apply(&[0; 4], b"PATCH\x00\x00\x01\x00\x04\xAA"),
+            Err(PatchError::Truncated)
+        );
+        assert_eq!(
+            apply(&[0; 4], b"PATCH\x00\x00\x01\x00\x00\x00"),
+            Err(PatchError::Truncated)
+        );
+        assert_eq!(apply(&[0; 4], b"NOTAPATCH"), Err(PatchError::UnknownFormat));
+    }
+
+    #[test]
+    fn bps_applies_every_action() {
+        let patch = bps(&SOURCE, TARGET.len(), &actions(), &TARGET);
+        assert_eq!(apply(&SOURCE, &patch).unwrap(), TARGET);
+    }
+
+    #[test]
+    fn bps_checks_all_three_checksums() {
+        let patch = bps(&SOURCE, TARGET.len(), &actions(), &TARGET);
+        assert!(matches!(
+            apply(&[1, 2, 3, 5], &patch),
+            Err(PatchError::SourceChecksum { .. })
+        ));
+
+        let wrong_target = bps(&SOURCE, TARGET.len(), &actions(), &[0; 9]);
+        assert!(matches!(
+            apply(&SOURCE, &wrong_target),
+            Err(PatchError::TargetChecksum { .. })
+        ));
+
+        let mut corrupt = patch.clone();
+        corrupt[8] ^= 1;
+        assert!(matches!(
+            apply(&SOURCE, &corrupt),
+            Err(PatchError::PatchChecksum { .. })
+        ));
+        assert_eq!(apply(&SOURCE, b"BPS1"), Err(PatchError::Truncated));
+    }
+
+    #[test]
+    fn bps_rejects_overflowing_numbers() {
+        // A varint that never ends before it overflows usize
+        let mut patch = b"BPS1".to_vec();
+        varint(&mut patch, SOURCE.len());
+        patch.extend_from_slice(&[0x7F; 12]);
+        patch.push(0xFF);
+        patch.extend_from_slice(&crc32(&SOURCE).to_le_bytes());
+        patch.extend_from_slice(&0u32.to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        assert_eq!(apply(&SOURCE, &patch), Err(PatchError::OutOfBounds));
+    }
+
+    #[test]
+    fn bps_rejects_huge_targets() {
+        let patch = bps(&SOURCE, MAX_BPS_TARGET + 1, &[], &[]);
+        assert_eq!(apply(&SOURCE, &patch), Err(PatchError::OutOfBounds));
+
+        // An action longer than the declared target
+        let mut actions = Vec::new();
+        action(&mut actions, 3, 1 << 40);
+        signed(&mut actions, 0);
+        let patch = bps(&SOURCE, 4, &actions, &[]);
+        assert_eq!(apply(&SOURCE, &patch), Err(PatchError::OutOfBounds));
+    }
+
+    #[test]
+    fn bps_rejects_copies_out_of_bounds() {
+        let rejected = |actions: Vec<u8>| {
+            let patch = bps(&SOURCE, 8, &actions, &[]);
+            assert_eq!(apply(&SOURCE, &patch), Err(PatchError::OutOfBounds));
+        };
+
+        // SourceRead past the end of the source
+        let mut actions = Vec::new();
+        action(&mut actions, 0, 5);
+        rejected(actions);
+
+        // SourceCopy from before the start
+        let mut actions = Vec::new();
+        action(&mut actions, 2, 1);
+        signed(&mut actions, -1);
+        rejected(actions);
+
+        // SourceCopy whose position overflows
+        let mut actions = Vec::new();
+        action(&mut actions, 2, 1);
+        signed(&mut actions, 0);
+        action(&mut actions, 2, 1);
+        signed(&mut actions, isize::MAX);
+        rejected(actions);
+
+        // TargetCopy of output that has not been written yet
+        let mut actions = Vec::new();
+        action(&mut actions, 3, 1);
+        signed(&mut actions, 0);
+        rejected(actions);
+    }
+}
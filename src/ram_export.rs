@@ -0,0 +1,50 @@
+// JSON snapshots of RAM annotated with symbol names, so editor plugins can
+// show variable views when a breakpoint is hit without a custom protocol
+use crate::cpu6502::Cpu6502;
+use crate::trace::SymbolTable;
+use std::fmt::Write;
+
+// Internal RAM as one JSON object on a single line: registers, the 2KB of
+// RAM as hex, and the current value of every symbol below $8000
+pub fn ram_json(cpu: &Cpu6502, symbols: &SymbolTable) -> String {
+    let mut json = String::new();
+    let _ = write!(
+        json,
+        "{{\"pc\":{},\"cycles\":{},\"registers\":{{\"a\":{},\"x\":{},\"y\":{},\"p\":{},\"sp\":{}}},\"ram\":\"",
+        cpu.pc, cpu.cycles, cpu.a, cpu.x, cpu.y, cpu.status, cpu.sp
+    );
+    for byte in &cpu.memory[0x0000..0x0800] {
+        let _ = write!(json, "{:02x}", byte);
+    }
+    json.push_str("\",\"symbols\":[");
+    let ram_symbols = symbols.iter().filter(|&(addr, _)| addr < 0x8000);
+    for (i, (addr, name)) in ram_symbols.enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(
+            json,
+            "{{\"name\":\"{}\",\"address\":{},\"value\":{}}}",
+            escape(name),
+            addr,
+            cpu.peek(addr)
+        );
+    }
+    json.push_str("]}");
+    json
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,118 @@
+use crate::cartridge::Cartridge;
+
+// Console timing region
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    // CPU clock rate in Hz
+    pub fn cpu_clock_hz(self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_772.727,
+            Region::Pal => 1_662_607.031,
+            Region::Dendy => 1_773_447.467,
+        }
+    }
+
+    // Average number of CPU cycles in one video frame
+    pub fn cpu_cycles_per_frame(self) -> f64 {
+        match self {
+            Region::Ntsc => 29_780.5,
+            Region::Pal => 33_247.5,
+            Region::Dendy => 35_464.0,
+        }
+    }
+
+    // CPU cycles from the start of a frame, taken as the pre-render line,
+    // to the start of vertical blank at scanline 241 dot 1 (291 on Dendy)
+    pub fn vblank_start_cycles(self) -> f64 {
+        let (line, dots_per_cycle) = match self {
+            Region::Ntsc => (241, 3.0),
+            Region::Pal => (241, 3.2),
+            Region::Dendy => (291, 3.0),
+        };
+        ((line + 1) * 341 + 1) as f64 / dots_per_cycle
+    }
+
+    // Video frames per second
+    pub fn frame_rate(self) -> f64 {
+        self.cpu_clock_hz() / self.cpu_cycles_per_frame()
+    }
+
+    // Parse a region name as used on the command line
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+}
+
+// Where an automatic region choice came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionSource {
+    Header,
+    Filename,
+    Default,
+    // Forced by the user rather than detected
+    Override,
+}
+
+// The region picked for a ROM and the reason for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegionChoice {
+    pub region: Region,
+    pub source: RegionSource,
+}
+
+// Pick the region for a cartridge: the header timing field wins, then
+// GoodNES/No-Intro style tags in the file name, then NTSC
+pub fn detect(cart: &Cartridge, file_name: Option<&str>) -> RegionChoice {
+    if let Some(region) = cart.header_region {
+        return RegionChoice {
+            region,
+            source: RegionSource::Header,
+        };
+    }
+    if let Some(region) = file_name.and_then(region_from_file_name) {
+        return RegionChoice {
+            region,
+            source: RegionSource::Filename,
+        };
+    }
+    RegionChoice {
+        region: Region::Ntsc,
+        source: RegionSource::Default,
+    }
+}
+
+// Look for region tags such as "(E)", "(Europe)" or "(USA)" in a file name
+pub fn region_from_file_name(name: &str) -> Option<Region> {
+    let name = name.to_ascii_lowercase();
+    let mut found = None;
+    for tag in name.split(['(', '[']).skip(1) {
+        let tag = tag.split([')', ']']).next().unwrap_or("");
+        for word in tag.split(',').map(str::trim) {
+            let region = match word {
+                "e" | "europe" | "eu" | "pal" | "a" | "australia" | "g" | "germany" | "f"
+                | "france" | "sw" | "sweden" | "i" | "italy" | "s" | "spain" | "uk" => Region::Pal,
+                "u" | "usa" | "us" | "j" | "japan" | "ntsc" | "k" | "korea" => Region::Ntsc,
+                "dendy" => Region::Dendy,
+                _ => continue,
+            };
+            // Ambiguous names like "(U)(E)" are left to the default
+            if found.is_some_and(|f| f != region) {
+                return None;
+            }
+            found = Some(region);
+        }
+    }
+    found
+}
@@ -0,0 +1,499 @@
+// libretro core entry points over the Emulator facade, so the crate can be
+// packaged as a RetroArch core. Build the shared library with
+//
+//     cargo rustc --release --lib --features retro --crate-type cdylib
+//
+// libretro calls every entry point from the same thread, so the core lives
+// in a thread-local. Controllers 0 and 1 are read as RetroPad joypads,
+// video is sent as XRGB8888 and audio as stereo copies of the mono mix.
+// Save states are not supported yet: retro_serialize_size reports 0, which
+// front-ends take as "no save states". Cheats of the form AAAA:VV freeze
+// a byte of RAM.
+//
+// The pointer contracts are the ones in libretro.h.
+#![allow(clippy::missing_safety_doc)]
+
+use crate::emulator::Emulator;
+use crate::frame::{Frame, FRAME_HEIGHT, FRAME_WIDTH};
+use crate::input::{
+    BUTTON_A, BUTTON_B, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT, BUTTON_SELECT, BUTTON_START,
+    BUTTON_UP,
+};
+use crate::region::Region;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_uint, c_void, CStr};
+use std::path::Path;
+
+pub const API_VERSION: c_uint = 1;
+
+// Values from libretro.h
+const ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const DEVICE_JOYPAD: c_uint = 1;
+const REGION_NTSC: c_uint = 0;
+const REGION_PAL: c_uint = 1;
+const MEMORY_SAVE_RAM: c_uint = 0;
+const MEMORY_SYSTEM_RAM: c_uint = 2;
+
+// RetroPad button ids and the NES buttons they drive
+const JOYPAD_MAP: [(c_uint, u8); 8] = [
+    (0, BUTTON_B),
+    (2, BUTTON_SELECT),
+    (3, BUTTON_START),
+    (4, BUTTON_UP),
+    (5, BUTTON_DOWN),
+    (6, BUTTON_LEFT),
+    (7, BUTTON_RIGHT),
+    (8, BUTTON_A),
+];
+
+// Internal RAM and cartridge RAM as exposed through retro_get_memory_data
+const SYSTEM_RAM: std::ops::Range<usize> = 0x0000..0x0800;
+const SAVE_RAM: std::ops::Range<usize> = 0x6000..0x8000;
+
+#[repr(C)]
+pub struct SystemInfo {
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    pub valid_extensions: *const c_char,
+    pub need_fullpath: bool,
+    pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    pub base_width: c_uint,
+    pub base_height: c_uint,
+    pub max_width: c_uint,
+    pub max_height: c_uint,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    pub geometry: GameGeometry,
+    pub timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+// Front-end callbacks. They are nullable in C, hence the Options.
+pub type EnvironmentFn = Option<unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool>;
+pub type VideoRefreshFn =
+    Option<unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize)>;
+pub type AudioSampleFn = Option<unsafe extern "C" fn(left: i16, right: i16)>;
+pub type AudioSampleBatchFn =
+    Option<unsafe extern "C" fn(data: *const i16, frames: usize) -> usize>;
+pub type InputPollFn = Option<unsafe extern "C" fn()>;
+pub type InputStateFn =
+    Option<unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16>;
+
+#[derive(Default)]
+struct Core {
+    emulator: Emulator,
+    loaded: bool,
+    environment: EnvironmentFn,
+    video_refresh: VideoRefreshFn,
+    audio_sample_batch: AudioSampleBatchFn,
+    input_poll: InputPollFn,
+    input_state: InputStateFn,
+    // Conversion buffers, kept between frames
+    video: Vec<u32>,
+    audio: Vec<i16>,
+}
+
+thread_local! {
+    static CORE: RefCell<Core> = RefCell::new(Core::default());
+}
+
+fn with_core<T>(f: impl FnOnce(&mut Core) -> T) -> T {
+    CORE.with(|core| f(&mut core.borrow_mut()))
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    with_core(|core| *core = Core::default());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    if info.is_null() {
+        return;
+    }
+    info.write(SystemInfo {
+        library_name: c"Arness".as_ptr(),
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast(),
+        valid_extensions: c"nes".as_ptr(),
+        need_fullpath: false,
+        block_extract: false,
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    if info.is_null() {
+        return;
+    }
+    let (fps, sample_rate) = with_core(|core| {
+        (
+            core.emulator.region().frame_rate(),
+            core.emulator.sample_rate(),
+        )
+    });
+    info.write(SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: FRAME_WIDTH as c_uint,
+            base_height: FRAME_HEIGHT as c_uint,
+            max_width: FRAME_WIDTH as c_uint,
+            max_height: FRAME_HEIGHT as c_uint,
+            aspect_ratio: 4.0 / 3.0,
+        },
+        timing: SystemTiming { fps, sample_rate },
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    with_core(|core| core.environment = callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    with_core(|core| core.video_refresh = callback);
+}
+
+// Single samples are not used; audio always goes out in batches
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    with_core(|core| core.audio_sample_batch = callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    with_core(|core| core.input_poll = callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    with_core(|core| core.input_state = callback);
+}
+
+// Both ports are always standard controllers
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    with_core(|core| core.emulator.reset());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_run() {
+    with_core(|core| {
+        if !core.loaded {
+            return;
+        }
+        if let Some(poll) = core.input_poll {
+            poll();
+        }
+        if let Some(state) = core.input_state {
+            for port in 0..2 {
+                let buttons = JOYPAD_MAP
+                    .iter()
+                    .filter(|&&(id, _)| state(port, DEVICE_JOYPAD, 0, id) != 0)
+                    .fold(0, |buttons, &(_, button)| buttons | button);
+                core.emulator.set_controller_state(port as usize, buttons);
+            }
+        }
+
+        let (video, audio) = (&mut core.video, &mut core.audio);
+        let (video_refresh, audio_sample_batch) = (core.video_refresh, core.audio_sample_batch);
+        core.emulator
+            .run_frame_into(&mut |frame: &Frame, samples: &[f32], _| {
+                if let Some(refresh) = video_refresh {
+                    video.clear();
+                    video.extend(frame.pixels.chunks_exact(4).map(|rgba| {
+                        (rgba[0] as u32) << 16 | (rgba[1] as u32) << 8 | rgba[2] as u32
+                    }));
+                    refresh(
+                        video.as_ptr().cast(),
+                        frame.width as c_uint,
+                        frame.height as c_uint,
+                        frame.width * 4,
+                    );
+                }
+                if let Some(batch) = audio_sample_batch {
+                    audio.clear();
+                    for &sample in samples {
+                        let level = (sample.clamp(0.0, 1.0) * i16::MAX as f32) as i16;
+                        audio.extend([level, level]);
+                    }
+                    // The front-end may take fewer frames than offered
+                    let mut sent = 0;
+                    while sent < samples.len() {
+                        let taken = batch(audio[sent * 2..].as_ptr(), samples.len() - sent);
+                        if taken == 0 {
+                            break;
+                        }
+                        sent += taken;
+                    }
+                }
+            });
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize(_data: *mut c_void, _size: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unserialize(_data: *const c_void, _size: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    with_core(|core| core.emulator.cpu_mut().clear_freezes());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(_index: c_uint, enabled: bool, code: *const c_char) {
+    if !enabled || code.is_null() {
+        return;
+    }
+    let Ok(code) = CStr::from_ptr(code).to_str() else {
+        return;
+    };
+    if let Some((addr, value)) = parse_cheat(code) {
+        with_core(|core| core.emulator.cpu_mut().freeze(addr, value));
+    }
+}
+
+// A raw RAM cheat, AAAA:VV in hex
+fn parse_cheat(code: &str) -> Option<(u16, u8)> {
+    let (addr, value) = code.trim().split_once(':')?;
+    Some((
+        u16::from_str_radix(addr, 16).ok()?,
+        u8::from_str_radix(value, 16).ok()?,
+    ))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    let Some(game) = game.as_ref() else {
+        return false;
+    };
+    if game.data.is_null() {
+        return false;
+    }
+    let rom = std::slice::from_raw_parts(game.data.cast::<u8>(), game.size);
+    let file_name = match game.path.is_null() {
+        true => None,
+        false => CStr::from_ptr(game.path).to_str().ok().and_then(|path| {
+            Path::new(path)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(str::to_string)
+        }),
+    };
+
+    with_core(|core| {
+        let mut format = PIXEL_FORMAT_XRGB8888;
+        let format_ok = core.environment.is_some_and(|environment| {
+            environment(
+                ENVIRONMENT_SET_PIXEL_FORMAT,
+                (&mut format as *mut c_uint).cast(),
+            )
+        });
+        if !format_ok {
+            return false;
+        }
+        core.loaded = core
+            .emulator
+            .load_rom_named(rom, file_name.as_deref())
+            .is_ok();
+        core.loaded
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const GameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    with_core(|core| {
+        core.emulator = Emulator::new();
+        core.loaded = false;
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    with_core(|core| match core.emulator.region() {
+        Region::Ntsc => REGION_NTSC,
+        Region::Pal | Region::Dendy => REGION_PAL,
+    })
+}
+
+// The memory stays put until the game is unloaded, as libretro expects
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    with_core(|core| match memory_range(core, id) {
+        Some(range) => core.emulator.cpu_mut().memory[range].as_mut_ptr().cast(),
+        None => std::ptr::null_mut(),
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    with_core(|core| memory_range(core, id).map_or(0, |range| range.len()))
+}
+
+fn memory_range(core: &Core, id: c_uint) -> Option<std::ops::Range<usize>> {
+    match id {
+        _ if !core.loaded => None,
+        MEMORY_SYSTEM_RAM => Some(SYSTEM_RAM),
+        MEMORY_SAVE_RAM if core.emulator.cartridge()?.has_battery => Some(SAVE_RAM),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        static FRAMES: Cell<usize> = const { Cell::new(0) };
+        static SAMPLES: Cell<usize> = const { Cell::new(0) };
+        static PIXEL_FORMAT: Cell<c_uint> = const { Cell::new(0) };
+    }
+
+    unsafe extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+        if cmd != ENVIRONMENT_SET_PIXEL_FORMAT {
+            return false;
+        }
+        PIXEL_FORMAT.set(*data.cast::<c_uint>());
+        true
+    }
+
+    unsafe extern "C" fn video_refresh(_: *const c_void, width: c_uint, height: c_uint, _: usize) {
+        assert_eq!((width, height), (256, 240));
+        FRAMES.set(FRAMES.get() + 1);
+    }
+
+    unsafe extern "C" fn audio_batch(_: *const i16, frames: usize) -> usize {
+        SAMPLES.set(SAMPLES.get() + frames);
+        frames
+    }
+
+    // Holds Start on controller 0
+    unsafe extern "C" fn input_state(port: c_uint, _: c_uint, _: c_uint, id: c_uint) -> i16 {
+        (port == 0 && id == 3) as i16
+    }
+
+    // A one-bank NROM image that copies $4016 bit 0 into $0010 forever
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg = vec![0xEA; 0x4000];
+        // LDA #1; STA $4016; LDA #0; STA $4016; LDA $4016 x4; STA $10; JMP $8000
+        let program = [
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, 0xAD, 0x16, 0x40, 0xAD,
+            0x16, 0x40, 0xAD, 0x16, 0x40, 0xAD, 0x16, 0x40, 0x85, 0x10, 0x4C, 0x00, 0x80,
+        ];
+        prg[..program.len()].copy_from_slice(&program);
+        prg[0x3FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+        rom
+    }
+
+    #[test]
+    fn runs_a_game_through_the_entry_points() {
+        let rom = rom();
+        let game = GameInfo {
+            path: c"/roms/Test (Europe).nes".as_ptr(),
+            data: rom.as_ptr().cast(),
+            size: rom.len(),
+            meta: std::ptr::null(),
+        };
+        retro_init();
+        retro_set_environment(Some(environment));
+        retro_set_video_refresh(Some(video_refresh));
+        retro_set_audio_sample_batch(Some(audio_batch));
+        retro_set_input_state(Some(input_state));
+        unsafe {
+            assert!(retro_load_game(&game));
+            assert_eq!(PIXEL_FORMAT.get(), PIXEL_FORMAT_XRGB8888);
+            retro_run();
+            retro_run();
+        }
+
+        assert_eq!(FRAMES.get(), 2);
+        if cfg!(feature = "audio") {
+            assert!(SAMPLES.get() > 1900, "{}", SAMPLES.get());
+        }
+        // The file name made it a PAL game
+        assert_eq!(retro_get_region(), REGION_PAL);
+        assert_eq!(retro_get_memory_size(MEMORY_SYSTEM_RAM), 0x800);
+        assert_eq!(retro_get_memory_size(MEMORY_SAVE_RAM), 0);
+        let ram = retro_get_memory_data(MEMORY_SYSTEM_RAM).cast::<u8>();
+        // Start is the fourth button read
+        assert_eq!(unsafe { *ram.add(0x10) } & 1, 1);
+
+        retro_unload_game();
+        assert_eq!(retro_get_memory_size(MEMORY_SYSTEM_RAM), 0);
+        retro_deinit();
+    }
+
+    #[test]
+    fn cheats_freeze_ram() {
+        assert_eq!(parse_cheat("0010:7F"), Some((0x0010, 0x7F)));
+        assert_eq!(parse_cheat(" 07ff:01 "), Some((0x07FF, 0x01)));
+        assert_eq!(parse_cheat("SXIOPO"), None);
+        assert_eq!(parse_cheat("10000:01"), None);
+
+        unsafe { retro_cheat_set(0, true, c"0020:42".as_ptr()) };
+        with_core(|core| {
+            core.emulator.cpu_mut().write(0x0020, 0);
+            assert_eq!(core.emulator.cpu().peek(0x0020), 0x42);
+        });
+        retro_cheat_reset();
+        with_core(|core| assert!(core.emulator.cpu().freezes().is_empty()));
+    }
+}
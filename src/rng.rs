@@ -0,0 +1,53 @@
+// Seedable random number generator used for every source of nondeterminism
+// in the emulator (power-on RAM contents, open-bus jitter, ...).
+// The same seed always produces the same sequence, so identical seeds and
+// inputs give identical runs, which movies and netplay depend on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EmuRng {
+    seed: u64,
+    state: u64,
+}
+
+impl EmuRng {
+    pub fn new(seed: u64) -> Self {
+        EmuRng { seed, state: seed }
+    }
+
+    // The seed this generator was created with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Restart the sequence from the original seed
+    pub fn reset(&mut self) {
+        self.state = self.seed;
+    }
+
+    // Next 64-bit value (SplitMix64)
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Next 8-bit value
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    // Fill a buffer with random bytes
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+impl Default for EmuRng {
+    fn default() -> Self {
+        EmuRng::new(0)
+    }
+}
@@ -0,0 +1,107 @@
+// Downloader and cache for the public nes-test-roms collection, used by
+// test harnesses. Only built with the `online-tests` feature so default
+// builds never touch the network.
+//
+// Files are cached under $ARNESS_TEST_ROMS (or target/test-roms). A ROM
+// fetched with a pinned hash is stored by that hash, so it is only
+// downloaded once and a changed upstream file is reported, not used.
+use crate::hash::fnv1a;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+pub const BASE_URL: &str = "https://raw.githubusercontent.com/christopherpow/nes-test-roms/master/";
+
+// Well-known suites, as paths inside the collection
+pub const NESTEST: &str = "other/nestest.nes";
+pub const INSTR_TEST_OFFICIAL: &str = "instr_test-v5/official_only.nes";
+pub const INSTR_TEST_ALL: &str = "instr_test-v5/all_instrs.nes";
+pub const CPU_TIMING: &str = "cpu_timing_test6/cpu_timing_test.nes";
+pub const CPU_DUMMY_READS: &str = "cpu_dummy_reads/cpu_dummy_reads.nes";
+pub const BRANCH_BASICS: &str = "branch_timing_tests/1.Branch_Basics.nes";
+
+// Directory the cache lives in
+pub fn cache_dir() -> PathBuf {
+    match std::env::var_os("ARNESS_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/test-roms"),
+    }
+}
+
+// 64-bit FNV-1a content hash used to pin ROM versions. It guards against
+// accidental changes, it is not meant to resist tampering.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    fnv1a(bytes)
+}
+
+// Fetch a ROM by its path in the collection, downloading it on first use.
+// With `expected_hash` the cached copy is keyed and verified by hash;
+// without it the file is cached by path, which is handy for finding the
+// hash to pin.
+pub fn fetch(path: &str, expected_hash: Option<u64>) -> io::Result<Vec<u8>> {
+    let dir = cache_dir();
+    let cached = match expected_hash {
+        Some(hash) => dir.join(format!("{:016x}.nes", hash)),
+        None => dir.join(path.replace('/', "__")),
+    };
+    if let Ok(bytes) = fs::read(&cached) {
+        if expected_hash.is_none_or(|hash| content_hash(&bytes) == hash) {
+            return Ok(bytes);
+        }
+    }
+
+    let bytes = download(&format!("{}{}", BASE_URL, path))?;
+    if let Some(hash) = expected_hash {
+        let actual = content_hash(&bytes);
+        if actual != hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has hash {:016x}, expected {:016x}", path, actual, hash),
+            ));
+        }
+    }
+
+    fs::create_dir_all(&dir)?;
+    fs::write(&cached, &bytes)?;
+    Ok(bytes)
+}
+
+fn download(url: &str) -> io::Result<Vec<u8>> {
+    let response = ureq::get(url)
+        .call()
+        .map_err(|e| io::Error::other(format!("{}: {}", url, e)))?;
+    let mut bytes = Vec::new();
+    response.into_reader().read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+
+    // nestest's automated mode: start at $C000 and let it run to the RTS at
+    // $C66E, then read the failing test numbers it leaves at $0002/$0003
+    #[test]
+    fn nestest_passes() {
+        let rom = fetch(NESTEST, None).expect("nestest download");
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&rom).unwrap();
+        let cpu = emulator.cpu_mut();
+        cpu.pc = 0xC000;
+        cpu.status = 0x24;
+        for _ in 0..10_000 {
+            if emulator.cpu().pc == 0xC66E || emulator.cpu().jammed {
+                break;
+            }
+            emulator.step_instruction();
+        }
+        let cpu = emulator.cpu();
+        assert_eq!(cpu.pc, 0xC66E, "nestest did not finish");
+        assert_eq!(
+            (cpu.peek(0x0002), cpu.peek(0x0003)),
+            (0, 0),
+            "nestest failure codes"
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use crate::cpu6502::Cpu6502;
+use crate::opcodes::{AddressingMode, OPCODES};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+// Layout of trace lines
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    // Instruction bytes, disassembly and registers
+    #[default]
+    Raw,
+    // Matches nestest.log, including the "= value" operand annotations and
+    // the PPU position implied by the cycle count
+    Nestest,
+    // Like Raw, with addresses replaced by names from the symbol table
+    Symbolized,
+}
+
+impl TraceFormat {
+    // Parse a format name as used on the command line
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "raw" => Some(TraceFormat::Raw),
+            "nestest" => Some(TraceFormat::Nestest),
+            "symbols" | "symbolized" => Some(TraceFormat::Symbolized),
+            _ => None,
+        }
+    }
+}
+
+// Names for addresses, loaded from an assembler label file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    names: HashMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    // Parse label files. Both the ld65 `-Ln` format ("al 00C000 .reset")
+    // and plain assignments ("reset = $C000") are understood; other lines
+    // are skipped.
+    pub fn parse(text: &str) -> Self {
+        let mut table = SymbolTable::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let entry = match words.as_slice() {
+                ["al", addr, name] => u32::from_str_radix(addr, 16)
+                    .ok()
+                    .map(|addr| (addr as u16, name.trim_start_matches('.'))),
+                [name, "=", addr] => {
+                    let addr = addr.trim_start_matches('$');
+                    u16::from_str_radix(addr, 16).ok().map(|addr| (addr, *name))
+                }
+                _ => None,
+            };
+            if let Some((addr, name)) = entry {
+                table.insert(addr, name);
+            }
+        }
+        table
+    }
+
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.names.insert(addr, name.to_string());
+    }
+
+    // Name for an address, if one is known
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    // Address of a name, if one is known
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.names
+            .iter()
+            .find(|(_, n)| n.as_str() == name)
+            .map(|(&addr, _)| addr)
+    }
+
+    // Every symbol, ordered by address
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        let mut entries: Vec<_> = self
+            .names
+            .iter()
+            .map(|(&addr, name)| (addr, name.as_str()))
+            .collect();
+        entries.sort();
+        entries.into_iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+impl From<&HashMap<String, u16>> for SymbolTable {
+    // Build a table from assembler labels
+    fn from(labels: &HashMap<String, u16>) -> Self {
+        let mut table = SymbolTable::new();
+        for (name, &addr) in labels {
+            table.insert(addr, name);
+        }
+        table
+    }
+}
+
+// Produces trace lines in a chosen format, skipping instructions outside
+// the PC filter so long runs stay manageable
+#[derive(Clone, Debug, Default)]
+pub struct Tracer {
+    pub format: TraceFormat,
+    pub pc_filter: Option<RangeInclusive<u16>>,
+    pub symbols: SymbolTable,
+}
+
+impl Tracer {
+    pub fn new(format: TraceFormat) -> Self {
+        Tracer {
+            format,
+            ..Tracer::default()
+        }
+    }
+
+    // The trace line for the instruction about to execute, or None if the
+    // filter excludes it
+    pub fn line(&self, cpu: &Cpu6502) -> Option<String> {
+        if let Some(range) = &self.pc_filter {
+            if !range.contains(&cpu.pc) {
+                return None;
+            }
+        }
+        Some(match self.format {
+            TraceFormat::Raw => format_line(cpu),
+            TraceFormat::Nestest => format_nestest_line(cpu),
+            TraceFormat::Symbolized => format_symbolized_line(cpu, &self.symbols),
+        })
+    }
+}
+
+// Disassemble the instruction at addr, returning its text and size in bytes
+pub fn disassemble(cpu: &Cpu6502, addr: u16) -> (String, u8) {
+    disassemble_with(cpu, addr, &|value, _| value)
+}
+
+// Disassemble with a hook that renders each operand address (given the
+// formatted hex and the raw value)
+fn disassemble_with(
+    cpu: &Cpu6502,
+    addr: u16,
+    render: &dyn Fn(String, u16) -> String,
+) -> (String, u8) {
+    let op = &OPCODES[cpu.peek(addr) as usize];
+    let b1 = cpu.peek(addr.wrapping_add(1));
+    let word = cpu.peek_word(addr.wrapping_add(1));
+    let zp = || render(format!("${:02X}", b1), b1 as u16);
+    let abs = || render(format!("${:04X}", word), word);
+    let operand = match op.mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Immediate => format!("#${:02X}", b1),
+        AddressingMode::ZeroPage => zp(),
+        AddressingMode::ZeroPageX => format!("{},X", zp()),
+        AddressingMode::ZeroPageY => format!("{},Y", zp()),
+        AddressingMode::Absolute => abs(),
+        AddressingMode::AbsoluteX => format!("{},X", abs()),
+        AddressingMode::AbsoluteY => format!("{},Y", abs()),
+        AddressingMode::Indirect => format!("({})", abs()),
+        AddressingMode::IndirectX => format!("({},X)", zp()),
+        AddressingMode::IndirectY => format!("({}),Y", zp()),
+        AddressingMode::Relative => {
+            let target = addr.wrapping_add(2).wrapping_add(b1 as i8 as u16);
+            render(format!("${:04X}", target), target)
+        }
+    };
+    let text = if operand.is_empty() {
+        op.mnemonic.to_string()
+    } else {
+        format!("{} {}", op.mnemonic, operand)
+    };
+    (text, op.size)
+}
+
+// Instruction bytes, space separated
+fn instruction_bytes(cpu: &Cpu6502, size: u8) -> String {
+    (0..size as u16)
+        .map(|i| format!("{:02X}", cpu.peek(cpu.pc.wrapping_add(i))))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Registers in the nestest layout
+fn registers(cpu: &Cpu6502) -> String {
+    format!(
+        "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+        cpu.a, cpu.x, cpu.y, cpu.status, cpu.sp
+    )
+}
+
+// Format the instruction about to execute and the CPU registers as one
+// trace line, laid out like the nestest log
+pub fn format_line(cpu: &Cpu6502) -> String {
+    let (text, size) = disassemble(cpu, cpu.pc);
+    join_columns(cpu, &text, size, "")
+}
+
+// Shared layout: address, bytes, unofficial marker, disassembly, registers
+fn join_columns(cpu: &Cpu6502, text: &str, size: u8, timing: &str) -> String {
+    let op = &OPCODES[cpu.peek(cpu.pc) as usize];
+    format!(
+        "{:04X}  {:<8} {}{:<32}{} {}CYC:{}",
+        cpu.pc,
+        instruction_bytes(cpu, size),
+        if op.official { ' ' } else { '*' },
+        text,
+        registers(cpu),
+        timing,
+        cpu.cycles
+    )
+}
+
+// A nestest.log line. The PPU position is derived from the cycle count,
+// which is exact while rendering is off, as it is in nestest.
+pub fn format_nestest_line(cpu: &Cpu6502) -> String {
+    let (mut text, size) = disassemble(cpu, cpu.pc);
+    text.push_str(&nestest_annotation(cpu));
+    let ppu_cycles = cpu.cycles * 3;
+    let timing = format!("PPU:{:>3},{:>3} ", ppu_cycles / 341 % 262, ppu_cycles % 341);
+    join_columns(cpu, &text, size, &timing)
+}
+
+// The "@ address = value" suffix nestest prints for memory operands
+fn nestest_annotation(cpu: &Cpu6502) -> String {
+    let op = &OPCODES[cpu.peek(cpu.pc) as usize];
+    let b1 = cpu.peek(cpu.pc.wrapping_add(1));
+    let word = cpu.peek_word(cpu.pc.wrapping_add(1));
+    let zp_word =
+        |ptr: u8| cpu.peek(ptr as u16) as u16 | (cpu.peek(ptr.wrapping_add(1) as u16) as u16) << 8;
+    match op.mode {
+        AddressingMode::ZeroPage => format!(" = {:02X}", cpu.peek(b1 as u16)),
+        AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+            let index = if op.mode == AddressingMode::ZeroPageX {
+                cpu.x
+            } else {
+                cpu.y
+            };
+            let addr = b1.wrapping_add(index);
+            format!(" @ {:02X} = {:02X}", addr, cpu.peek(addr as u16))
+        }
+        AddressingMode::Absolute if op.mnemonic != "JMP" && op.mnemonic != "JSR" => {
+            format!(" = {:02X}", cpu.peek(word))
+        }
+        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+            let index = if op.mode == AddressingMode::AbsoluteX {
+                cpu.x
+            } else {
+                cpu.y
+            };
+            let addr = word.wrapping_add(index as u16);
+            format!(" @ {:04X} = {:02X}", addr, cpu.peek(addr))
+        }
+        AddressingMode::Indirect => {
+            let hi_addr = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+            let target = cpu.peek(word) as u16 | (cpu.peek(hi_addr) as u16) << 8;
+            format!(" = {:04X}", target)
+        }
+        AddressingMode::IndirectX => {
+            let ptr = b1.wrapping_add(cpu.x);
+            let addr = zp_word(ptr);
+            format!(" @ {:02X} = {:04X} = {:02X}", ptr, addr, cpu.peek(addr))
+        }
+        AddressingMode::IndirectY => {
+            let base = zp_word(b1);
+            let addr = base.wrapping_add(cpu.y as u16);
+            format!(" = {:04X} @ {:04X} = {:02X}", base, addr, cpu.peek(addr))
+        }
+        _ => String::new(),
+    }
+}
+
+// A raw line with operand addresses and the current PC shown by name
+pub fn format_symbolized_line(cpu: &Cpu6502, symbols: &SymbolTable) -> String {
+    let render = |hex: String, value: u16| match symbols.name(value) {
+        Some(name) => name.to_string(),
+        None => hex,
+    };
+    let (text, size) = disassemble_with(cpu, cpu.pc, &render);
+    let line = join_columns(cpu, &text, size, "");
+    match symbols.name(cpu.pc) {
+        Some(name) => format!("{}:\n{}", name, line),
+        None => line,
+    }
+}
+
+#[cfg(all(test, feature = "audio"))]
+mod tests {
+    use super::*;
+    use crate::apu::Apu;
+    use crate::input::ControllerPorts;
+
+    // A CPU with a frame IRQ pending and buttons latched into port 0
+    fn cpu_with_devices() -> Cpu6502 {
+        let mut cpu = Cpu6502::new();
+        cpu.apu = Some(Apu::new());
+        cpu.controllers = Some(ControllerPorts::new());
+        for _ in 0..29829 {
+            cpu.apu.as_mut().unwrap().tick();
+        }
+        cpu.controllers
+            .as_mut()
+            .unwrap()
+            .set_buttons(0, 0b1010_0101);
+        cpu.write(0x4016, 1);
+        cpu.write(0x4016, 0);
+        cpu
+    }
+
+    fn assert_devices_untouched(cpu: &Cpu6502) {
+        assert!(cpu.apu.as_ref().unwrap().irq_pending());
+        let ports = cpu.controllers.as_ref().unwrap();
+        let shifted: u8 = (0..8).map(|i| (ports.read(0) & 1) << i).sum();
+        assert_eq!(shifted, 0b1010_0101);
+    }
+
+    #[test]
+    fn tracing_does_not_touch_io() {
+        let mut cpu = cpu_with_devices();
+        // LDA $4015 and LDA $4016, whose values the nestest format shows.
+        // Code can no longer sit on the write-only registers, which read
+        // as open bus.
+        cpu.memory[0x0200..0x0206].copy_from_slice(&[0xAD, 0x15, 0x40, 0xAD, 0x16, 0x40]);
+        for pc in [0x0200, 0x0203] {
+            cpu.pc = pc;
+            for format in [TraceFormat::Raw, TraceFormat::Nestest] {
+                Tracer::new(format).line(&cpu);
+            }
+            format_symbolized_line(&cpu, &SymbolTable::new());
+        }
+        assert_devices_untouched(&cpu);
+    }
+
+    #[test]
+    fn backtrace_does_not_touch_io() {
+        let mut cpu = cpu_with_devices();
+        // Return addresses whose call sites would be $4015 and $4016
+        cpu.memory[0x01FC..0x0200].copy_from_slice(&[0x17, 0x40, 0x18, 0x40]);
+        cpu.sp = 0xFB;
+        cpu.backtrace();
+        assert_devices_untouched(&cpu);
+    }
+}
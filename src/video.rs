@@ -0,0 +1,246 @@
+// Post-processing of finished frames. Stages are stacked in a chain that
+// front-ends configure once and run on every frame; presets approximate the
+// look of the console's composite, S-Video and RGB outputs.
+//
+// The stages only see finished RGBA pixels. NTSC artifacting (chroma
+// fringes and dot crawl) and the 2C03/2C05 RGB palettes both need the PPU's
+// palette indices, so they wait until the PPU can hand those over; until
+// then the presets differ only in blur, gamma, scanlines and mask.
+use crate::frame::{Frame, ScaleOptions};
+
+// One step of the chain, editing the frame in place
+pub trait PostStage {
+    fn apply(&mut self, frame: &mut Frame);
+}
+
+// Gamma correction through a lookup table
+#[derive(Clone, Debug)]
+pub struct Gamma {
+    table: [u8; 256],
+}
+
+impl Gamma {
+    pub fn new(gamma: f32) -> Self {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let level = (i as f32 / 255.0).powf(1.0 / gamma);
+            *entry = (level * 255.0).round() as u8;
+        }
+        Gamma { table }
+    }
+}
+
+impl PostStage for Gamma {
+    fn apply(&mut self, frame: &mut Frame) {
+        for rgba in frame.pixels.chunks_exact_mut(4) {
+            for channel in &mut rgba[..3] {
+                *channel = self.table[*channel as usize];
+            }
+        }
+    }
+}
+
+// Horizontal blur standing in for the limited bandwidth of composite and
+// S-Video. `amount` is how much of each pixel is replaced by its two
+// neighbours together, out of 256, split evenly between them. It is capped
+// at 128, a 1:2:1 blur where each neighbour gives a quarter.
+#[derive(Clone, Debug)]
+pub struct Soften {
+    pub amount: u8,
+    row: Vec<u8>,
+}
+
+impl Soften {
+    pub fn new(amount: u8) -> Self {
+        Soften {
+            amount,
+            row: Vec::new(),
+        }
+    }
+}
+
+impl PostStage for Soften {
+    fn apply(&mut self, frame: &mut Frame) {
+        if frame.width == 0 || frame.height == 0 {
+            return;
+        }
+        let side = self.amount.min(128) as u32 / 2;
+        let centre = 256 - 2 * side;
+        let stride = frame.width * 4;
+        for line in frame.pixels.chunks_exact_mut(stride) {
+            self.row.clear();
+            self.row.extend_from_slice(line);
+            for x in 0..frame.width {
+                let left = x.saturating_sub(1) * 4;
+                let right = (x + 1).min(frame.width - 1) * 4;
+                for c in 0..3 {
+                    let sum = self.row[left + c] as u32 * side
+                        + self.row[x * 4 + c] as u32 * centre
+                        + self.row[right + c] as u32 * side;
+                    line[x * 4 + c] = (sum / 256) as u8;
+                }
+            }
+        }
+    }
+}
+
+// Integer scaling with optional scanlines, as a stage
+#[derive(Clone, Debug)]
+pub struct Scale {
+    pub options: ScaleOptions,
+    scratch: Frame,
+}
+
+impl Scale {
+    pub fn new(options: ScaleOptions) -> Self {
+        Scale {
+            options,
+            scratch: Frame::new(0, 0),
+        }
+    }
+}
+
+impl PostStage for Scale {
+    fn apply(&mut self, frame: &mut Frame) {
+        frame.scale_into(&self.options, &mut self.scratch);
+        std::mem::swap(frame, &mut self.scratch);
+    }
+}
+
+// Aperture-grille style mask: each column favours one of red, green and
+// blue, and the other two channels keep `level` out of 256. Works best
+// after scaling, so each source pixel covers a full triad.
+#[derive(Clone, Debug)]
+pub struct CrtMask {
+    pub level: u8,
+}
+
+impl PostStage for CrtMask {
+    fn apply(&mut self, frame: &mut Frame) {
+        let width = frame.width;
+        for (i, rgba) in frame.pixels.chunks_exact_mut(4).enumerate() {
+            let keep = i % width % 3;
+            for (c, channel) in rgba[..3].iter_mut().enumerate() {
+                if c != keep {
+                    *channel = (*channel as u16 * self.level as u16 / 256) as u8;
+                }
+            }
+        }
+    }
+}
+
+// The console output a preset imitates. Every preset uses the same 2C02
+// colours; see the top of this file for what is still missing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum VideoOutput {
+    #[default]
+    Composite,
+    SVideo,
+    // 2C03/2C05 style RGB, as on Vs. System and PlayChoice boards. For now
+    // this is a sharp scale with no scanlines; the RGB PPU palettes are not
+    // applied yet
+    Rgb,
+}
+
+// Stages applied in order to each frame
+#[derive(Default)]
+pub struct VideoPostChain {
+    stages: Vec<Box<dyn PostStage>>,
+}
+
+impl VideoPostChain {
+    pub fn new() -> Self {
+        VideoPostChain::default()
+    }
+
+    // A ready-made chain for an output type
+    pub fn preset(output: VideoOutput) -> Self {
+        let chain = VideoPostChain::new();
+        match output {
+            VideoOutput::Composite => chain
+                .with(Soften::new(96))
+                .with(Gamma::new(1.1))
+                .with(Scale::new(ScaleOptions {
+                    factor: 3,
+                    scanlines: Some(192),
+                }))
+                .with(CrtMask { level: 200 }),
+            VideoOutput::SVideo => chain.with(Soften::new(48)).with(Scale::new(ScaleOptions {
+                factor: 3,
+                scanlines: Some(208),
+            })),
+            VideoOutput::Rgb => chain.with(Scale::new(ScaleOptions {
+                factor: 3,
+                scanlines: None,
+            })),
+        }
+    }
+
+    // Append a stage
+    pub fn with(mut self, stage: impl PostStage + 'static) -> Self {
+        self.push(stage);
+        self
+    }
+
+    pub fn push(&mut self, stage: impl PostStage + 'static) {
+        self.stages.push(Box::new(stage));
+    }
+
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    // Run every stage over a frame
+    pub fn apply(&mut self, frame: &mut Frame) {
+        for stage in &mut self.stages {
+            stage.apply(frame);
+        }
+    }
+
+    // Run the chain on a copy, leaving the source untouched
+    pub fn process(&mut self, frame: &Frame) -> Frame {
+        let mut out = frame.clone();
+        self.apply(&mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn soften_ignores_empty_frames() {
+        for (width, height) in [(0, 0), (0, 4), (4, 0)] {
+            let mut frame = Frame::new(width, height);
+            Soften::new(128).apply(&mut frame);
+            assert!(frame.pixels.is_empty());
+        }
+    }
+
+    #[test]
+    fn soften_splits_amount_between_neighbours() {
+        let mut frame = Frame::new(3, 1);
+        frame.set_pixel(1, 0, [200, 100, 0, 255]);
+        Soften::new(128).apply(&mut frame);
+        assert_eq!(frame.pixel(0, 0), [50, 25, 0, 0]);
+        assert_eq!(frame.pixel(1, 0), [100, 50, 0, 255]);
+        assert_eq!(frame.pixel(2, 0), [50, 25, 0, 0]);
+
+        // Anything above 128 is the same 1:2:1 blur
+        let mut capped = Frame::new(3, 1);
+        capped.set_pixel(1, 0, [200, 100, 0, 255]);
+        Soften::new(255).apply(&mut capped);
+        assert_eq!(capped, frame);
+
+        let mut light = Frame::new(3, 1);
+        light.set_pixel(1, 0, [200, 100, 0, 255]);
+        Soften::new(64).apply(&mut light);
+        assert_eq!(light.pixel(0, 0), [25, 12, 0, 0]);
+        assert_eq!(light.pixel(1, 0), [150, 75, 0, 255]);
+    }
+}
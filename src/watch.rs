@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+
+// One recorded memory write
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteEntry {
+    // CPU cycle count when the instruction started
+    pub cycle: u64,
+    // Address of the instruction that made the write
+    pub pc: u16,
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+// Bounded log of writes to an address range. Once full, the oldest
+// entries are dropped so long runs never grow memory use.
+#[derive(Clone, Debug)]
+pub struct WriteLog {
+    range: RangeInclusive<u16>,
+    capacity: usize,
+    entries: VecDeque<WriteEntry>,
+    dropped: u64,
+}
+
+impl WriteLog {
+    pub fn new(range: RangeInclusive<u16>, capacity: usize) -> Self {
+        WriteLog {
+            range,
+            capacity,
+            entries: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    // The watched address range
+    pub fn range(&self) -> &RangeInclusive<u16> {
+        &self.range
+    }
+
+    // Entries from oldest to newest
+    pub fn entries(&self) -> impl Iterator<Item = &WriteEntry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Number of entries discarded because the log was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.dropped = 0;
+    }
+
+    // Record a write if it falls inside the watched range
+    pub fn record(&mut self, entry: WriteEntry) {
+        if !self.range.contains(&entry.addr) || self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+            self.dropped += 1;
+        }
+        self.entries.push_back(entry);
+    }
+}